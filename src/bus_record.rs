@@ -0,0 +1,594 @@
+// BusOps の記録と再生
+// 実機とのやりとりをファイルに記録しておき、あとから同じ応答を返すことで、
+// IT930x / TC90522 / チューナーのドライバを実機なしで回帰テストできるようにする。
+//
+// 記録ファイルはテキストで、1行1イベント。
+//   # px4 bus record v1
+//   MAXBULK <max_bulk_size>
+//   <経過μs> TX <hex>          ctrl_tx で送ったデータ
+//   <経過μs> RX <hex>          ctrl_rx で受け取ったデータ
+//   <経過μs> STREAM <hex>      stream_rx で受け取ったデータ
+//   <経過μs> START / STOP      start_streaming / stop_streaming
+//   <経過μs> ERR <op> <kind> <説明>   各操作がエラーになった場合
+//
+// 経過時間は、再生時に set_realtime(true) にすると記録時と同じ間隔で応答を返すのに使う。
+// (ストリームの詰まり方やタイムアウト周りを再現したいとき用。既定では待たずに返す)
+//
+// 記録は録画コマンドの --record-bus で、再生 (ReplayBus) はテストから使う。
+
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+#[cfg(test)]
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::itedtv_bus::{BusError, BusOps};
+
+const RECORD_HEADER: &str = "# px4 bus record v1";
+
+fn to_hex(data: &[u8]) -> String
+{
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data
+    {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+#[cfg(test)]
+fn from_hex(s: &str) -> Option<Vec<u8>>
+{
+    if !s.len().is_multiple_of(2)
+    {
+        return None;
+    }
+
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+// 記録対象の操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordOp
+{
+    CtrlTx,
+    CtrlRx,
+    StreamRx,
+    StartStreaming,
+    StopStreaming,
+}
+
+impl RecordOp
+{
+    fn tag(&self) -> &'static str
+    {
+        match self
+        {
+            RecordOp::CtrlTx => "TX",
+            RecordOp::CtrlRx => "RX",
+            RecordOp::StreamRx => "STREAM",
+            RecordOp::StartStreaming => "START",
+            RecordOp::StopStreaming => "STOP",
+        }
+    }
+
+    #[cfg(test)]
+    fn from_tag(tag: &str) -> Option<Self>
+    {
+        match tag
+        {
+            "TX" => Some(RecordOp::CtrlTx),
+            "RX" => Some(RecordOp::CtrlRx),
+            "STREAM" => Some(RecordOp::StreamRx),
+            "START" => Some(RecordOp::StartStreaming),
+            "STOP" => Some(RecordOp::StopStreaming),
+            _ => None,
+        }
+    }
+}
+
+// 記録したエラーの種類
+// rusb::Error はそのまま書き戻せないので、再生時は Other で返す
+fn error_kind(e: &BusError) -> (&'static str, String)
+{
+    match e
+    {
+        BusError::Usb(e) => ("usb", format!("{:?}", e)),
+        BusError::Timeout => ("timeout", String::new()),
+        BusError::Disconnected => ("disconnected", String::new()),
+        BusError::Other(s) => ("other", s.replace('\n', " ")),
+    }
+}
+
+#[cfg(test)]
+fn error_from_kind(kind: &str, desc: &str) -> BusError
+{
+    match kind
+    {
+        "timeout" => BusError::Timeout,
+        "disconnected" => BusError::Disconnected,
+        "usb" => BusError::Other(format!("recorded usb error: {}", desc)),
+        _ => BusError::Other(desc.to_string()),
+    }
+}
+
+// 記録側
+// 中身の BusOps をそのまま呼び出して、結果を追記していく
+pub struct RecordBus<B: BusOps>
+{
+    inner: B,
+    out: Mutex<BufWriter<File>>,
+    start: Instant,
+}
+
+impl<B: BusOps> RecordBus<B>
+{
+    pub fn new<P: AsRef<Path>>(inner: B, path: P) -> Result<Self, BusError>
+    {
+        let file = File::create(path).map_err(|e| BusError::Other(format!("failed to create record file: {}", e)))?;
+        let mut out = BufWriter::new(file);
+
+        writeln!(out, "{}", RECORD_HEADER)
+            .and_then(|_| writeln!(out, "MAXBULK {}", inner.max_bulk_size()))
+            .and_then(|_| out.flush())
+            .map_err(|e| BusError::Other(format!("failed to write record file: {}", e)))?;
+
+        Ok(Self { inner, out: Mutex::new(out), start: Instant::now() })
+    }
+
+    // 記録の失敗で本来の通信を止めたくないので、書き込みエラーは無視する
+    fn log(&self, op: RecordOp, result: Result<&[u8], &BusError>)
+    {
+        let ts = self.start.elapsed().as_micros();
+        let mut out = self.out.lock().unwrap();
+
+        let _ = match result
+        {
+            Ok([]) => writeln!(out, "{} {}", ts, op.tag()),
+            Ok(data) => writeln!(out, "{} {} {}", ts, op.tag(), to_hex(data)),
+            Err(e) =>
+            {
+                let (kind, desc) = error_kind(e);
+                writeln!(out, "{} ERR {} {} {}", ts, op.tag(), kind, desc)
+            }
+        };
+        let _ = out.flush();
+    }
+}
+
+impl<B: BusOps> BusOps for RecordBus<B>
+{
+    fn ctrl_tx(&self, buf: &[u8]) -> Result<(), BusError>
+    {
+        // 送ったデータは成否に関わらず残しておく (再生時の照合に使う)
+        self.log(RecordOp::CtrlTx, Ok(buf));
+        let ret = self.inner.ctrl_tx(buf);
+        if let Err(e) = &ret
+        {
+            self.log(RecordOp::CtrlTx, Err(e));
+        }
+        ret
+    }
+
+    fn ctrl_rx(&self, buf: &mut [u8]) -> Result<usize, BusError>
+    {
+        let ret = self.inner.ctrl_rx(buf);
+        match &ret
+        {
+            Ok(len) => self.log(RecordOp::CtrlRx, Ok(&buf[..*len])),
+            Err(e) => self.log(RecordOp::CtrlRx, Err(e)),
+        }
+        ret
+    }
+
+    fn stream_rx(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, BusError>
+    {
+        let ret = self.inner.stream_rx(buf, timeout);
+        match &ret
+        {
+            Ok(len) => self.log(RecordOp::StreamRx, Ok(&buf[..*len])),
+            Err(e) => self.log(RecordOp::StreamRx, Err(e)),
+        }
+        ret
+    }
+
+    fn start_streaming(&self) -> Result<(), BusError>
+    {
+        let ret = self.inner.start_streaming();
+        match &ret
+        {
+            Ok(()) => self.log(RecordOp::StartStreaming, Ok(&[])),
+            Err(e) => self.log(RecordOp::StartStreaming, Err(e)),
+        }
+        ret
+    }
+
+    fn stop_streaming(&self) -> Result<(), BusError>
+    {
+        let ret = self.inner.stop_streaming();
+        match &ret
+        {
+            Ok(()) => self.log(RecordOp::StopStreaming, Ok(&[])),
+            Err(e) => self.log(RecordOp::StopStreaming, Err(e)),
+        }
+        ret
+    }
+
+    fn max_bulk_size(&self) -> u32
+    {
+        self.inner.max_bulk_size()
+    }
+}
+
+// 記録ファイルの1イベント
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct RecordEntry
+{
+    pub timestamp_us: u128,
+    pub op: RecordOp,
+    pub result: Result<Vec<u8>, (String, String)>,
+}
+
+// 再生時に見つかった食い違い
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ReplayMismatch
+{
+    pub index: usize,
+    pub expected_op: Option<RecordOp>,
+    pub actual_op: RecordOp,
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+}
+
+#[cfg(test)]
+struct ReplayState
+{
+    entries: VecDeque<RecordEntry>,
+    index: usize,
+    mismatches: Vec<ReplayMismatch>,
+    pending_stream: Vec<u8>,
+
+    // realtime のとき、記録の経過時間 0 に当たる時刻 (最初の記録を取り出したときに決める)
+    origin: Option<Instant>,
+}
+
+// 再生側
+// 記録した順に応答を返し、送られてきたデータが記録と違えば食い違いとして残す。
+#[cfg(test)]
+pub struct ReplayBus
+{
+    state: Mutex<ReplayState>,
+    max_bulk_size: u32,
+
+    // true なら食い違いの時点でエラーを返す
+    strict: bool,
+
+    // true なら記録の経過時間に合わせて待ってから返す
+    realtime: bool,
+}
+
+#[cfg(test)]
+impl ReplayBus
+{
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BusError>
+    {
+        let file = File::open(path).map_err(|e| BusError::Other(format!("failed to open record file: {}", e)))?;
+        let reader = BufReader::new(file);
+
+        let mut entries = VecDeque::new();
+        let mut max_bulk_size = 512;
+
+        for (n, line) in reader.lines().enumerate()
+        {
+            let line = line.map_err(|e| BusError::Other(format!("failed to read record file: {}", e)))?;
+            let line = line.trim_end();
+            let bad_line = || BusError::Other(format!("invalid record at line {}: {}", n + 1, line));
+
+            if line.is_empty() || line.starts_with('#')
+            {
+                continue;
+            }
+
+            let mut fields = line.splitn(3, ' ');
+            let first = fields.next().ok_or_else(bad_line)?;
+
+            if first == "MAXBULK"
+            {
+                max_bulk_size = fields.next().and_then(|v| v.parse().ok()).ok_or_else(bad_line)?;
+                continue;
+            }
+
+            let timestamp_us = first.parse().map_err(|_| bad_line())?;
+            let tag = fields.next().ok_or_else(bad_line)?;
+            let rest = fields.next().unwrap_or("");
+
+            let (op, result) = if tag == "ERR"
+            {
+                let mut err = rest.splitn(3, ' ');
+                let op = err.next().and_then(RecordOp::from_tag).ok_or_else(bad_line)?;
+                let kind = err.next().ok_or_else(bad_line)?.to_string();
+                let desc = err.next().unwrap_or("").to_string();
+                (op, Err((kind, desc)))
+            }
+            else
+            {
+                let op = RecordOp::from_tag(tag).ok_or_else(bad_line)?;
+                (op, Ok(from_hex(rest).ok_or_else(bad_line)?))
+            };
+
+            entries.push_back(RecordEntry { timestamp_us, op, result });
+        }
+
+        Ok(Self
+        {
+            state: Mutex::new(ReplayState { entries, index: 0, mismatches: Vec::new(), pending_stream: Vec::new(), origin: None }),
+            max_bulk_size,
+            strict: true,
+            realtime: false,
+        })
+    }
+
+    pub fn set_strict(&mut self, strict: bool)
+    {
+        self.strict = strict;
+    }
+
+    pub fn set_realtime(&mut self, realtime: bool)
+    {
+        self.realtime = realtime;
+    }
+
+    pub fn mismatches(&self) -> Vec<ReplayMismatch>
+    {
+        self.state.lock().unwrap().mismatches.clone()
+    }
+
+    // 記録を最後まで使い切ったか
+    pub fn is_finished(&self) -> bool
+    {
+        self.state.lock().unwrap().entries.is_empty()
+    }
+
+    pub fn remaining(&self) -> usize
+    {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    // 次の記録を取り出す
+    // 操作の種類が違う場合は食い違いとして残して、記録は消費しない
+    fn next_entry(&self, state: &mut ReplayState, op: RecordOp, sent: &[u8]) -> Result<RecordEntry, BusError>
+    {
+        let matches = state.entries.front().map(|e| e.op == op).unwrap_or(false);
+        if !matches
+        {
+            let expected_op = state.entries.front().map(|e| e.op);
+            state.mismatches.push(ReplayMismatch { index: state.index, expected_op, actual_op: op, expected: Vec::new(), actual: sent.to_vec() });
+            return Err(BusError::Other(format!("replay: expected {:?} but got {:?} at #{}", expected_op, op, state.index)));
+        }
+
+        state.index += 1;
+        let entry = state.entries.pop_front().unwrap();
+
+        if self.realtime
+        {
+            let at = Duration::from_micros(entry.timestamp_us.min(u64::MAX as u128) as u64);
+            let origin = *state.origin.get_or_insert_with(|| Instant::now().checked_sub(at).unwrap_or_else(Instant::now));
+            let wait = (origin + at).saturating_duration_since(Instant::now());
+            if !wait.is_zero()
+            {
+                std::thread::sleep(wait);
+            }
+        }
+
+        Ok(entry)
+    }
+
+    fn entry_result(entry: RecordEntry) -> Result<Vec<u8>, BusError>
+    {
+        entry.result.map_err(|(kind, desc)| error_from_kind(&kind, &desc))
+    }
+}
+
+#[cfg(test)]
+impl BusOps for ReplayBus
+{
+    fn ctrl_tx(&self, buf: &[u8]) -> Result<(), BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+        let entry = self.next_entry(&mut state, RecordOp::CtrlTx, buf)?;
+        let index = state.index - 1;
+
+        let expected = entry.result.clone().unwrap_or_default();
+        if expected != buf
+        {
+            state.mismatches.push(ReplayMismatch { index, expected_op: Some(RecordOp::CtrlTx), actual_op: RecordOp::CtrlTx, expected: expected.clone(), actual: buf.to_vec() });
+            if self.strict
+            {
+                return Err(BusError::Other(format!("replay: ctrl_tx mismatch at #{}: expected {} got {}", index, to_hex(&expected), to_hex(buf))));
+            }
+        }
+
+        // 記録時に送信が失敗していたなら、そのエラーも再現する
+        if let Some(e) = state.entries.front()
+        {
+            if e.op == RecordOp::CtrlTx && e.result.is_err()
+            {
+                let e = self.next_entry(&mut state, RecordOp::CtrlTx, buf)?;
+                return Self::entry_result(e).map(|_| ());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ctrl_rx(&self, buf: &mut [u8]) -> Result<usize, BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+        let entry = self.next_entry(&mut state, RecordOp::CtrlRx, &[])?;
+        let data = Self::entry_result(entry)?;
+
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn stream_rx(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+
+        // 記録時より小さい buf で読まれた場合は、残りを次回に回す
+        if state.pending_stream.is_empty()
+        {
+            let entry = self.next_entry(&mut state, RecordOp::StreamRx, &[])?;
+            state.pending_stream = Self::entry_result(entry)?;
+        }
+
+        let len = state.pending_stream.len().min(buf.len());
+        buf[..len].copy_from_slice(&state.pending_stream[..len]);
+        state.pending_stream.drain(..len);
+        Ok(len)
+    }
+
+    fn start_streaming(&self) -> Result<(), BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+        let entry = self.next_entry(&mut state, RecordOp::StartStreaming, &[])?;
+        Self::entry_result(entry).map(|_| ())
+    }
+
+    fn stop_streaming(&self) -> Result<(), BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+        let entry = self.next_entry(&mut state, RecordOp::StopStreaming, &[])?;
+        Self::entry_result(entry).map(|_| ())
+    }
+
+    fn max_bulk_size(&self) -> u32
+    {
+        self.max_bulk_size
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::it930x::{GpioMode, IT930x};
+    use crate::it930x_sim::SimBus;
+
+    fn temp_path(name: &str) -> std::path::PathBuf
+    {
+        std::env::temp_dir().join(format!("px4_bus_record_{}_{}.txt", name, std::process::id()))
+    }
+
+    // 記録と再生で同じ手順を踏む
+    fn session<B: BusOps>(it930x: &IT930x<B>) -> (u32, Vec<u8>, bool)
+    {
+        it930x.raise().unwrap();
+        let version = it930x.read_firmware_version().unwrap();
+        it930x.init_warm().unwrap();
+        it930x.set_gpio_mode(7, GpioMode::Out, true).unwrap();
+        it930x.write_gpio(7, false).unwrap();
+
+        let bus = it930x.bus();
+        bus.start_streaming().unwrap();
+        let mut buf = [0u8; 8];
+        let len = bus.stream_rx(&mut buf, Duration::from_millis(10)).unwrap();
+        let timeout = matches!(bus.stream_rx(&mut buf[len..], Duration::from_millis(10)), Err(BusError::Timeout));
+        bus.stop_streaming().unwrap();
+
+        (version, buf[..len].to_vec(), timeout)
+    }
+
+    #[test]
+    fn record_then_replay_round_trip()
+    {
+        let path = temp_path("round_trip");
+
+        let sim = SimBus::new_booted();
+        sim.set_max_bulk_size(64);
+        sim.push_stream_data(&[0x47, 0x11, 0x22, 0x33]);
+        let recorded = session(&IT930x::new(RecordBus::new(sim.clone(), &path).unwrap()));
+        assert_eq!(recorded.1, [0x47, 0x11, 0x22, 0x33]);
+        assert!(recorded.2);
+
+        let replay = ReplayBus::open(&path).unwrap();
+        assert_eq!(replay.max_bulk_size(), 64);
+
+        let it930x = IT930x::new(replay);
+        let replayed = session(&it930x);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(replayed, recorded);
+        assert!(it930x.bus().is_finished());
+        assert!(it930x.bus().mismatches().is_empty());
+    }
+
+    #[test]
+    fn replay_reports_mismatch()
+    {
+        let path = temp_path("mismatch");
+
+        IT930x::new(RecordBus::new(SimBus::new_booted(), &path).unwrap()).read_firmware_version().unwrap();
+
+        let it930x = IT930x::new(ReplayBus::open(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+
+        // 記録とは違う操作
+        assert!(it930x.bus().start_streaming().is_err());
+        assert_eq!(it930x.bus().mismatches()[0].expected_op, Some(RecordOp::CtrlTx));
+        assert_eq!(it930x.bus().mismatches()[0].actual_op, RecordOp::StartStreaming);
+
+        // 記録とは違う内容
+        assert!(it930x.write_regs(0xd8c3, &[1]).is_err());
+        let mismatch = &it930x.bus().mismatches()[1];
+        assert_eq!(mismatch.expected_op, Some(RecordOp::CtrlTx));
+        assert_eq!(mismatch.index, 0);
+        assert_ne!(mismatch.expected, mismatch.actual);
+    }
+
+    #[test]
+    fn replay_without_strict_continues_past_mismatch()
+    {
+        let path = temp_path("lenient");
+
+        IT930x::new(RecordBus::new(SimBus::new_booted(), &path).unwrap()).read_firmware_version().unwrap();
+
+        let mut replay = ReplayBus::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        replay.set_strict(false);
+        let total = replay.remaining();
+
+        // 内容が違っても記録を消費して先に進む
+        replay.ctrl_tx(&[0x00]).unwrap();
+        assert_eq!(replay.remaining(), total - 1);
+        assert_eq!(replay.mismatches().len(), 1);
+        assert_eq!(replay.mismatches()[0].index, 0);
+    }
+
+    #[test]
+    fn replay_in_realtime_keeps_recorded_intervals()
+    {
+        let path = temp_path("realtime");
+        std::fs::write(&path, format!("{}\nMAXBULK 512\n1000 START\n81000 STREAM 47\n81500 STOP\n", RECORD_HEADER)).unwrap();
+
+        let mut replay = ReplayBus::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        replay.set_realtime(true);
+
+        replay.start_streaming().unwrap();
+        let start = Instant::now();
+        let mut buf = [0u8; 1];
+        assert_eq!(replay.stream_rx(&mut buf, Duration::ZERO).unwrap(), 1);
+        replay.stop_streaming().unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        assert!(replay.is_finished());
+    }
+}
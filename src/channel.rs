@@ -0,0 +1,187 @@
+// チャンネル名 → 選局パラメータ
+// recpt1 などで使われている名前をそのまま受け付ける。
+//   BS01_0〜BS23_3 : BS (奇数トランスポンダ) + スロット (TMCC の TSID 一覧の何番目か)
+//   CS2〜CS24      : CS110 (偶数トランスポンダ)
+//   13〜62         : 地デジ (UHF)
+// 周波数は kHz で、ISDB-S は RT710 に、ISDB-T は R850 に渡す値。
+
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::px4_device::System;
+
+// BS: 1ch が 1049.48MHz、トランスポンダ 2つごとに 38.36MHz
+const BS_BASE_FREQ: u32 = 1049480;
+const BS_STEP_FREQ: u32 = 38360;
+const BS_TP_MAX: u32 = 23;
+const BS_SLOT_MAX: u8 = 3;
+
+// CS110: 2ch が 1613MHz、トランスポンダ 2つごとに 40MHz
+const CS_BASE_FREQ: u32 = 1613000;
+const CS_STEP_FREQ: u32 = 40000;
+const CS_TP_MIN: u32 = 2;
+const CS_TP_MAX: u32 = 24;
+
+// UHF: 13ch の中心が 473 + 1/7 MHz、6MHz 間隔
+const UHF_BASE_FREQ: u32 = 473143;
+const UHF_STEP_FREQ: u32 = 6000;
+const UHF_CH_MIN: u32 = 13;
+const UHF_CH_MAX: u32 = 62;
+
+#[derive(Debug, Error)]
+pub enum ChannelError
+{
+    #[error("invalid channel name: {0}")]
+    InvalidName(String),
+    #[error("channel out of range: {0}")]
+    OutOfRange(String),
+}
+
+// 選局後に、どの TS を取るか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsidSelect
+{
+    // 選ばなくて良い (地デジ)
+    None,
+    // TMCC の TSID 一覧の何番目か
+    Slot(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Channel
+{
+    pub name: String,
+    pub system: System,
+    // kHz
+    pub freq: u32,
+    pub tsid: TsidSelect,
+}
+
+impl fmt::Display for Channel
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{} ({}, {} kHz", self.name, self.system, self.freq)?;
+        match self.tsid
+        {
+            TsidSelect::None => {}
+            TsidSelect::Slot(s) => write!(f, ", slot {}", s)?,
+        }
+        write!(f, ")")
+    }
+}
+
+pub fn bs_freq(tp: u32) -> u32
+{
+    BS_BASE_FREQ + (tp - 1) / 2 * BS_STEP_FREQ
+}
+
+pub fn cs_freq(tp: u32) -> u32
+{
+    CS_BASE_FREQ + (tp - CS_TP_MIN) / 2 * CS_STEP_FREQ
+}
+
+pub fn uhf_freq(ch: u32) -> u32
+{
+    UHF_BASE_FREQ + (ch - UHF_CH_MIN) * UHF_STEP_FREQ
+}
+
+fn parse_number(s: &str, name: &str) -> Result<u32, ChannelError>
+{
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit())
+    {
+        return Err(ChannelError::InvalidName(name.to_string()));
+    }
+    s.parse::<u32>().map_err(|_| ChannelError::InvalidName(name.to_string()))
+}
+
+// チャンネル名を解釈する (大文字小文字は問わない)
+pub fn parse_channel(name: &str) -> Result<Channel, ChannelError>
+{
+    let upper = name.trim().to_ascii_uppercase();
+    let out_of_range = || ChannelError::OutOfRange(name.to_string());
+
+    if let Some(rest) = upper.strip_prefix("BS")
+    {
+        // "BS15" は "BS15_0" と同じ扱い
+        let (tp, slot) = match rest.split_once('_')
+        {
+            Some((tp, slot)) => (parse_number(tp, name)?, parse_number(slot, name)?),
+            None => (parse_number(rest, name)?, 0),
+        };
+
+        if !(1..=BS_TP_MAX).contains(&tp) || tp.is_multiple_of(2) || slot > BS_SLOT_MAX as u32
+        {
+            return Err(out_of_range());
+        }
+
+        return Ok(Channel { name: format!("BS{:02}_{}", tp, slot), system: System::IsdbS, freq: bs_freq(tp), tsid: TsidSelect::Slot(slot as u8) });
+    }
+
+    if let Some(rest) = upper.strip_prefix("CS")
+    {
+        let tp = parse_number(rest, name)?;
+        if !(CS_TP_MIN..=CS_TP_MAX).contains(&tp) || !tp.is_multiple_of(2)
+        {
+            return Err(out_of_range());
+        }
+
+        return Ok(Channel { name: format!("CS{}", tp), system: System::IsdbS, freq: cs_freq(tp), tsid: TsidSelect::Slot(0) });
+    }
+
+    let ch = parse_number(&upper, name)?;
+    if !(UHF_CH_MIN..=UHF_CH_MAX).contains(&ch)
+    {
+        return Err(out_of_range());
+    }
+
+    Ok(Channel { name: format!("{}", ch), system: System::IsdbT, freq: uhf_freq(ch), tsid: TsidSelect::None })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn bs_channels()
+    {
+        let ch = parse_channel("BS15_1").unwrap();
+        assert_eq!(ch, Channel { name: "BS15_1".to_string(), system: System::IsdbS, freq: 1318000, tsid: TsidSelect::Slot(1) });
+
+        // スロット省略、小文字、ゼロ詰め無し
+        assert_eq!(parse_channel("bs1").unwrap(), Channel { name: "BS01_0".to_string(), system: System::IsdbS, freq: 1049480, tsid: TsidSelect::Slot(0) });
+        assert_eq!(parse_channel("BS23_3").unwrap().freq, 1471440);
+        assert_eq!(parse_channel(" BS03_2 ").unwrap().to_string(), "BS03_2 (ISDB-S, 1087840 kHz, slot 2)");
+    }
+
+    #[test]
+    fn cs_and_uhf_channels()
+    {
+        assert_eq!(parse_channel("CS2").unwrap(), Channel { name: "CS2".to_string(), system: System::IsdbS, freq: 1613000, tsid: TsidSelect::Slot(0) });
+        assert_eq!(parse_channel("cs24").unwrap().freq, 2053000);
+
+        assert_eq!(parse_channel("13").unwrap(), Channel { name: "13".to_string(), system: System::IsdbT, freq: 473143, tsid: TsidSelect::None });
+        assert_eq!(parse_channel("27").unwrap().freq, 557143);
+        assert_eq!(parse_channel("62").unwrap().to_string(), "62 (ISDB-T, 767143 kHz)");
+    }
+
+    #[test]
+    fn out_of_range()
+    {
+        for name in ["BS02_0", "BS25_0", "BS01_4", "CS3", "CS26", "CS0", "12", "63", "0"]
+        {
+            assert!(matches!(parse_channel(name), Err(ChannelError::OutOfRange(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_names()
+    {
+        for name in ["", "BS", "BS_1", "BS1_", "BS01_x", "CS", "CS+2", "-1", "27ch", "C27", "99999999999"]
+        {
+            assert!(matches!(parse_channel(name), Err(ChannelError::InvalidName(_))), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// コマンドライン (recpt1 互換)
+// recpt1 [--b25 [--round N] [--strip] [--EMM]] [--device devicefile] [--lnb voltage] [--sid SID1,SID2] channel rectime destfile
+// の形で呼ばれるので、それに合わせる。
+// ただし B25 の復号はできないので、--b25 はエラーにする (スクランブルされたままの TS を黙って書かないように)。
+// --round / --EMM は --b25 の付属なので、受け付けて無視する。
+// 既存のシェルスクリプトや EPGStation の設定から、そのまま呼べるようにするのが目的。
+
+use std::time::Duration;
+
+use crate::ts_splitter::{parse_sid_list, SidSelector};
+
+pub const USAGE: &str = "\
+Usage: px4-recpt1 [--device devicefile] [--serial serial] [--lnb voltage] [--sid SID1,SID2,...] [--strip] channel rectime destfile
+       px4-recpt1 --signal [--device devicefile] [--serial serial] [--lnb voltage] channel [rectime]
+       px4-recpt1 --list
+
+Options:
+  -d, --device devicefile   use the specified tuner (/dev/px4videoN or N)
+      --serial serial       use the device with this serial number (--device is then the tuner on it)
+      --list                list connected devices and their tuners
+      --disable-multi-device-power-control
+                            do not tie the power of PX-Q3U4/Q3PE4 halves together
+  -n, --lnb voltage         LNB power for BS/CS110 (15, 0 = off; 11V is not available on these devices)
+  -i, --sid SID1,SID2,...   keep only the specified services (also: hd, sd1, sd2, sd3, 1seg, all, epg)
+  -s, --strip               drop null packets
+  -b, --b25                 not supported (fails; pipe the output through an external B25 decoder)
+  -r, --round N, -m, --EMM  accepted and ignored for compatibility
+      --signal              print C/N and RF level every second instead of recording
+      --record-bus file     record the USB control/stream traffic to file (for replaying in tests)
+  -h, --help                show this help
+
+rectime   : recording time in seconds, or '-' to record until interrupted
+destfile  : output file, or '-' for stdout";
+
+// LNB の電源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LnbVoltage
+{
+    Off,
+    V15,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination
+{
+    Stdout,
+    File(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct RecOptions
+{
+    pub channel: String,
+    // None なら止められるまで録画
+    pub duration: Option<Duration>,
+    pub dest: Destination,
+
+    // px4videoN の N (serial があれば、そのデバイスの中での番号)
+    pub device: Option<u32>,
+    pub serial: Option<String>,
+    pub lnb: LnbVoltage,
+    pub sid: Vec<SidSelector>,
+    pub strip: bool,
+    // 録画せずに受信状態を表示する (checksignal 相当)
+    pub signal: bool,
+    // px4_drv の disable_multi_device_power_control
+    pub no_multi_device_power: bool,
+    // USB のやりとりを記録するファイル (bus_record.rs の形式)
+    pub record_bus: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum CliAction
+{
+    Record(RecOptions),
+    List,
+    Help,
+}
+
+// "/dev/px4video2" でも "2" でも受け付ける
+fn parse_device(s: &str) -> Result<u32, String>
+{
+    let digits: String = s.chars().rev().take_while(|c| c.is_ascii_digit()).collect::<Vec<_>>().into_iter().rev().collect();
+    if digits.is_empty()
+    {
+        return Err(format!("invalid device: {}", s));
+    }
+    digits.parse::<u32>().map_err(|_| format!("invalid device: {}", s))
+}
+
+fn parse_lnb(s: &str) -> Result<LnbVoltage, String>
+{
+    match s
+    {
+        "0" | "off" => Ok(LnbVoltage::Off),
+        // PX4 系の LNB 電源は 15V 固定で、11V には切り替えられない
+        "11" => Err("--lnb 11 is not supported: the LNB supply of these devices is fixed at 15V. use --lnb 15.".to_string()),
+        "15" => Ok(LnbVoltage::V15),
+        _ => Err(format!("invalid lnb voltage: {}", s)),
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Option<Duration>, String>
+{
+    if s == "-"
+    {
+        return Ok(None);
+    }
+
+    match s.parse::<u64>()
+    {
+        Ok(sec) if sec > 0 => Ok(Some(Duration::from_secs(sec))),
+        _ => Err(format!("invalid rectime: {}", s)),
+    }
+}
+
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<CliAction, String>
+{
+    let mut device = None;
+    let mut serial = None;
+    let mut lnb = LnbVoltage::Off;
+    let mut sid = Vec::new();
+    let mut strip = false;
+    let mut signal = false;
+    let mut no_multi_device_power = false;
+    let mut record_bus = None;
+    let mut positional = Vec::new();
+
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next()
+    {
+        // "--sid=101" の形も受け付ける
+        let (name, inline) = match arg.split_once('=')
+        {
+            Some((n, v)) if arg.starts_with("--") => (n.to_string(), Some(v.to_string())),
+            _ => (arg.clone(), None),
+        };
+
+        let mut value = |opt: &str| -> Result<String, String>
+        {
+            inline.clone().or_else(|| it.next()).ok_or_else(|| format!("option {} requires an argument", opt))
+        };
+
+        match name.as_str()
+        {
+            "-h" | "--help" => return Ok(CliAction::Help),
+            "--list" => return Ok(CliAction::List),
+            "--serial" => serial = Some(value(&name)?),
+            "-d" | "--device" | "--dev" => device = Some(parse_device(&value(&name)?)?),
+            "-n" | "--lnb" => lnb = parse_lnb(&value(&name)?)?,
+            "-i" | "--sid" => sid = parse_sid_list(&value(&name)?)?,
+            "-s" | "--strip" => strip = true,
+            "-b" | "--b25" => return Err("--b25 is not supported: descrambling is not available. record without it and decode the output with an external B25 decoder.".to_string()),
+            "-r" | "--round" => { value(&name)?; }
+            "-m" | "--EMM" => {}
+            "--signal" => signal = true,
+            "--disable-multi-device-power-control" => no_multi_device_power = true,
+            "--record-bus" => record_bus = Some(value(&name)?),
+            "-" => positional.push(arg),
+            _ if name.starts_with('-') && name.len() > 1 => return Err(format!("unknown option: {}", name)),
+            _ => positional.push(arg),
+        }
+    }
+
+    // --signal のときは destfile は要らない (rectime も省略できる)
+    let valid = if signal { (1..=2).contains(&positional.len()) } else { positional.len() == 3 };
+    if !valid
+    {
+        return Err(if signal { "channel is required" } else { "channel, rectime and destfile are required" }.to_string());
+    }
+
+    let duration = match positional.get(1)
+    {
+        Some(s) => parse_duration(s)?,
+        None => None,
+    };
+
+    let dest = match positional.get(2).map(|s| s.as_str())
+    {
+        Some("-") | None => Destination::Stdout,
+        Some(path) => Destination::File(path.to_string()),
+    };
+
+    Ok(CliAction::Record(RecOptions
+    {
+        channel: positional[0].clone(),
+        duration,
+        dest,
+        device,
+        serial,
+        lnb,
+        sid,
+        strip,
+        signal,
+        no_multi_device_power,
+        record_bus,
+    }))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliAction, String>
+    {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    fn record(args: &[&str]) -> RecOptions
+    {
+        match parse(args)
+        {
+            Ok(CliAction::Record(opts)) => opts,
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn recpt1_style_arguments()
+    {
+        let opts = record(&["--device", "/dev/px4video2", "--lnb", "15", "--sid=hd,epg", "--strip", "BS01_0", "60", "out.ts"]);
+
+        assert_eq!(opts.channel, "BS01_0");
+        assert_eq!(opts.duration, Some(Duration::from_secs(60)));
+        assert_eq!(opts.dest, Destination::File("out.ts".to_string()));
+        assert_eq!(opts.device, Some(2));
+        assert_eq!(opts.lnb, LnbVoltage::V15);
+        assert_eq!(opts.sid, [SidSelector::Hd, SidSelector::Epg]);
+        assert!(opts.strip);
+        assert!(!opts.signal);
+    }
+
+    #[test]
+    fn stdout_and_unlimited_time()
+    {
+        let opts = record(&["-d", "3", "27", "-", "-"]);
+
+        assert_eq!(opts.device, Some(3));
+        assert_eq!(opts.duration, None);
+        assert_eq!(opts.dest, Destination::Stdout);
+        assert_eq!(opts.lnb, LnbVoltage::Off);
+    }
+
+    #[test]
+    fn signal_mode_needs_only_channel()
+    {
+        let opts = record(&["--signal", "--serial", "ABC123", "27"]);
+
+        assert!(opts.signal);
+        assert_eq!(opts.serial.as_deref(), Some("ABC123"));
+        assert_eq!(opts.duration, None);
+
+        assert!(parse(&["--signal"]).is_err());
+        assert!(parse(&["27", "60"]).is_err());
+    }
+
+    #[test]
+    fn b25_is_rejected_but_its_options_are_ignored()
+    {
+        assert!(parse(&["--b25", "27", "60", "out.ts"]).unwrap_err().contains("--b25"));
+        assert!(parse(&["-b", "27", "60", "out.ts"]).is_err());
+
+        // --round は値を取るので、それが channel として扱われないこと
+        let opts = record(&["--round", "4", "--EMM", "-m", "-r", "2", "27", "60", "out.ts"]);
+        assert_eq!(opts.channel, "27");
+        assert!(parse(&["27", "60", "out.ts", "--round"]).is_err());
+    }
+
+    #[test]
+    fn lnb_voltages()
+    {
+        assert_eq!(record(&["--lnb", "0", "BS01_0", "1", "-"]).lnb, LnbVoltage::Off);
+        assert_eq!(record(&["-n", "off", "BS01_0", "1", "-"]).lnb, LnbVoltage::Off);
+        assert!(parse(&["--lnb", "11", "BS01_0", "1", "-"]).unwrap_err().contains("15V"));
+        assert!(parse(&["--lnb", "18", "BS01_0", "1", "-"]).is_err());
+    }
+
+    #[test]
+    fn invalid_arguments()
+    {
+        assert!(parse(&["27", "0", "out.ts"]).is_err());
+        assert!(parse(&["27", "abc", "out.ts"]).is_err());
+        assert!(parse(&["--device", "px4video", "27", "60", "out.ts"]).is_err());
+        assert!(parse(&["--sid", "hd,foo", "27", "60", "out.ts"]).is_err());
+        assert!(parse(&["--unknown", "27", "60", "out.ts"]).unwrap_err().contains("--unknown"));
+        assert!(parse(&["--device"]).is_err());
+    }
+
+    #[test]
+    fn other_actions()
+    {
+        assert!(matches!(parse(&["--list"]), Ok(CliAction::List)));
+        assert!(matches!(parse(&["27", "-h"]), Ok(CliAction::Help)));
+
+        let opts = record(&["--disable-multi-device-power-control", "--record-bus", "bus.txt", "27", "1", "-"]);
+        assert!(opts.no_multi_device_power);
+        assert_eq!(opts.record_bus.as_deref(), Some("bus.txt"));
+    }
+}
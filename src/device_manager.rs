@@ -0,0 +1,156 @@
+// 複数台の管理
+// device_profile.rs の表に載っている USB デバイスを全部拾って、シリアル番号を読んでおく。
+// 並びはシリアル番号順にしておくので、挿し直しや再起動で USB のバス / アドレスが変わっても
+// 「N 台目」や px4videoN は同じ箱を指す。
+
+use rusb::{Context, Device, DeviceHandle, UsbContext};
+use thiserror::Error;
+
+use crate::device_profile::{find_profile, DeviceProfile};
+
+#[derive(Debug, Error)]
+pub enum DeviceError
+{
+    #[error("USB error: {0}")]
+    Usb(#[from] rusb::Error),
+    #[error("device #{0} not found.")]
+    IndexNotFound(usize),
+    #[error("device with serial {0} not found.")]
+    SerialNotFound(String),
+    #[error("px4video{0} not found.")]
+    ChrdevNotFound(u32),
+}
+
+// どの 1台を開くか
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector
+{
+    Index(usize),
+    Serial(String),
+}
+
+pub struct DeviceInfo
+{
+    pub profile: &'static DeviceProfile,
+    // USB の文字列ディスクリプタから (読めなければ None)
+    pub serial: Option<String>,
+    pub bus_number: u8,
+    pub address: u8,
+    device: Device<Context>,
+}
+
+impl DeviceInfo
+{
+    // 校正キャッシュなどのキーに使う名前
+    // シリアル番号が読めなければ bus / address で代用
+    pub fn key(&self) -> String
+    {
+        self.serial.clone().unwrap_or_else(|| format!("bus{}-addr{}", self.bus_number, self.address))
+    }
+
+    pub fn device(&self) -> &Device<Context>
+    {
+        &self.device
+    }
+}
+
+pub struct DeviceManager
+{
+    context: Context,
+    devices: Vec<DeviceInfo>,
+}
+
+impl DeviceManager
+{
+    pub fn new() -> Result<Self, DeviceError>
+    {
+        let mut manager = Self { context: Context::new()?, devices: Vec::new() };
+        manager.scan()?;
+        Ok(manager)
+    }
+
+    pub fn context(&self) -> &Context
+    {
+        &self.context
+    }
+
+    // 繋がっているデバイスを数え直す
+    pub fn scan(&mut self) -> Result<(), DeviceError>
+    {
+        let mut devices = Vec::new();
+
+        for device in self.context.devices()?.iter()
+        {
+            let desc = match device.device_descriptor()
+            {
+                Ok(desc) => desc,
+                Err(_) => continue,
+            };
+
+            let profile = match find_profile(desc.vendor_id(), desc.product_id())
+            {
+                Some(p) => p,
+                None => continue,
+            };
+
+            // シリアル番号を読むためだけに一瞬開く (他で使っていても読める)
+            let serial = device.open().ok().and_then(|h| h.read_serial_number_string_ascii(&desc).ok());
+
+            devices.push(DeviceInfo { profile, serial, bus_number: device.bus_number(), address: device.address(), device });
+        }
+
+        // シリアル番号のあるものを先に、番号順。無いものは bus / address 順
+        devices.sort_by(|a, b|
+        {
+            (a.serial.is_none(), &a.serial, a.bus_number, a.address).cmp(&(b.serial.is_none(), &b.serial, b.bus_number, b.address))
+        });
+
+        self.devices = devices;
+        Ok(())
+    }
+
+    pub fn devices(&self) -> &[DeviceInfo]
+    {
+        &self.devices
+    }
+
+    pub fn find(&self, selector: &DeviceSelector) -> Result<(usize, &DeviceInfo), DeviceError>
+    {
+        match selector
+        {
+            DeviceSelector::Index(i) => self.devices.get(*i).map(|d| (*i, d)).ok_or(DeviceError::IndexNotFound(*i)),
+            DeviceSelector::Serial(s) => self.devices.iter().enumerate()
+                .find(|(_, d)| d.serial.as_deref() == Some(s.as_str()))
+                .ok_or_else(|| DeviceError::SerialNotFound(s.clone())),
+        }
+    }
+
+    // px4videoN の N を (何台目か, その中の何番目のチューナーか) にする
+    // N は、並び順にチューナーの数を積み上げた通し番号
+    pub fn resolve_chrdev(&self, n: u32) -> Result<(usize, usize), DeviceError>
+    {
+        let mut base = 0u32;
+        for (i, d) in self.devices.iter().enumerate()
+        {
+            let num = d.profile.inputs.len() as u32;
+            if n < base + num
+            {
+                return Ok((i, (n - base) as usize));
+            }
+            base += num;
+        }
+
+        Err(DeviceError::ChrdevNotFound(n))
+    }
+
+    // 開いてインターフェース 0 を占有する
+    pub fn open(&self, selector: &DeviceSelector) -> Result<(&DeviceInfo, DeviceHandle<Context>), DeviceError>
+    {
+        let (_, info) = self.find(selector)?;
+
+        let handle = info.device.open()?;
+        handle.claim_interface(0)?;
+
+        Ok((info, handle))
+    }
+}
@@ -0,0 +1,253 @@
+// 対応機種ごとの構成
+// px4_drv は機種ごとに px4_device.c / pxmlt_device.c / s1ur_device.c / m1ur_device.c / isdb2056_device.c と
+// 別々に書かれているが、IT930x から見た違いは
+//   - 入力ポートごとの I2C バス / アドレス、port / slave 番号、同期バイト
+//   - 復調部とチューナーの組み合わせ
+//   - 電源を入れる GPIO の手順と、LNB 電源の GPIO
+// くらいなので、ここで表にまとめて IT930x / Px4Device はこれを見て組み立てる。
+//
+// 今あるドライバは TC90522 + RT710 / R850 だけなので、CXD2856ER を使う機種 (PX-MLT, PX-M1UR, DTV02A-1T1S-U) は
+// 表には載せておくが、Px4Device::init で未対応として弾く。
+
+use std::time::Duration;
+
+use crate::px4_device::System;
+
+pub const PLEX_VID: u16 = 0x0511;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceModel
+{
+    PxW3U4,
+    PxQ3U4,
+    PxW3PE4,
+    PxQ3PE4,
+    PxMlt5U,
+    PxMlt5Pe,
+    PxMlt8Pe3,
+    PxMlt8Pe5,
+    PxS1Ur,
+    PxM1Ur,
+    // e-Better DTV02A-1T1S-U (DIGIBEST ISDB2056)
+    Dtv02a1t1sU,
+    // e-Better DTV02A-4TS-P (DIGIBEST ISDB6014)
+    Dtv02a4tsP,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DemodKind
+{
+    Tc90522,
+    Cxd2856er,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunerKind
+{
+    Rt710,
+    R850,
+    Cxd2858er,
+}
+
+// IT930x の入力ポート 1つ分 (= チャンネル 1つ)
+#[derive(Debug, Clone, Copy)]
+pub struct InputProfile
+{
+    // 受けられる放送。先頭が既定 (MLT などは地上 / 衛星の両方)
+    pub systems: &'static [System],
+    pub demod: DemodKind,
+    pub tuner: TunerKind,
+
+    // 復調部の I2C バスとアドレス (チューナーは復調部のゲートの先)
+    pub i2c_bus: u8,
+    pub i2c_addr: u8,
+
+    pub port_number: u8,
+    pub slave_number: u8,
+    pub sync_byte: u8,
+}
+
+// 電源操作の 1手順 (GPIO を書いてから wait 待つ)
+#[derive(Debug, Clone, Copy)]
+pub struct GpioStep
+{
+    pub gpio: i32,
+    pub high: bool,
+    pub wait: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceProfile
+{
+    pub model: DeviceModel,
+    pub name: &'static str,
+    pub vid: u16,
+    pub pid: u16,
+
+    pub inputs: &'static [InputProfile],
+
+    // 出力に設定する GPIO と、その初期値 (電源は切った状態)
+    pub gpio_init: &'static [(i32, bool)],
+    pub power_on: &'static [GpioStep],
+    pub power_off: &'static [GpioStep],
+    // LNB 電源の GPIO (無い機種は None)
+    pub lnb_gpio: Option<i32>,
+
+    pub i2c_speed: u8,
+    // 1回の bulk 転送で受け取るパケット数 (xfer_size = 188 * これ)
+    pub xfer_packets: u32,
+}
+
+impl DeviceProfile
+{
+    // 今のドライバで扱える機種か
+    pub fn is_supported(&self) -> bool
+    {
+        self.inputs.iter().all(|i| i.demod == DemodKind::Tc90522 && i.tuner != TunerKind::Cxd2858er)
+    }
+
+    // Q3U4 / Q3PE4 のように、2つの USB デバイスで電源を共有しているか (px4_mldev.c)
+    pub fn is_multi_device(&self) -> bool
+    {
+        matches!(self.model, DeviceModel::PxQ3U4 | DeviceModel::PxQ3PE4)
+    }
+}
+
+const fn ms(v: u64) -> Duration
+{
+    Duration::from_millis(v)
+}
+
+const fn sync_byte(i: u8) -> u8
+{
+    ((i + 1) << 4) | 0x07
+}
+
+const ISDB_S_ONLY: &[System] = &[System::IsdbS];
+const ISDB_T_ONLY: &[System] = &[System::IsdbT];
+const ISDB_T_AND_S: &[System] = &[System::IsdbT, System::IsdbS];
+
+const fn tc90522_input(system: &'static [System], tuner: TunerKind, i2c_bus: u8, i2c_addr: u8, i: u8) -> InputProfile
+{
+    InputProfile { systems: system, demod: DemodKind::Tc90522, tuner, i2c_bus, i2c_addr, port_number: i + 1, slave_number: i, sync_byte: sync_byte(i) }
+}
+
+const fn cxd2856er_input(i2c_bus: u8, i2c_addr: u8, port_number: u8, i: u8) -> InputProfile
+{
+    InputProfile { systems: ISDB_T_AND_S, demod: DemodKind::Cxd2856er, tuner: TunerKind::Cxd2858er, i2c_bus, i2c_addr, port_number, slave_number: i, sync_byte: sync_byte(i) }
+}
+
+// px4_device.c の PX4 系 (W3U4 / W3PE4 / DTV02A-4TS-P、Q3 は W3 が USB デバイス 2つに見える)
+// 衛星が 0x11 / 0x13、地上が 0x10 / 0x12 で、I2C バスは 2
+const PX4_INPUTS: &[InputProfile] = &[
+    tc90522_input(ISDB_S_ONLY, TunerKind::Rt710, 2, 0x11, 0),
+    tc90522_input(ISDB_S_ONLY, TunerKind::Rt710, 2, 0x13, 1),
+    tc90522_input(ISDB_T_ONLY, TunerKind::R850, 2, 0x10, 2),
+    tc90522_input(ISDB_T_ONLY, TunerKind::R850, 2, 0x12, 3),
+];
+
+// px4_device.c の px4_backend_set_power
+const PX4_GPIO_INIT: &[(i32, bool)] = &[(7, true), (2, false)];
+const PX4_POWER_ON: &[GpioStep] = &[
+    GpioStep { gpio: 7, high: false, wait: ms(80) },
+    GpioStep { gpio: 2, high: true, wait: ms(20) },
+];
+const PX4_POWER_OFF: &[GpioStep] = &[
+    GpioStep { gpio: 2, high: false, wait: ms(0) },
+    GpioStep { gpio: 7, high: true, wait: ms(0) },
+];
+
+// s1ur_device.c: 地上 1ch、I2C バスは 3
+const S1UR_INPUTS: &[InputProfile] = &[
+    tc90522_input(ISDB_T_ONLY, TunerKind::R850, 3, 0x10, 0),
+];
+
+// pxmlt_device.c: 入力ごとに I2C バスが違い、port 番号も並びが違う
+const PXMLT5_INPUTS: &[InputProfile] = &[
+    cxd2856er_input(3, 0x65, 4, 0),
+    cxd2856er_input(1, 0x6c, 3, 1),
+    cxd2856er_input(1, 0x64, 1, 2),
+    cxd2856er_input(3, 0x6c, 2, 3),
+    cxd2856er_input(1, 0x6d, 0, 4),
+];
+
+// MLT8 は 3ch と 5ch の 2つの USB デバイスに見える
+const PXMLT8PE3_INPUTS: &[InputProfile] = &[
+    cxd2856er_input(3, 0x65, 4, 0),
+    cxd2856er_input(1, 0x6c, 3, 1),
+    cxd2856er_input(1, 0x64, 1, 2),
+];
+
+// m1ur_device.c / isdb2056_device.c: 1ch、I2C バスは 3
+const SINGLE_CXD_INPUTS: &[InputProfile] = &[
+    cxd2856er_input(3, 0x65, 0, 0),
+];
+
+// pxmlt_device.c などの set_power (gpio3 を落としてから gpio2 を上げる)
+const CXD_GPIO_INIT: &[(i32, bool)] = &[(3, true), (2, false)];
+const CXD_POWER_ON: &[GpioStep] = &[
+    GpioStep { gpio: 3, high: false, wait: ms(100) },
+    GpioStep { gpio: 2, high: true, wait: ms(20) },
+];
+const CXD_POWER_OFF: &[GpioStep] = &[
+    GpioStep { gpio: 2, high: false, wait: ms(0) },
+    GpioStep { gpio: 3, high: true, wait: ms(0) },
+];
+
+const fn px4_profile(model: DeviceModel, name: &'static str, pid: u16) -> DeviceProfile
+{
+    DeviceProfile
+    {
+        model, name, vid: PLEX_VID, pid,
+        inputs: PX4_INPUTS,
+        gpio_init: PX4_GPIO_INIT, power_on: PX4_POWER_ON, power_off: PX4_POWER_OFF,
+        lnb_gpio: Some(11),
+        i2c_speed: 0x07,
+        // px4_usb_params.c の .xfer_packets = 816
+        xfer_packets: 816,
+    }
+}
+
+const fn cxd_profile(model: DeviceModel, name: &'static str, pid: u16, inputs: &'static [InputProfile]) -> DeviceProfile
+{
+    DeviceProfile
+    {
+        model, name, vid: PLEX_VID, pid,
+        inputs,
+        gpio_init: CXD_GPIO_INIT, power_on: CXD_POWER_ON, power_off: CXD_POWER_OFF,
+        lnb_gpio: None,
+        i2c_speed: 0x07,
+        xfer_packets: 816,
+    }
+}
+
+// px4_usb.c の px4_usb_ids の並び
+pub const PX_W3U4: DeviceProfile = px4_profile(DeviceModel::PxW3U4, "PX-W3U4", 0x083f);
+
+pub const DEVICE_PROFILES: &[DeviceProfile] = &[
+    PX_W3U4,
+    px4_profile(DeviceModel::PxQ3U4, "PX-Q3U4", 0x084a),
+    px4_profile(DeviceModel::PxW3PE4, "PX-W3PE4", 0x023f),
+    px4_profile(DeviceModel::PxQ3PE4, "PX-Q3PE4", 0x024a),
+    cxd_profile(DeviceModel::PxMlt5U, "PX-MLT5U", 0x084e, PXMLT5_INPUTS),
+    cxd_profile(DeviceModel::PxMlt5Pe, "PX-MLT5PE", 0x024e, PXMLT5_INPUTS),
+    cxd_profile(DeviceModel::PxMlt8Pe3, "PX-MLT8PE3", 0x0252, PXMLT8PE3_INPUTS),
+    cxd_profile(DeviceModel::PxMlt8Pe5, "PX-MLT8PE5", 0x0253, PXMLT5_INPUTS),
+    cxd_profile(DeviceModel::Dtv02a1t1sU, "DTV02A-1T1S-U", 0x004b, SINGLE_CXD_INPUTS),
+    px4_profile(DeviceModel::Dtv02a4tsP, "DTV02A-4TS-P", 0x0254),
+    cxd_profile(DeviceModel::PxM1Ur, "PX-M1UR", 0x0854, SINGLE_CXD_INPUTS),
+    DeviceProfile
+    {
+        model: DeviceModel::PxS1Ur, name: "PX-S1UR", vid: PLEX_VID, pid: 0x0855,
+        inputs: S1UR_INPUTS,
+        gpio_init: PX4_GPIO_INIT, power_on: PX4_POWER_ON, power_off: PX4_POWER_OFF,
+        lnb_gpio: None,
+        i2c_speed: 0x07,
+        xfer_packets: 816,
+    },
+];
+
+pub fn find_profile(vid: u16, pid: u16) -> Option<&'static DeviceProfile>
+{
+    DEVICE_PROFILES.iter().find(|p| p.vid == vid && p.pid == pid)
+}
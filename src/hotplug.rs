@@ -0,0 +1,142 @@
+// USB の抜き差しの監視
+// libusb の hotplug (rusb::HotplugBuilder) で、device_profile.rs の表に載っているデバイスの抜き差しを拾う。
+//   - 抜かれたら、watch で渡されたフラグ (UsbBusRusb::disconnect_flag) を立てる。
+//     → ctrl_msg や stream_rx で待っている側は、タイムアウトを待たずに BusError::Disconnected で返ってくる
+//   - 挿されたら、wait_event で受け取れるので、上位で開き直して初期化し直す
+// hotplug のコールバックの中では同期転送をしてはいけないので、ここでは通知するだけにしている。
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rusb::{Context, Device, Hotplug, HotplugBuilder, Registration, UsbContext};
+
+use crate::device_profile::{find_profile, DeviceProfile};
+use crate::itedtv_bus::BusError;
+
+// handle_events 1回あたりの待ち時間 (停止要求を確認する間隔も兼ねる)
+const HOTPLUG_EVENT_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub enum HotplugEvent
+{
+    Arrived { bus_number: u8, address: u8, profile: &'static DeviceProfile },
+    Left { bus_number: u8, address: u8 },
+}
+
+type WatchMap = Arc<Mutex<HashMap<(u8, u8), Arc<AtomicBool>>>>;
+
+struct HotplugHandler
+{
+    tx: Sender<HotplugEvent>,
+    watched: WatchMap,
+}
+
+impl Hotplug<Context> for HotplugHandler
+{
+    fn device_arrived(&mut self, device: Device<Context>)
+    {
+        // ディスクリプタは libusb がキャッシュしているので、ここで読んでも大丈夫
+        let profile = match device.device_descriptor().ok().and_then(|d| find_profile(d.vendor_id(), d.product_id()))
+        {
+            Some(p) => p,
+            None => return,
+        };
+
+        let _ = self.tx.send(HotplugEvent::Arrived { bus_number: device.bus_number(), address: device.address(), profile });
+    }
+
+    fn device_left(&mut self, device: Device<Context>)
+    {
+        let key = (device.bus_number(), device.address());
+        if let Some(flag) = self.watched.lock().unwrap().remove(&key)
+        {
+            flag.store(true, Ordering::SeqCst);
+        }
+
+        let _ = self.tx.send(HotplugEvent::Left { bus_number: key.0, address: key.1 });
+    }
+}
+
+pub struct HotplugWatcher
+{
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    rx: Receiver<HotplugEvent>,
+    watched: WatchMap,
+
+    // drop するとコールバックの登録が外れる (thread を止めてから外す)
+    _registration: Registration<Context>,
+}
+
+impl HotplugWatcher
+{
+    // libusb が hotplug に対応していなければ BusError::Usb(NotSupported)
+    pub fn start(context: &Context) -> Result<Self, BusError>
+    {
+        if !rusb::has_hotplug()
+        {
+            return Err(BusError::Usb(rusb::Error::NotSupported));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let watched: WatchMap = Arc::new(Mutex::new(HashMap::new()));
+
+        let registration = HotplugBuilder::new()
+            .enumerate(false)
+            .register(context.clone(), Box::new(HotplugHandler { tx, watched: Arc::clone(&watched) }))?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let running_t = Arc::clone(&running);
+        let context_t = context.clone();
+
+        let thread = thread::Builder::new()
+            .name("px4-hotplug".to_string())
+            .spawn(move ||
+            {
+                while running_t.load(Ordering::SeqCst)
+                {
+                    if let Err(e) = context_t.handle_events(Some(HOTPLUG_EVENT_TIMEOUT))
+                    {
+                        eprintln!("[hotplug] handle_events failed: {}", e);
+                        thread::sleep(HOTPLUG_EVENT_TIMEOUT);
+                    }
+                }
+            })
+            .map_err(|e| BusError::Other(format!("failed to spawn hotplug thread: {}", e)))?;
+
+        Ok(Self { running, thread: Some(thread), rx, watched, _registration: registration })
+    }
+
+    // bus / address のデバイスが抜かれたら flag を立てる
+    pub fn watch(&self, bus_number: u8, address: u8, flag: Arc<AtomicBool>)
+    {
+        self.watched.lock().unwrap().insert((bus_number, address), flag);
+    }
+
+    pub fn unwatch(&self, bus_number: u8, address: u8)
+    {
+        self.watched.lock().unwrap().remove(&(bus_number, address));
+    }
+
+    // 次の抜き差しを待つ (timeout を過ぎたら None)
+    pub fn wait_event(&self, timeout: Duration) -> Option<HotplugEvent>
+    {
+        self.rx.recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for HotplugWatcher
+{
+    fn drop(&mut self)
+    {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(t) = self.thread.take()
+        {
+            let _ = t.join();
+        }
+    }
+}
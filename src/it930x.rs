@@ -18,6 +18,7 @@ pub struct I2CCommRequest<'a>
 }
 
 use crate::itedtv_bus::{BusError, BusOps};
+use crate::device_profile::{DeviceProfile, PX_W3U4};
 
 // エラー型
 // 
@@ -44,7 +45,6 @@ pub enum CtrlMsgError
     IO(#[from] std::io::Error),
 }
 
-use std::os::macos::raw::stat;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Mutex;
 // シーケンス管理
@@ -61,12 +61,10 @@ pub struct IT930x<B: BusOps>
 }
 
 // Checksum ... it930x.c 58 〜 76 の移植
-fn checksum(buf: &[u8]) -> u16
+pub fn checksum(buf: &[u8]) -> u16
 {
     let mut sum: u16 = 0;
-    let mut iter = buf.chunks(2);
-
-    while let Some(chunk) = iter.next()
+    for chunk in buf.chunks(2)
     {
         let word = match chunk
         {
@@ -80,16 +78,6 @@ fn checksum(buf: &[u8]) -> u16
 }
 
 
-// debug用
-fn dump_hex(label: &str, data: &[u8]) {
-    print!("{label} ({}):", data.len());
-    for b in data {
-        print!(" {:02X}", b);
-    }
-    println!();
-}
-
-
 impl<B: BusOps> IT930x<B>
 {
     // it930x.c 78〜176 の移植 ... おそらく Mutex が要るので、あとで調査する。
@@ -140,10 +128,6 @@ impl<B: BusOps> IT930x<B>
         let mut rx = [0u8; 256];
         let rlen = self.bus.ctrl_rx(&mut rx).map_err(CtrlMsgError::Bus)?;
 
-        // debug
-        dump_hex("CTRL_MSG WB", &tx);
-        dump_hex("CTRL_MSG RB (expect)", &rx[0..rlen]);
-
         // packet size validate
         //let len = rx[0] as usize;
         //if len != rx_len - 1 // この辺も、想定通りに動くか？ (ctrl_rx の 読み込み buffer サイズは変わったりしないか？)
@@ -202,13 +186,13 @@ impl<B: BusOps> IT930x<B>
 // IT930x の 内部レジスタ を 読み書き するための 最小API
 // (直接 ctrl_msg を使わず、意味のある操作のAPIとする箇所)
 // 操作コマンドリスト
-const IT930X_CMD_REG_READ: u16 = 0x0000; // u32じゃね？ ... ctrl_msg の cmd を u16 にしてるので、一旦、u16で……。
-const IT930X_CMD_REG_WRITE: u16 = 0x0001;
-const IT930X_CMD_QUERYINFO: u16 = 0x0022;
-const IT930X_CMD_BOOT: u16 = 0x0023;
-const IT930X_CMD_FW_SCATTER_WRITE: u16 = 0x0029;
-const IT930X_CMD_I2C_READ: u16 = 0x002a;
-const IT930X_CMD_I2C_WRITE: u16 = 0x002b;
+pub const IT930X_CMD_REG_READ: u16 = 0x0000; // u32じゃね？ ... ctrl_msg の cmd を u16 にしてるので、一旦、u16で……。
+pub const IT930X_CMD_REG_WRITE: u16 = 0x0001;
+pub const IT930X_CMD_QUERYINFO: u16 = 0x0022;
+pub const IT930X_CMD_BOOT: u16 = 0x0023;
+pub const IT930X_CMD_FW_SCATTER_WRITE: u16 = 0x0029;
+pub const IT930X_CMD_I2C_READ: u16 = 0x002a;
+pub const IT930X_CMD_I2C_WRITE: u16 = 0x002b;
 
 // it930x.c 44 〜 56 の移植
 fn it930x_reg_length(reg: u32) -> u8
@@ -297,7 +281,6 @@ pub struct StreamInput
     pub slave_number: u8,
     pub i2c_bus: u8,
     pub i2c_addr: u8,
-    pub packet_len: u8,
     pub sync_byte: u8,
 }
 
@@ -305,82 +288,61 @@ pub struct IT930xConfig
 {
     pub i2c_speed: u8,
     pub xfer_size: u32,
-    pub inputs: [StreamInput; 5],
+    pub inputs: [StreamInput; IT930X_INPUT_NUM],
 }
 
-// 
-impl Default for IT930xConfig
+// IT930x は入力ポートが 5つまで
+pub const IT930X_INPUT_NUM: usize = 5;
+
+impl IT930xConfig
 {
-    fn default() -> Self 
+    // 機種の構成表から組み立てる。使わないポートは disable のまま
+    pub fn from_profile(profile: &DeviceProfile) -> Self
     {
-        let inputs =
-        [
-            StreamInput
-            {
-                enable: true,
-                is_parallel: false,
-                port_number: 1,
-                slave_number: 0,
-                i2c_bus: 2,
-                i2c_addr: 0x11,
-                packet_len: 188,
-                sync_byte: 0x17,
-            },
-            StreamInput
-            {
-                enable: true,
-                is_parallel: false,
-                port_number: 2,
-                slave_number: 1,
-                i2c_bus: 2,
-                i2c_addr: 0x13,
-                packet_len: 188,
-                sync_byte: 0x27,
-            },
-            StreamInput
-            {
-                enable: true,
-                is_parallel: false,
-                port_number: 3,
-                slave_number: 2,
-                i2c_bus: 2,
-                i2c_addr: 0x10,
-                packet_len: 188,
-                sync_byte: 0x37,
-            },
-            StreamInput
+        let mut inputs: [StreamInput; IT930X_INPUT_NUM] = std::array::from_fn(|_| StreamInput
+        {
+            enable: false,
+            is_parallel: false,
+            port_number: 0,
+            slave_number: 0,
+            i2c_bus: 0,
+            i2c_addr: 0,
+            sync_byte: 0,
+        });
+
+        for (input, p) in inputs.iter_mut().zip(profile.inputs.iter())
+        {
+            *input = StreamInput
             {
                 enable: true,
                 is_parallel: false,
-                port_number: 4,
-                slave_number: 3,
-                i2c_bus: 2,
-                i2c_addr: 0x12,
-                packet_len: 188,
-                sync_byte: 0x47,
-            },
-            StreamInput
-            {
-                enable: false,
-                is_parallel: false,
-                port_number: 0,
-                slave_number: 0,
-                i2c_bus: 0,
-                i2c_addr: 0,
-                packet_len: 0,
-                sync_byte: 0,
-            },
-        ];
+                port_number: p.port_number,
+                slave_number: p.slave_number,
+                i2c_bus: p.i2c_bus,
+                i2c_addr: p.i2c_addr,
+                sync_byte: p.sync_byte,
+            };
+        }
 
         Self
         {
-            i2c_speed: 0x07,
-            xfer_size: 188 * 816, // px4_usb.c の it930x->config.xfer_size = 188 * px4_usb_params.xfer_packets; と px4_usb_params.c の .xfer_packets = 816, から
+            i2c_speed: profile.i2c_speed,
+            // px4_usb.c の it930x->config.xfer_size = 188 * px4_usb_params.xfer_packets; から
+            xfer_size: 188 * profile.xfer_packets,
             inputs,
         }
     }
 }
 
+// 今までどおり PX-W3U4 の構成
+impl Default for IT930xConfig
+{
+    fn default() -> Self 
+    {
+        Self::from_profile(&PX_W3U4)
+    }
+}
+
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum GpioMode
@@ -422,7 +384,7 @@ impl<B: BusOps> IT930x<B>
         self.ctrl_msg(IT930X_CMD_QUERYINFO, &wbuf,&mut rbuf)?;
         let fw_version = ((rbuf[0] as u32) << 24) | ((rbuf[1] as u32) << 16) | ((rbuf[2] as u32) << 8) | (rbuf[3] as u32);
 
-        return Ok(fw_version)
+        Ok(fw_version)
     }
 
     // it930x.c 619〜630 をそのまま移植
@@ -430,12 +392,12 @@ impl<B: BusOps> IT930x<B>
     {
         let mut last_err = None;
 
-        for i in 0..5
+        for _ in 0..5
         {
             // readチェックのみ
             match self.read_firmware_version()
             {
-                Ok(u32) => return Ok(()),
+                Ok(_) => return Ok(()),
                 Err(e) => last_err = Some(e),
             }
         }
@@ -463,12 +425,10 @@ impl<B: BusOps> IT930x<B>
         let fw_version = self.read_firmware_version()?;
         if fw_version != 0
         {
-            println!("Firmware is already loaded. version: {}.{}.{}.{}", (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, fw_version & 0xff);
+            eprintln!("Firmware is already loaded. version: {}.{}.{}.{}", (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, fw_version & 0xff);
             return Ok(());
         }
 
-        println!("[debug] passed read_firmware_version()");
-
         // 2. I2Cスピード設定
         self.write_regs(0xf103, &[self.config.i2c_speed])?;
 
@@ -526,8 +486,8 @@ impl<B: BusOps> IT930x<B>
             return Err(CtrlMsgError::Bus(rusb::Error::Other.into()));
         }
 
-        println!("Firmware is loaded. version: {}.{}.{}.{}", (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, fw_version & 0xff);
-        return Ok(());
+        eprintln!("Firmware is loaded. version: {}.{}.{}.{}", (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, (fw_version >> 24) & 0xff, fw_version & 0xff);
+        Ok(())
     }
 
     pub fn config_i2c(&self) -> Result<(), CtrlMsgError>
@@ -596,7 +556,7 @@ impl<B: BusOps> IT930x<B>
     {
         self.write_reg_mask(0xda1d, 0x01, 0x01)?;
 
-        let mut ret: Result<(), CtrlMsgError> = (||
+        let ret: Result<(), CtrlMsgError> = (||
         {
             // disable ep4
             self.write_reg_mask(0xdd11, 0x00, 0x20)?;
@@ -626,7 +586,7 @@ impl<B: BusOps> IT930x<B>
         let ret2 = self.write_reg_mask(0xda1d, 0x00, 0x01);
         let ret3 = self.write_regs(0xd920, &[0]);
 
-        if ret.is_err() { return ret; }
+        ret?;
         ret2?;
         ret3?;
 
@@ -764,13 +724,6 @@ impl<B: BusOps> IT930x<B>
 
                     let buf = [len as u8, bus, req.addr << 1,];
 
-                    // debug
-                    println!(
-                        "[i2c_read] bus={} addr=0x{:02x} len={}",
-                        bus, req.addr, len
-                    );
-                    dump_hex("wb", &buf);
-
                     self.ctrl_msg(IT930X_CMD_I2C_READ, &buf, req.data,)?;
                 }
                 
@@ -788,13 +741,6 @@ impl<B: BusOps> IT930x<B>
                     buf.push(req.addr << 1);
                     buf.extend_from_slice(req.data);
 
-                    // debug
-                    println!(
-                        "[i2c_write] bus={} addr=0x{:02x} len={}",
-                        bus, req.addr, len
-                    );
-                    dump_hex("wb", &buf);
-
                     self.ctrl_msg(IT930X_CMD_I2C_WRITE, &buf, &mut [])?;
                 }
             }
@@ -806,9 +752,27 @@ impl<B: BusOps> IT930x<B>
 
 impl<B: BusOps> IT930x<B>
 {
+    #[cfg(test)]
     pub fn new(bus: B) -> Self
     {
-        // 多分、IT930xConfig::default() は、xfer_size の設定もした方がいいと思う。
-        Self { bus, seq: AtomicU8::new(0), config: IT930xConfig::default(), ctrl_lock: Mutex::new(()), i2c_lock: Mutex::new(()), gpio_lock: Mutex::new(()), gpio_status: Mutex::new([GpioStatus::default(); 16]), }
+        Self::with_config(bus, IT930xConfig::default())
+    }
+
+    // 機種ごとの構成で使うとき (IT930xConfig::from_profile)
+    pub fn with_config(bus: B, config: IT930xConfig) -> Self
+    {
+        Self { bus, seq: AtomicU8::new(0), config, ctrl_lock: Mutex::new(()), i2c_lock: Mutex::new(()), gpio_lock: Mutex::new(()), gpio_status: Mutex::new([GpioStatus::default(); 16]), }
+    }
+
+    #[cfg(test)]
+    pub fn config(&self) -> &IT930xConfig
+    {
+        &self.config
+    }
+
+    // ストリーム (bulk) の受信は ctrl_msg を通さないので、バスを直接使う
+    pub fn bus(&self) -> &B
+    {
+        &self.bus
     }
 }
\ No newline at end of file
@@ -0,0 +1,942 @@
+// IT930x シミュレータ
+// 実機 (PX-W3U4 など) なしで IT930x の ctrl_msg を動かすための BusOps 実装
+// ctrl_tx で受け取ったフレームを解釈して、ctrl_rx で返すフレームを積んでおく、という動き。
+// レジスタはメモリ上に持つだけなので、GPIO なども書いた値がそのまま読める。
+// I2C の先には SimI2cSlave を繋げられる。(TC90522 + RT710/R850 の模擬は後半)
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::it930x::{
+    checksum, I2CRequestType, IT930X_CMD_BOOT, IT930X_CMD_FW_SCATTER_WRITE, IT930X_CMD_I2C_READ,
+    IT930X_CMD_I2C_WRITE, IT930X_CMD_QUERYINFO, IT930X_CMD_REG_READ, IT930X_CMD_REG_WRITE,
+};
+use crate::itedtv_bus::{BusError, BusOps};
+
+// BOOT 後に QUERYINFO で返す firmware version
+pub const SIM_FW_VERSION: u32 = 0x01_04_00_00;
+
+// 応答フレームの status
+// 実機の値は不明なので、0 以外であることだけが意味を持つ
+pub const SIM_STATUS_ERROR: u8 = 0x01;
+pub const SIM_STATUS_BAD_CHECKSUM: u8 = 0x02;
+pub const SIM_STATUS_UNKNOWN_CMD: u8 = 0x03;
+pub const SIM_STATUS_NOT_BOOTED: u8 = 0x04;
+pub const SIM_STATUS_I2C_NACK: u8 = 0x05;
+
+// I2C の先に繋ぐ模擬デバイス
+// false を返すと NACK 扱い (ctrl_msg は DeviceError になる)
+pub trait SimI2cSlave: Send
+{
+    fn write(&mut self, data: &[u8]) -> bool;
+    fn read(&mut self, buf: &mut [u8]) -> bool;
+}
+
+// シミュレータが受け取った I2C 転送の記録
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimI2cTransfer
+{
+    pub bus: u8,
+    pub addr: u8, // 7bit アドレス
+    pub req: I2CRequestType,
+    pub data: Vec<u8>,
+}
+
+struct SimState
+{
+    regs: HashMap<u32, u8>,
+    booted: bool,
+    fw_blocks: usize,
+    rx_queue: VecDeque<Vec<u8>>,
+    i2c_log: Vec<SimI2cTransfer>,
+    i2c_slaves: HashMap<(u8, u8), Box<dyn SimI2cSlave>>,
+    ctrl_count: usize,
+    streaming: bool,
+    stream_queue: VecDeque<Vec<u8>>,
+    max_bulk_size: u32,
+    disconnected: bool,
+}
+
+// IT930x::new() に渡すと所有権ごと持っていかれるので、中身は Arc で共有して
+// clone したものから状態を覗けるようにしている。
+#[derive(Clone)]
+pub struct SimBus
+{
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Default for SimBus
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl SimBus
+{
+    pub fn new() -> Self
+    {
+        let mut regs = HashMap::new();
+
+        // EEPROM あり (check_epprom が通るように)
+        regs.insert(0x4979, 0x01);
+
+        Self
+        {
+            state: Arc::new(Mutex::new(SimState
+            {
+                regs,
+                booted: false,
+                fw_blocks: 0,
+                rx_queue: VecDeque::new(),
+                i2c_log: Vec::new(),
+                i2c_slaves: HashMap::new(),
+                ctrl_count: 0,
+                streaming: false,
+                stream_queue: VecDeque::new(),
+                max_bulk_size: 512,
+                disconnected: false,
+            })),
+        }
+    }
+
+    // firmware ロード済み (warm start) の状態から始める
+    pub fn new_booted() -> Self
+    {
+        let sim = Self::new();
+        sim.state.lock().unwrap().booted = true;
+        sim
+    }
+
+    pub fn reg(&self, addr: u32) -> u8
+    {
+        *self.state.lock().unwrap().regs.get(&addr).unwrap_or(&0)
+    }
+
+    pub fn set_reg(&self, addr: u32, val: u8)
+    {
+        self.state.lock().unwrap().regs.insert(addr, val);
+    }
+
+    pub fn is_booted(&self) -> bool
+    {
+        self.state.lock().unwrap().booted
+    }
+
+    pub fn fw_blocks(&self) -> usize
+    {
+        self.state.lock().unwrap().fw_blocks
+    }
+
+    // USB ケーブルを抜いた / 挿し直した状態にする
+    // 抜いている間は全部 BusError::Disconnected
+    pub fn set_disconnected(&self, disconnected: bool)
+    {
+        self.state.lock().unwrap().disconnected = disconnected;
+    }
+
+    pub fn is_disconnected(&self) -> bool
+    {
+        self.state.lock().unwrap().disconnected
+    }
+
+    // 受け取った ctrl_msg の数
+    pub fn ctrl_count(&self) -> usize
+    {
+        self.state.lock().unwrap().ctrl_count
+    }
+
+    pub fn i2c_log(&self) -> Vec<SimI2cTransfer>
+    {
+        self.state.lock().unwrap().i2c_log.clone()
+    }
+
+    // bus 番号と 7bit アドレスを指定して I2C スレーブを繋ぐ
+    pub fn attach_i2c_slave<S: SimI2cSlave + 'static>(&self, bus: u8, addr: u8, slave: S)
+    {
+        self.state.lock().unwrap().i2c_slaves.insert((bus, addr), Box::new(slave));
+    }
+
+    // PX-W3U4 の構成で TC90522 とチューナーを繋ぐ
+    // device_profile.rs の PX4_INPUTS と同じ順で返す
+    pub fn attach_px4_w3u4(&self) -> Vec<SimTc90522>
+    {
+        let layout = [
+            (0x11, SimTunerType::RT710),
+            (0x13, SimTunerType::RT710),
+            (0x10, SimTunerType::R850),
+            (0x12, SimTunerType::R850),
+        ];
+
+        layout.iter().map(|&(addr, tuner)|
+        {
+            let demod = SimTc90522::new();
+            demod.attach_tuner(SimTuner::new(tuner));
+            self.attach_i2c_slave(2, addr, demod.clone());
+            demod
+        }).collect()
+    }
+
+    // stream_rx で返すデータを積む
+    pub fn push_stream_data(&self, data: &[u8])
+    {
+        self.state.lock().unwrap().stream_queue.push_back(data.to_vec());
+    }
+
+    pub fn set_max_bulk_size(&self, size: u32)
+    {
+        self.state.lock().unwrap().max_bulk_size = size;
+    }
+
+    // load_firmware() に食わせられる最小の firmware イメージ
+    // it930x.c の scatter-write のブロック形式に合わせてある
+    // [0x03, 0x00, 0x00, m, (addr_h, addr_l, len) * m, data...]
+    pub fn dummy_firmware() -> Vec<u8>
+    {
+        let mut fw = Vec::new();
+        for block in 0..2u8
+        {
+            let data = [0xa5u8, 0x5a, block, 0x00];
+            fw.extend_from_slice(&[0x03, 0x00, 0x00, 0x01]);
+            fw.extend_from_slice(&[0x10, block, data.len() as u8]);
+            fw.extend_from_slice(&data);
+        }
+        fw
+    }
+}
+
+impl SimState
+{
+    // 1コマンド分の処理
+    // Ok なら応答データ、Err なら応答の status
+    fn handle_cmd(&mut self, cmd: u16, wdata: &[u8]) -> Result<Vec<u8>, u8>
+    {
+        match cmd
+        {
+            IT930X_CMD_REG_READ =>
+            {
+                if wdata.len() < 6
+                {
+                    return Err(SIM_STATUS_ERROR);
+                }
+
+                let len = wdata[0] as usize;
+                let reg = Self::reg_addr(&wdata[2..6]);
+
+                Ok((0..len as u32).map(|i| *self.regs.get(&(reg + i)).unwrap_or(&0)).collect())
+            }
+
+            IT930X_CMD_REG_WRITE =>
+            {
+                if wdata.len() < 6 || wdata.len() - 6 != wdata[0] as usize
+                {
+                    return Err(SIM_STATUS_ERROR);
+                }
+
+                let reg = Self::reg_addr(&wdata[2..6]);
+                for (i, v) in wdata[6..].iter().enumerate()
+                {
+                    self.regs.insert(reg + i as u32, *v);
+                }
+
+                Ok(Vec::new())
+            }
+
+            IT930X_CMD_QUERYINFO =>
+            {
+                // 1: firmware version
+                if wdata.first() != Some(&1)
+                {
+                    return Err(SIM_STATUS_ERROR);
+                }
+
+                let ver = if self.booted { SIM_FW_VERSION } else { 0 };
+                Ok(ver.to_be_bytes().to_vec())
+            }
+
+            IT930X_CMD_FW_SCATTER_WRITE =>
+            {
+                if self.booted || wdata.first() != Some(&0x03)
+                {
+                    return Err(SIM_STATUS_ERROR);
+                }
+
+                self.fw_blocks += 1;
+                Ok(Vec::new())
+            }
+
+            IT930X_CMD_BOOT =>
+            {
+                if self.fw_blocks == 0
+                {
+                    return Err(SIM_STATUS_ERROR);
+                }
+
+                self.booted = true;
+                Ok(Vec::new())
+            }
+
+            IT930X_CMD_I2C_READ =>
+            {
+                // I2C は firmware が動いていないと使えない
+                if !self.booted
+                {
+                    return Err(SIM_STATUS_NOT_BOOTED);
+                }
+                if wdata.len() < 3
+                {
+                    return Err(SIM_STATUS_ERROR);
+                }
+
+                let (bus, addr) = (wdata[1], wdata[2] >> 1);
+                let mut data = vec![0u8; wdata[0] as usize];
+                let ack = match self.i2c_slaves.get_mut(&(bus, addr))
+                {
+                    Some(slave) => slave.read(&mut data),
+                    None => false,
+                };
+
+                self.i2c_log.push(SimI2cTransfer { bus, addr, req: I2CRequestType::Read, data: data.clone() });
+                if !ack
+                {
+                    return Err(SIM_STATUS_I2C_NACK);
+                }
+                Ok(data)
+            }
+
+            IT930X_CMD_I2C_WRITE =>
+            {
+                if !self.booted
+                {
+                    return Err(SIM_STATUS_NOT_BOOTED);
+                }
+                if wdata.len() < 3 || wdata.len() - 3 != wdata[0] as usize
+                {
+                    return Err(SIM_STATUS_ERROR);
+                }
+
+                let (bus, addr) = (wdata[1], wdata[2] >> 1);
+                let ack = match self.i2c_slaves.get_mut(&(bus, addr))
+                {
+                    Some(slave) => slave.write(&wdata[3..]),
+                    None => false,
+                };
+
+                self.i2c_log.push(SimI2cTransfer { bus, addr, req: I2CRequestType::Write, data: wdata[3..].to_vec() });
+                if !ack
+                {
+                    return Err(SIM_STATUS_I2C_NACK);
+                }
+                Ok(Vec::new())
+            }
+
+            _ => Err(SIM_STATUS_UNKNOWN_CMD),
+        }
+    }
+
+    fn reg_addr(b: &[u8]) -> u32
+    {
+        ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32)
+    }
+
+    // ctrl_msg が期待する応答フレーム
+    // [LEN, SEQ, STATUS, DATA..., CHK_H, CHK_L]
+    fn push_response(&mut self, seq: u8, status: u8, data: &[u8])
+    {
+        let mut rx = Vec::with_capacity(5 + data.len());
+        rx.push((4 + data.len()) as u8);
+        rx.push(seq);
+        rx.push(status);
+        rx.extend_from_slice(data);
+
+        let chk = checksum(&rx[1..]);
+        rx.push((chk >> 8) as u8);
+        rx.push((chk & 0xff) as u8);
+
+        self.rx_queue.push_back(rx);
+    }
+}
+
+impl BusOps for SimBus
+{
+    fn ctrl_tx(&self, buf: &[u8]) -> Result<(), BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected
+        {
+            return Err(BusError::Disconnected);
+        }
+
+        // [LEN, CMD_H, CMD_L, SEQ, DATA..., CHK_H, CHK_L]
+        if buf.len() < 6 || buf[0] as usize != buf.len() - 1
+        {
+            return Err(BusError::Other(format!("sim: malformed ctrl frame (len={})", buf.len())));
+        }
+
+        state.ctrl_count += 1;
+
+        let n = buf.len();
+        let cmd = ((buf[1] as u16) << 8) | (buf[2] as u16);
+        let seq = buf[3];
+        let chk = ((buf[n - 2] as u16) << 8) | (buf[n - 1] as u16);
+
+        if checksum(&buf[1..n - 2]) != chk
+        {
+            state.push_response(seq, SIM_STATUS_BAD_CHECKSUM, &[]);
+            return Ok(());
+        }
+
+        match state.handle_cmd(cmd, &buf[4..n - 2])
+        {
+            Ok(data) => state.push_response(seq, 0, &data),
+            Err(status) => state.push_response(seq, status, &[]),
+        }
+
+        Ok(())
+    }
+
+    fn ctrl_rx(&self, buf: &mut [u8]) -> Result<usize, BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected
+        {
+            return Err(BusError::Disconnected);
+        }
+
+        // 応答するものがない = 実機ならタイムアウト
+        let rx = state.rx_queue.pop_front().ok_or(BusError::Timeout)?;
+        let len = rx.len().min(buf.len());
+        buf[..len].copy_from_slice(&rx[..len]);
+
+        Ok(len)
+    }
+
+    fn stream_rx(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, BusError>
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected
+        {
+            return Err(BusError::Disconnected);
+        }
+
+        if !state.streaming
+        {
+            return Err(BusError::Timeout);
+        }
+
+        let mut data = state.stream_queue.pop_front().ok_or(BusError::Timeout)?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+
+        // 入りきらなかった分は次回に回す
+        if len < data.len()
+        {
+            state.stream_queue.push_front(data.split_off(len));
+        }
+
+        Ok(len)
+    }
+
+    fn start_streaming(&self) -> Result<(), BusError>
+    {
+        self.state.lock().unwrap().streaming = true;
+        Ok(())
+    }
+
+    fn stop_streaming(&self) -> Result<(), BusError>
+    {
+        self.state.lock().unwrap().streaming = false;
+        Ok(())
+    }
+
+    fn max_bulk_size(&self) -> u32
+    {
+        self.state.lock().unwrap().max_bulk_size
+    }
+}
+
+// ここから I2C スレーブの模擬
+
+fn reverse_bit(val: u8) -> u8
+{
+    let mut t = val;
+
+    t = ((t & 0x55) << 1) | ((t & 0xAA) >> 1);
+    t = ((t & 0x33) << 2) | ((t & 0xCC) >> 2);
+    ((t & 0x0F) << 4) | ((t & 0xF0) >> 4)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimTunerType
+{
+    RT710,
+    RT720,
+    R850,
+}
+
+struct SimTunerState
+{
+    tuner_type: SimTunerType,
+    regs: Vec<u8>,
+    writes: Vec<(u8, u8)>,
+
+    // PLL のロック状態 (RT710 は reg 0x02 bit7、R850 は reg 0x02 bit6 に出る)
+    pll_locked: bool,
+
+    // R850 の校正用 ADC (reg 0x01 bit5:0) の値を、今のレジスタから決める (無ければ書かれた値のまま)
+    adc: Option<SimTunerAdc>,
+}
+
+pub type SimTunerAdc = Arc<dyn Fn(&[u8]) -> u8 + Send + Sync>;
+
+// RT710/RT720 (0x7a) と R850 (0x7c) の模擬
+// どちらも読み出しは常にレジスタ 0 から始まり、ビット順が反転して返ってくる。
+// (rt710.c / r850.c の read_regs が reverse_bit しているのはこのため)
+#[derive(Clone)]
+pub struct SimTuner
+{
+    state: Arc<Mutex<SimTunerState>>,
+}
+
+impl SimTuner
+{
+    pub fn new(tuner_type: SimTunerType) -> Self
+    {
+        let mut regs = match tuner_type
+        {
+            SimTunerType::RT710 | SimTunerType::RT720 => vec![0u8; 0x10],
+            SimTunerType::R850 => vec![0u8; 0x30],
+        };
+
+        // チップ判定に使われるレジスタ
+        match tuner_type
+        {
+            // 上位 4bit が 0x7 なら RT710、それ以外は RT720
+            SimTunerType::RT710 => regs[0x03] = 0x70,
+            SimTunerType::RT720 => regs[0x03] = 0x80,
+            SimTunerType::R850 =>
+            {
+                regs[0x00] = 0x98;
+                // xtal 発振確認用 (bit6 = lock, 下位 6bit = bank 55 付近)
+                regs[0x02] = 0x40 | 55;
+            }
+        }
+
+        Self { state: Arc::new(Mutex::new(SimTunerState { tuner_type, regs, writes: Vec::new(), pll_locked: true, adc: None })) }
+    }
+
+    pub fn tuner_type(&self) -> SimTunerType
+    {
+        self.state.lock().unwrap().tuner_type
+    }
+
+    pub fn i2c_addr(&self) -> u8
+    {
+        match self.tuner_type()
+        {
+            SimTunerType::RT710 | SimTunerType::RT720 => 0x7a,
+            SimTunerType::R850 => 0x7c,
+        }
+    }
+
+    pub fn reg(&self, reg: u8) -> u8
+    {
+        self.state.lock().unwrap().regs[reg as usize]
+    }
+
+    pub fn set_reg(&self, reg: u8, val: u8)
+    {
+        self.state.lock().unwrap().regs[reg as usize] = val;
+    }
+
+    pub fn set_pll_locked(&self, locked: bool)
+    {
+        self.state.lock().unwrap().pll_locked = locked;
+    }
+
+    // 校正で読まれる ADC の値を、書き込まれたレジスタ (IMR の gain/phase や LPF のコード) から決める
+    pub fn set_adc<F: Fn(&[u8]) -> u8 + Send + Sync + 'static>(&self, adc: F)
+    {
+        self.state.lock().unwrap().adc = Some(Arc::new(adc));
+    }
+}
+
+impl SimTunerState
+{
+    // 読み出したときの値 (状態ビットは書き込まれた値ではなく、模擬している状態を返す)
+    fn read_reg(&self, reg: usize) -> u8
+    {
+        let v = self.regs[reg];
+        if reg == 0x01
+        {
+            if let Some(adc) = &self.adc
+            {
+                return (v & 0xc0) | (adc(&self.regs) & 0x3f);
+            }
+        }
+        if reg != 0x02
+        {
+            return v;
+        }
+
+        let bit = match self.tuner_type
+        {
+            SimTunerType::RT710 | SimTunerType::RT720 => 0x80,
+            SimTunerType::R850 => 0x40,
+        };
+        if self.pll_locked { v | bit } else { v & !bit }
+    }
+}
+
+impl SimI2cSlave for SimTuner
+{
+    fn write(&mut self, data: &[u8]) -> bool
+    {
+        let mut state = self.state.lock().unwrap();
+
+        // 先頭はレジスタ番号、続きはそこからの連続書き込み
+        // レジスタ番号だけの書き込みは読み出し前のダミーなので、何もしない
+        let (reg, vals) = match data.split_first()
+        {
+            Some((reg, vals)) => (*reg as usize, vals),
+            None => return false,
+        };
+
+        if reg + vals.len() > state.regs.len()
+        {
+            return false;
+        }
+
+        for (i, v) in vals.iter().enumerate()
+        {
+            state.regs[reg + i] = *v;
+            state.writes.push(((reg + i) as u8, *v));
+        }
+
+        true
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> bool
+    {
+        let state = self.state.lock().unwrap();
+
+        if buf.len() > state.regs.len()
+        {
+            return false;
+        }
+
+        for (i, b) in buf.iter_mut().enumerate()
+        {
+            *b = reverse_bit(state.read_reg(i));
+        }
+
+        true
+    }
+}
+
+struct SimTc90522State
+{
+    regs: [u8; 256],
+    reg_ptr: u8,
+    writes: Vec<(u8, u8)>,
+    tuner: Option<SimTuner>,
+
+    // 0xfe で読み出し指定された直後の read はチューナー側へ流す
+    gate_read: bool,
+}
+
+// TC90522 の模擬
+// 通常のレジスタアクセスに加えて、0xfe から始まる書き込みで、裏にいるチューナーへ中継する。
+//   [0xfe, addr << 1, data...]  : チューナーへ書き込み
+//   [0xfe, (addr << 1) | 1]     : 次の read をチューナーから読む
+#[derive(Clone)]
+pub struct SimTc90522
+{
+    state: Arc<Mutex<SimTc90522State>>,
+}
+
+impl Default for SimTc90522
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl SimTc90522
+{
+    pub fn new() -> Self
+    {
+        Self
+        {
+            state: Arc::new(Mutex::new(SimTc90522State
+            {
+                regs: [0u8; 256],
+                reg_ptr: 0,
+                writes: Vec::new(),
+                tuner: None,
+                gate_read: false,
+            })),
+        }
+    }
+
+    pub fn attach_tuner(&self, tuner: SimTuner)
+    {
+        self.state.lock().unwrap().tuner = Some(tuner);
+    }
+
+    pub fn tuner(&self) -> Option<SimTuner>
+    {
+        self.state.lock().unwrap().tuner.clone()
+    }
+
+    pub fn reg(&self, reg: u8) -> u8
+    {
+        self.state.lock().unwrap().regs[reg as usize]
+    }
+
+    pub fn set_reg(&self, reg: u8, val: u8)
+    {
+        self.state.lock().unwrap().regs[reg as usize] = val;
+    }
+}
+
+impl SimI2cSlave for SimTc90522
+{
+    fn write(&mut self, data: &[u8]) -> bool
+    {
+        let mut state = self.state.lock().unwrap();
+
+        if data.first() == Some(&0xfe)
+        {
+            if data.len() < 2
+            {
+                return false;
+            }
+
+            let addr = data[1] >> 1;
+            let mut tuner = match &state.tuner
+            {
+                Some(t) if t.i2c_addr() == addr => t.clone(),
+                _ => return false,
+            };
+
+            if data[1] & 0x01 != 0
+            {
+                state.gate_read = true;
+                return true;
+            }
+
+            drop(state);
+            return tuner.write(&data[2..]);
+        }
+
+        let (reg, vals) = match data.split_first()
+        {
+            Some((reg, vals)) => (*reg, vals),
+            None => return false,
+        };
+
+        state.reg_ptr = reg;
+        for v in vals
+        {
+            let r = state.reg_ptr;
+            state.regs[r as usize] = *v;
+            state.writes.push((r, *v));
+            state.reg_ptr = r.wrapping_add(1);
+        }
+
+        true
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> bool
+    {
+        let mut state = self.state.lock().unwrap();
+
+        if state.gate_read
+        {
+            state.gate_read = false;
+            let mut tuner = match &state.tuner
+            {
+                Some(t) => t.clone(),
+                None => return false,
+            };
+
+            drop(state);
+            return tuner.read(buf);
+        }
+
+        for b in buf.iter_mut()
+        {
+            let r = state.reg_ptr;
+            *b = state.regs[r as usize];
+            state.reg_ptr = r.wrapping_add(1);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::it930x::{CtrlMsgError, GpioMode, IT930x, IT930xConfig};
+
+    fn write_temp_firmware(name: &str, data: &[u8]) -> std::path::PathBuf
+    {
+        let path = std::env::temp_dir().join(format!("px4_sim_{}_{}.bin", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn raise_on_cold_device()
+    {
+        let sim = SimBus::new();
+        let it930x = IT930x::new(sim.clone());
+
+        it930x.raise().unwrap();
+        assert_eq!(it930x.read_firmware_version().unwrap(), 0);
+        assert_eq!(sim.ctrl_count(), 2);
+    }
+
+    #[test]
+    fn load_firmware_from_file()
+    {
+        let sim = SimBus::new();
+        let it930x = IT930x::new(sim.clone());
+        let path = write_temp_firmware("load", &SimBus::dummy_firmware());
+
+        let result = it930x.load_firmware(&path);
+        let _ = std::fs::remove_file(&path);
+        result.unwrap();
+
+        assert!(sim.is_booted());
+        assert_eq!(sim.fw_blocks(), 2);
+        assert_eq!(it930x.read_firmware_version().unwrap(), SIM_FW_VERSION);
+        assert_eq!(sim.reg(0xf103), it930x.config().i2c_speed);
+    }
+
+    #[test]
+    fn load_firmware_skips_when_already_booted()
+    {
+        let sim = SimBus::new_booted();
+        let it930x = IT930x::new(sim.clone());
+
+        // 読まれないはずなので、存在しないファイルでも通る
+        it930x.load_firmware("/nonexistent/it930x-firmware.bin").unwrap();
+        assert_eq!(sim.fw_blocks(), 0);
+    }
+
+    #[test]
+    fn load_firmware_rejects_broken_image()
+    {
+        let sim = SimBus::new();
+        let it930x = IT930x::new(sim.clone());
+        let path = write_temp_firmware("broken", &[0x01, 0x00, 0x00, 0x00]);
+
+        let result = it930x.load_firmware(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert!(result.is_err());
+        assert!(!sim.is_booted());
+    }
+
+    #[test]
+    fn init_warm_configures_stream_and_i2c()
+    {
+        let sim = SimBus::new_booted();
+        let config = IT930xConfig::default();
+        let xfer = config.xfer_size / 4;
+        let it930x = IT930x::with_config(sim.clone(), config);
+
+        it930x.init_warm().unwrap();
+
+        assert_eq!(sim.reg(0xdd0c), (512 / 4) as u8);
+        assert_eq!(sim.reg(0xdd88), (xfer & 0xff) as u8);
+        assert_eq!(sim.reg(0xdd89), ((xfer >> 8) & 0xff) as u8);
+        assert_eq!(sim.reg(0xda1d), 0x00);
+
+        // PX-W3U4 の 4入力
+        for (input, (addr_reg, bus_reg)) in it930x.config().inputs.iter().take(4).zip([(0x4975, 0x4971), (0x4974, 0x4970), (0x4973, 0x496f), (0x4972, 0x496e)])
+        {
+            let port = input.port_number as u32;
+            assert_eq!(sim.reg(0xda4c + port), 1);
+            assert_eq!(sim.reg(0xda73 + port), 1);
+            assert_eq!(sim.reg(0xda78 + port), input.sync_byte);
+            assert_eq!(sim.reg(addr_reg), input.i2c_addr << 1);
+            assert_eq!(sim.reg(bus_reg), input.i2c_bus);
+        }
+
+        // 5つ目は使わない
+        assert_eq!(sim.reg(0xda4c + it930x.config().inputs[4].port_number as u32), 0);
+    }
+
+    #[test]
+    fn config_stream_output_follows_max_bulk_size()
+    {
+        let sim = SimBus::new_booted();
+        sim.set_max_bulk_size(64);
+        let it930x = IT930x::new(sim.clone());
+
+        it930x.config_stream_output().unwrap();
+        assert_eq!(sim.reg(0xdd0c), 64 / 4);
+    }
+
+    #[test]
+    fn gpio_mode_and_output()
+    {
+        let sim = SimBus::new_booted();
+        let it930x = IT930x::new(sim.clone());
+
+        // 出力にする前は書けない
+        assert!(matches!(it930x.write_gpio(7, true), Err(CtrlMsgError::InvalidArgument)));
+
+        it930x.set_gpio_mode(7, GpioMode::Out, true).unwrap();
+        assert_eq!(sim.reg(0xd8c4), 1);
+        assert_eq!(sim.reg(0xd8c5), 1);
+
+        it930x.write_gpio(7, true).unwrap();
+        assert_eq!(sim.reg(0xd8c3), 1);
+        it930x.write_gpio(7, false).unwrap();
+        assert_eq!(sim.reg(0xd8c3), 0);
+
+        assert!(matches!(it930x.set_gpio_mode(0, GpioMode::Out, true), Err(CtrlMsgError::InvalidArgument)));
+        assert!(matches!(it930x.set_gpio_mode(17, GpioMode::Out, true), Err(CtrlMsgError::InvalidArgument)));
+    }
+
+    #[test]
+    fn write_reg_mask_keeps_other_bits()
+    {
+        let sim = SimBus::new_booted();
+        let it930x = IT930x::new(sim.clone());
+        sim.set_reg(0xf41f, 0xa0);
+
+        it930x.write_reg_mask(0xf41f, 0x04, 0x04).unwrap();
+        assert_eq!(sim.reg(0xf41f), 0xa4);
+
+        // 変化がなければ書かない
+        let count = sim.ctrl_count();
+        it930x.write_reg_mask(0xf41f, 0x04, 0x04).unwrap();
+        assert_eq!(sim.ctrl_count(), count + 1);
+    }
+
+    #[test]
+    fn device_errors_are_reported()
+    {
+        let sim = SimBus::new();
+        let it930x = IT930x::new(sim.clone());
+
+        // firmware が動くまで I2C は使えない
+        let mut buf = [0u8; 1];
+        let mut reqs = [crate::it930x::I2CCommRequest { addr: 0x11, data: &mut buf, req: I2CRequestType::Read }];
+        assert!(matches!(it930x.i2c_master_request(2, &mut reqs), Err(CtrlMsgError::DeviceError(SIM_STATUS_NOT_BOOTED))));
+
+        sim.set_disconnected(true);
+        assert!(matches!(it930x.read_firmware_version(), Err(CtrlMsgError::Bus(BusError::Disconnected))));
+        assert!(sim.is_disconnected());
+    }
+}
@@ -3,18 +3,21 @@
 // 上位層の it930x から USB を隠蔽している
 // → USB実装を差し替えやすくなる、らしい。
 
-use std::time::Duration;
-use rusb::{Context, DeviceHandle};
-use std::sync::Mutex;
-use std::thread;
+use std::time::{Duration, Instant};
+use rusb::{ffi, Context, Device, DeviceHandle, Speed, UsbContext, Version};
+use rusb::constants::*;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
 
 #[derive(Debug)]
-pub enum BusError 
+pub enum BusError
 {
     Usb(rusb::Error),
     Timeout,
     Disconnected,
-    Other(String),   
+    Other(String),
 }
 
 impl From<rusb::Error> for BusError
@@ -43,63 +46,337 @@ pub trait BusOps
     fn max_bulk_size(&self) -> u32;
 }
 
+// px4_usb_params.c の .max_urbs / .xfer_packets から
+pub const DEFAULT_URB_NUM: u32 = 6;
+pub const DEFAULT_XFER_SIZE: usize = 188 * 816;
+
+// xfer_size を max_bulk_size の倍数に切り上げる
+// 188 * 816 は 64 の倍数だが 512 の倍数ではないので、High Speed では端数が出る。
+// 受信側の buffer は大きめに取り、実際の 1転送は IT930x 側の閾値 (0xdd88) で短いパケットで終わる。
+pub fn align_xfer_size(xfer_size: usize, max_bulk_size: u32) -> usize
+{
+    xfer_size.div_ceil(max_bulk_size as usize) * max_bulk_size as usize
+}
+
+// イベント処理スレッドの handle_events 1回あたりの待ち時間
+// (stream_rx が停止や抜去を確認する間隔も兼ねる)
+const STREAM_WORKER_TIMEOUT: Duration = Duration::from_millis(300);
+
+// 完了 callback から受け取り側へ渡す、1転送分のデータ
+pub type StreamBuffer = Vec<u8>;
+
+// 完了 callback と共有する状態
+// libusb_transfer の user_data にはこれへのポインタを入れる
+struct StreamShared
+{
+    running: AtomicBool,
+    // submit 済みで、まだ callback が返ってきていない転送の数
+    in_flight: AtomicUsize,
+    // callback の再 submit と stop_streaming の cancel が入れ違わないようにする
+    submit_lock: Mutex<()>,
+    tx: SyncSender<StreamBuffer>,
+    // 転送が失敗したら、ここに入れて止まる (stream_rx が拾う)
+    error: Mutex<Option<BusError>>,
+    dropped: Arc<AtomicU64>,
+    disconnected: Arc<AtomicBool>,
+}
+
+impl StreamShared
+{
+    fn fail(&self, e: rusb::Error)
+    {
+        self.running.store(false, Ordering::SeqCst);
+
+        let mut error = self.error.lock().unwrap();
+        if error.is_none()
+        {
+            *error = Some(UsbBusRusb::map_usb_error(&self.disconnected, e));
+        }
+    }
+}
+
+// libusb_transfer と、その受信 buffer
+struct StreamTransfer
+{
+    transfer: *mut ffi::libusb_transfer,
+    // libusb が書き込むので、転送が全部返ってくるまで解放も移動もしない
+    _buf: Box<[u8]>,
+}
+
+// transfer を触るのは、submit / cancel / free (どれもスレッドを選ばない) と、libusb が呼ぶ callback だけ
+unsafe impl Send for StreamTransfer {}
+
+// stream_rx 側の状態
+// stream_rx は streaming の lock を持ったまま待たないように、これだけを lock して待つ
+struct StreamReader
+{
+    rx: Receiver<StreamBuffer>,
+
+    // stream_rx の buf に入りきらなかった残り
+    pending: StreamBuffer,
+    pending_pos: usize,
+}
+
+// ストリーミング中の状態
+// C の struct itedtv_usb_context の streaming 周りに該当 するらしい
+struct StreamContext
+{
+    shared: Arc<StreamShared>,
+    transfers: Vec<StreamTransfer>,
+    event_thread: Option<JoinHandle<()>>,
+    reader: Arc<Mutex<StreamReader>>,
+}
+
+fn usb_error_from_code(code: i32) -> rusb::Error
+{
+    match code
+    {
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        _ => rusb::Error::Io,
+    }
+}
+
+// itedtv_bus.c の itedtv_usb_complete 相当
+// 同じ endpoint の URB は submit した順に完了するので、完了した順に渡して、同じ順で再 submit すれば
+// TS の並びは崩れない。
+extern "system" fn stream_complete(transfer: *mut ffi::libusb_transfer)
+{
+    // user_data は StreamContext が持っている StreamShared で、全部の callback が返ってくるまで解放しない
+    let (shared, status, data) = unsafe
+    {
+        let t = &*transfer;
+        let shared = &*(t.user_data as *const StreamShared);
+        (shared, t.status, std::slice::from_raw_parts(t.buffer, t.actual_length.max(0) as usize))
+    };
+
+    let error = match status
+    {
+        LIBUSB_TRANSFER_COMPLETED | LIBUSB_TRANSFER_TIMED_OUT =>
+        {
+            if !data.is_empty()
+            {
+                // 受け取り側が詰まっている場合は捨てる (C で ringbuffer が溢れたときと同じ扱い)
+                match shared.tx.try_send(data.to_vec())
+                {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => { shared.dropped.fetch_add(1, Ordering::Relaxed); }
+                    Err(TrySendError::Disconnected(_)) => shared.running.store(false, Ordering::SeqCst),
+                }
+            }
+
+            let _lock = shared.submit_lock.lock().unwrap();
+            if shared.running.load(Ordering::SeqCst)
+            {
+                match unsafe { ffi::libusb_submit_transfer(transfer) }
+                {
+                    0 => return,
+                    code => Some(usb_error_from_code(code)),
+                }
+            }
+            else
+            {
+                None
+            }
+        }
+        LIBUSB_TRANSFER_CANCELLED => None,
+        LIBUSB_TRANSFER_NO_DEVICE => Some(rusb::Error::NoDevice),
+        LIBUSB_TRANSFER_STALL => Some(rusb::Error::Pipe),
+        LIBUSB_TRANSFER_OVERFLOW => Some(rusb::Error::Overflow),
+        _ => Some(rusb::Error::Io),
+    };
+
+    if let Some(e) = error
+    {
+        shared.fail(e);
+    }
+
+    shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+}
+
 // メモ: C の struct itedtv_bus に該当 するらしい
 pub struct UsbBusRusb
 {
-    handle: Mutex<DeviceHandle<Context>>,
+    // 受信スレッドと共有するので Arc にした。
+    // DeviceHandle は Sync なので、Bulk In は Control転送の lock を取らずに並行して投げられる。
+    handle: Arc<DeviceHandle<Context>>,
+    ctrl_lock: Mutex<()>,
     ctrl_tx_ep: u8,
     ctrl_rx_ep: u8,
     stream_ep: u8,
     ctrl_timeout: Duration,
     max_bulk_size: u32,
-    //streaming: // ... あとで足す
+
+    // C の urb_num と xfer_size
+    urb_num: u32,
+    xfer_size: usize,
+    streaming: Mutex<Option<StreamContext>>,
+
+    // 受け取り側が間に合わずに捨てた転送の数
+    dropped: Arc<AtomicU64>,
+
+    // デバイスが抜かれた (hotplug の通知か、NoDevice が返ってきた)
+    // 受信スレッドや hotplug の監視スレッドと共有する
+    disconnected: Arc<AtomicBool>,
 }
 
 impl UsbBusRusb
 {
-    pub fn new(handle: DeviceHandle<Context>) -> Result<Self, BusError>
+    // itedtv_bus.c の itedtv_bus_init 相当
+    // ディスクリプタと速度は DeviceHandle からは取れないので、Device も一緒にもらう
+    pub fn new(device: &Device<Context>, handle: DeviceHandle<Context>) -> Result<Self, BusError>
     {
-        // ここだとダメ
-        // main.rs で Device<Context> としている箇所じゃないといけないので、後で調整
-        //let usb_version = handle.device_descriptor().usb_version();
-        //if usb_version < 0x0110 
-        //{
-        //    return Err(BusError::Other(format!("USB device requires at least USB 1.1")));
-        //}
+        let usb_version = device.device_descriptor()?.usb_version();
+        if usb_version < Version(1, 1, 0)
+        {
+            return Err(BusError::Other(format!("USB device requires at least USB 1.1 (bcdUSB {})", usb_version)));
+        }
 
-        //let max_bulk_size = if usb_version == 0x0110 { 64 } else { 512 };
+        // USB 2.0 のデバイスでも、USB 1.1 のハブ越しだと Full Speed で繋がるので、実際の速度を見る
+        // 速度が分からなければ bcdUSB で判断する
+        let max_bulk_size = match device.speed()
+        {
+            Speed::Low => return Err(BusError::Other("USB device is connected at low speed.".to_string())),
+            Speed::Full => 64,
+            Speed::Unknown if usb_version == Version(1, 1, 0) => 64,
+            _ => 512,
+        };
 
-        Ok(Self 
-        { 
-            handle: Mutex::new(handle), // ここを Arc<Mutex<DeviceHandle<rusb::Context>>> として、参照カウントに対応するようにした方がいいと言われた
+        Ok(Self
+        {
+            handle: Arc::new(handle),
+            ctrl_lock: Mutex::new(()),
             ctrl_tx_ep: 0x02,
-            ctrl_rx_ep: 0x81, 
-            stream_ep: 0x84, 
+            ctrl_rx_ep: 0x81,
+            stream_ep: 0x84,
             ctrl_timeout: Duration::from_millis(3000), // px4_usb_params.c px4_usb_params.ctrl_timeout から。
-            max_bulk_size: 512, //max_bulk_size,
+            max_bulk_size,
+            urb_num: DEFAULT_URB_NUM,
+            xfer_size: align_xfer_size(DEFAULT_XFER_SIZE, max_bulk_size),
+            streaming: Mutex::new(None),
+            dropped: Arc::new(AtomicU64::new(0)),
+            disconnected: Arc::new(AtomicBool::new(false)),
         })
     }
+
+    // ストリーミング開始前に呼ぶ
+    // xfer_size は IT930xConfig::xfer_size を align_xfer_size で切り上げたものにすること
+    pub fn set_stream_params(&mut self, urb_num: u32, xfer_size: usize) -> Result<(), BusError>
+    {
+        if urb_num == 0 || xfer_size == 0 || !xfer_size.is_multiple_of(self.max_bulk_size as usize)
+        {
+            return Err(BusError::Other(format!("invalid stream params: urb_num={} xfer_size={}", urb_num, xfer_size)));
+        }
+
+        self.urb_num = urb_num;
+        self.xfer_size = xfer_size;
+        Ok(())
+    }
+
+    // 受け取り側が追いつかずに捨てた転送の数 (start_streaming で 0 に戻る)
+    // バスを IT930x に渡したあとも読めるように、カウンタごと渡す
+    pub fn dropped_counter(&self) -> Arc<AtomicU64>
+    {
+        Arc::clone(&self.dropped)
+    }
+
+    // hotplug の監視に渡して、抜かれたときに立ててもらう
+    pub fn disconnect_flag(&self) -> Arc<AtomicBool>
+    {
+        Arc::clone(&self.disconnected)
+    }
+
+    fn check_connected(&self) -> Result<(), BusError>
+    {
+        if self.disconnected.load(Ordering::SeqCst)
+        {
+            return Err(BusError::Disconnected);
+        }
+        Ok(())
+    }
+
+    // NoDevice は抜かれたということなので、以降は全部 Disconnected にする
+    fn map_usb_error(disconnected: &AtomicBool, e: rusb::Error) -> BusError
+    {
+        match e
+        {
+            rusb::Error::NoDevice =>
+            {
+                disconnected.store(true, Ordering::SeqCst);
+                BusError::Disconnected
+            }
+            e => BusError::Usb(e),
+        }
+    }
+
+    // callback は libusb_handle_events の中で呼ばれるので、返ってくる転送がある間は回し続ける
+    // (hotplug の監視スレッドも同じ Context で回しているが、libusb の中で排他されるので問題ない)
+    fn stream_events(context: Context, shared: Arc<StreamShared>)
+    {
+        let tv = libc::timeval { tv_sec: 0, tv_usec: STREAM_WORKER_TIMEOUT.as_micros() as libc::suseconds_t };
+
+        while shared.in_flight.load(Ordering::SeqCst) > 0
+        {
+            let ret = unsafe { ffi::libusb_handle_events_timeout_completed(context.as_raw(), &tv, std::ptr::null_mut()) };
+            if ret != 0 && ret != LIBUSB_ERROR_INTERRUPTED
+            {
+                shared.fail(usb_error_from_code(ret));
+                thread::sleep(STREAM_WORKER_TIMEOUT);
+            }
+        }
+    }
+
+    // 全部 cancel して、callback が返ってくるのを待ってから解放する
+    fn release_stream(ctx: StreamContext)
+    {
+        {
+            let _lock = ctx.shared.submit_lock.lock().unwrap();
+            ctx.shared.running.store(false, Ordering::SeqCst);
+            for t in &ctx.transfers
+            {
+                // 既に返ってきているものは NOT_FOUND になるだけ
+                unsafe { ffi::libusb_cancel_transfer(t.transfer) };
+            }
+        }
+
+        if let Some(t) = ctx.event_thread
+        {
+            let _ = t.join();
+        }
+
+        for t in ctx.transfers
+        {
+            unsafe { ffi::libusb_free_transfer(t.transfer) };
+        }
+    }
 }
 
 impl BusOps for UsbBusRusb
 {
     // itedtv_bus.c の 47〜70 と思われる。
-    fn ctrl_tx(&self, buf: &[u8]) -> Result<(), BusError> 
+    fn ctrl_tx(&self, buf: &[u8]) -> Result<(), BusError>
     {
-        let guarded_handle = self.handle.lock().unwrap();
+        self.check_connected()?;
+
+        let _lock = self.ctrl_lock.lock().unwrap();
         //self.handle.write_bulk(self.ctrl_ep, buf, self.ctrl_timeout,)?;
-        guarded_handle.write_bulk(self.ctrl_tx_ep, buf, self.ctrl_timeout,)?;
+        self.handle.write_bulk(self.ctrl_tx_ep, buf, self.ctrl_timeout,).map_err(|e| Self::map_usb_error(&self.disconnected, e))?;
 
         thread::sleep(Duration::from_millis(1));
-        Ok(())    
+        Ok(())
     }
 
     // itedtv_bus.c の 72〜97 と思われる。
     fn ctrl_rx(&self, buf: &mut [u8]) -> Result<usize, BusError>
     {
-        let guarded_handle = self.handle.lock().unwrap();
+        self.check_connected()?;
+
+        let _lock = self.ctrl_lock.lock().unwrap();
         //let read_len = self.handle.read_bulk(self.ctrl_ep, buf, self.ctrl_timeout)?;
-        let read_len = guarded_handle.read_bulk(self.ctrl_rx_ep, buf, self.ctrl_timeout)?;
+        let read_len = self.handle.read_bulk(self.ctrl_rx_ep, buf, self.ctrl_timeout).map_err(|e| Self::map_usb_error(&self.disconnected, e))?;
 
         // あとで消す
         //if read_len != buf.len()
@@ -112,34 +389,180 @@ impl BusOps for UsbBusRusb
     }
 
     // itedtv_bus.c の 99〜118 と思われる。
+    // ストリーミング中は、完了 callback が受け取った転送を channel から取り出す。
+    // ストリーミングしていなければ、今まで通り直接 read_bulk する。
     fn stream_rx(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, BusError>
     {
-        let guarded_handle = self.handle.lock().unwrap();
-        //let size = self.handle.read_bulk(self.stream_ep, buf, self.stream_timeout)?;
-        let size = guarded_handle.read_bulk(self.stream_ep, buf, timeout)?;
-        Ok(size)
+        self.check_connected()?;
+
+        // 待っている間に stop_streaming できるように、streaming の lock はすぐ放す
+        let (shared, reader) = match self.streaming.lock().unwrap().as_ref()
+        {
+            Some(ctx) => (Arc::clone(&ctx.shared), Arc::clone(&ctx.reader)),
+            None =>
+            {
+                //let size = self.handle.read_bulk(self.stream_ep, buf, self.stream_timeout)?;
+                let size = self.handle.read_bulk(self.stream_ep, buf, timeout).map_err(|e| Self::map_usb_error(&self.disconnected, e))?;
+                return Ok(size);
+            }
+        };
+
+        let mut reader = reader.lock().unwrap();
+
+        if reader.pending_pos >= reader.pending.len()
+        {
+            // 抜かれたり止められたりしたらすぐ返れるように、STREAM_WORKER_TIMEOUT ごとに確認しながら待つ
+            let deadline = Instant::now() + timeout;
+            reader.pending = loop
+            {
+                let wait = deadline.saturating_duration_since(Instant::now()).min(STREAM_WORKER_TIMEOUT);
+                match reader.rx.recv_timeout(wait)
+                {
+                    Ok(data) => break data,
+                    Err(RecvTimeoutError::Timeout) =>
+                    {
+                        self.check_connected()?;
+                        if let Some(e) = shared.error.lock().unwrap().take()
+                        {
+                            return Err(e);
+                        }
+                        if Instant::now() >= deadline || !shared.running.load(Ordering::SeqCst)
+                        {
+                            return Err(BusError::Timeout);
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return Err(BusError::Disconnected),
+                }
+            };
+            reader.pending_pos = 0;
+        }
+
+        let pos = reader.pending_pos;
+        let len = buf.len().min(reader.pending.len() - pos);
+        buf[..len].copy_from_slice(&reader.pending[pos..pos + len]);
+        reader.pending_pos += len;
+
+        Ok(len)
     }
 
     // itedtv_bus.c の 411〜509 と思われる。
-    // mutex や メモリ確保、とかのように見える。
-    fn start_streaming(&self) -> Result<(), BusError> 
+    // C と同じく、urb_num 個の転送を順に submit しておき、完了 callback の中で再 submit する。
+    fn start_streaming(&self) -> Result<(), BusError>
     {
-        // C では、フラグ管理らしいので、それらしきことをすれば良い？
-        Ok(())    
+        let mut streaming = self.streaming.lock().unwrap();
+        if streaming.is_some()
+        {
+            return Ok(());
+        }
+
+        self.check_connected()?;
+
+        // C でも開始時に ep の halt をクリアしている
+        self.handle.clear_halt(self.stream_ep).map_err(|e| Self::map_usb_error(&self.disconnected, e))?;
+
+        // urb_num 個分 + 余裕を持たせて、受け取り側が少し遅れても捨てずに済むようにする
+        let (tx, rx) = mpsc::sync_channel(self.urb_num as usize * 4);
+        self.dropped.store(0, Ordering::Relaxed);
+
+        let shared = Arc::new(StreamShared
+        {
+            running: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+            submit_lock: Mutex::new(()),
+            tx,
+            error: Mutex::new(None),
+            dropped: Arc::clone(&self.dropped),
+            disconnected: Arc::clone(&self.disconnected),
+        });
+
+        let mut ctx = StreamContext
+        {
+            shared: Arc::clone(&shared),
+            transfers: Vec::with_capacity(self.urb_num as usize),
+            event_thread: None,
+            reader: Arc::new(Mutex::new(StreamReader { rx, pending: Vec::new(), pending_pos: 0 })),
+        };
+
+        let mut result = Ok(());
+        for _ in 0..self.urb_num
+        {
+            let transfer = unsafe { ffi::libusb_alloc_transfer(0) };
+            if transfer.is_null()
+            {
+                result = Err(BusError::Usb(rusb::Error::NoMem));
+                break;
+            }
+
+            let mut buf = vec![0u8; self.xfer_size].into_boxed_slice();
+            unsafe
+            {
+                // タイムアウトは無し (C と同じ)。止めるときは cancel する
+                ffi::libusb_fill_bulk_transfer(transfer, self.handle.as_raw(), self.stream_ep, buf.as_mut_ptr(), buf.len() as i32, stream_complete, Arc::as_ptr(&shared) as *mut libc::c_void, 0);
+            }
+            ctx.transfers.push(StreamTransfer { transfer, _buf: buf });
+
+            shared.in_flight.fetch_add(1, Ordering::SeqCst);
+            let ret = unsafe { ffi::libusb_submit_transfer(transfer) };
+            if ret != 0
+            {
+                shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+                result = Err(Self::map_usb_error(&self.disconnected, usb_error_from_code(ret)));
+                break;
+            }
+        }
+
+        // 途中で失敗しても、submit 済みの分を回収するためにイベント処理は回す
+        let context = self.handle.context().clone();
+        let shared_t = Arc::clone(&shared);
+        match thread::Builder::new().name("px4-stream".to_string()).spawn(move || Self::stream_events(context, shared_t))
+        {
+            Ok(t) => ctx.event_thread = Some(t),
+            Err(e) =>
+            {
+                // 回収できないまま解放すると libusb が解放済みの buffer に書くので、ここは諦める
+                ctx.shared.running.store(false, Ordering::SeqCst);
+                std::mem::forget(ctx);
+                return Err(BusError::Other(format!("failed to spawn stream thread: {}", e)));
+            }
+        }
+
+        if let Err(e) = result
+        {
+            Self::release_stream(ctx);
+            return Err(e);
+        }
+
+        *streaming = Some(ctx);
+        Ok(())
     }
 
     // itedtv_bus.c の 511〜540 と思われる。
-    // たぶん、streaming の開始で取った諸々を片付ける処理が入っている、と思われる。
-    fn stop_streaming(&self) -> Result<(), BusError> 
+    // 転送を全部 cancel して、返ってきたら解放する。
+    fn stop_streaming(&self) -> Result<(), BusError>
     {
-        // C では、フラグ管理らしいので、それらしきことをすれば良い？
+        let ctx = match self.streaming.lock().unwrap().take()
+        {
+            Some(ctx) => ctx,
+            None => return Ok(()),
+        };
+
+        Self::release_stream(ctx);
         Ok(())
     }
 
-    fn max_bulk_size(&self) -> u32 
+    fn max_bulk_size(&self) -> u32
     {
         self.max_bulk_size
     }
 }
 
+// 転送が残ったまま buffer を解放すると libusb が解放済みのところに書くので、必ず止めてから
+impl Drop for UsbBusRusb
+{
+    fn drop(&mut self)
+    {
+        let _ = self.stop_streaming();
+    }
+}
+
 // ここまでが USBバスレイヤー
\ No newline at end of file
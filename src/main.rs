@@ -1,130 +1,529 @@
 mod itedtv_bus;
+#[cfg(test)]
+mod it930x_sim;
+mod bus_record;
 mod it930x;
 mod rt710;
 mod r850;
 mod tc90522;
+mod ts_framer;
+mod ts_demux;
+mod ts_splitter;
 mod px4_device;
+mod device_profile;
+mod device_manager;
+mod multi_device;
+mod hotplug;
+mod channel;
+mod cli;
 
-use rusb::{Context, UsbContext};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
-use itedtv_bus::UsbBusRusb;
-use it930x::IT930x;
-use px4_device::Px4Device;
+use channel::Channel;
+use cli::{CliAction, Destination, LnbVoltage, RecOptions};
+use device_manager::{DeviceInfo, DeviceManager, DeviceSelector};
+use device_profile::DeviceProfile;
+use hotplug::{HotplugEvent, HotplugWatcher};
+use itedtv_bus::{align_xfer_size, BusError, BusOps, UsbBusRusb, DEFAULT_URB_NUM, DEFAULT_XFER_SIZE};
+use bus_record::RecordBus;
+use it930x::{IT930x, IT930xConfig};
+use multi_device::{PowerCoordinator, PowerMemberKey};
+use px4_device::{apply_power, Px4Device, System, PX4_LNB_VOLTAGE};
+use ts_splitter::TsSplitter;
 
-fn main()
+// --signal の表示間隔
+const SIGNAL_INTERVAL: Duration = Duration::from_secs(1);
+// BER / PER を測るパケット数 (地デジの 13セグでも 1秒かからずに測り終わるくらい)
+const SIGNAL_BER_PERIOD: u16 = 10000;
+
+// stream_rx 1回あたりの待ち時間
+const STREAM_RX_TIMEOUT: Duration = Duration::from_millis(500);
+
+// 抜かれた後、挿し直されたかを確認する間隔と、挿し直されてから開くまでの待ち時間
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+// SIGINT/SIGTERM を受けたら立てる (recpt1 と同じく、途中で止めても普通に閉じて終わる)
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop_signal(_: libc::c_int)
 {
-    // まず、USB関連の準備
-    let context = match Context::new()
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// ハンドラの中ではフラグを立てるだけで、各ループがそれを見て抜ける
+// そのあとは通常の終了処理 (flush、ストリーム停止、LNB/チューナーの電源断) がそのまま走る
+fn install_stop_handler()
+{
+    let handler = on_stop_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe
+    {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+fn stop_requested() -> bool
+{
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+// 録画時間が過ぎたか、止めるように言われたか
+fn should_stop(deadline: Option<Instant>) -> bool
+{
+    stop_requested() || deadline.map(|d| Instant::now() >= d).unwrap_or(false)
+}
+
+fn main() -> ExitCode
+{
+    let opts = match cli::parse_args(std::env::args().skip(1))
     {
-        Ok(c) => c,
+        Ok(CliAction::Record(opts)) => opts,
+        Ok(CliAction::List) =>
+        {
+            return match list_devices()
+            {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) =>
+                {
+                    eprintln!("{}", e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Ok(CliAction::Help) =>
+        {
+            eprintln!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
         Err(e) =>
         {
-            println!("Failed to create USB context: {}", e);
-            return;
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            return ExitCode::FAILURE;
         }
     };
 
-    // USBデバイスの検索
-    const PX4_VID: u16 = 0x0511;
-    const PX4_PID: u16 = 0x083f;
+    install_stop_handler();
 
-    let devices = match context.devices()
+    match record(&opts)
     {
-        Ok(d) => d,
-        Err(e) => 
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) =>
         {
-            println!("Failed to list USB devices: {}", e);
-            return;
+            eprintln!("{}", e);
+            ExitCode::FAILURE
         }
-    };
+    }
+}
+
+// 繋がっているデバイスと、px4videoN の番号の対応を出す
+fn list_devices() -> Result<(), String>
+{
+    let manager = DeviceManager::new().map_err(|e| format!("Failed to list USB devices: {}", e))?;
+
+    let mut n = 0;
+    for (i, d) in manager.devices().iter().enumerate()
+    {
+        let num = d.profile.inputs.len();
+        println!("#{} {} serial: {} (bus {} addr {}) px4video{}-{}{}", i, d.profile.name, d.serial.as_deref().unwrap_or("-"), d.bus_number, d.address, n, n + num - 1,
+            if d.profile.is_supported() { "" } else { " [not supported]" });
+        n += num;
+    }
+
+    Ok(())
+}
+
+fn record(opts: &RecOptions) -> Result<(), String>
+{
+    let channel = channel::parse_channel(&opts.channel).map_err(|e| e.to_string())?;
 
-    let device = match devices.iter().find(|d|
+    // 出力先は、チューナーを触る前に開いておく (開けなかったときに無駄に選局しないように)
+    let mut writer: Box<dyn Write> = match &opts.dest
     {
-        d.device_descriptor().map(|desc| desc.vendor_id() == PX4_VID && desc.product_id() == PX4_PID).unwrap_or(false)
-    })
+        _ if opts.signal => Box::new(io::sink()),
+        Destination::Stdout => Box::new(BufWriter::new(io::stdout().lock())),
+        Destination::File(path) => Box::new(BufWriter::new(File::create(path).map_err(|e| format!("Failed to open {}: {}", path, e))?)),
+    };
+
+    // まず、USB関連の準備
+    let mut manager = DeviceManager::new().map_err(|e| format!("Failed to list USB devices: {}", e))?;
+
+    // 抜き差しの監視 (使えなければ、抜かれたらそこで終わり)
+    let watcher = match HotplugWatcher::start(manager.context())
     {
-        Some(d) => d,
-        None => 
+        Ok(w) => Some(w),
+        Err(e) =>
         {
-            println!("PX4 device not found.");
-            return;
+            eprintln!("hotplug is not available: {:?}", e);
+            None
         }
     };
 
-    // USBデバイスを開く
-    let handle = match device.open()
+    // --serial があれば、そのデバイスの中での番号。無ければ全デバイスの通し番号
+    let (mut selector, chrdev_index) = match (&opts.serial, opts.device)
     {
-        Ok(h) => h,
-        Err(e) =>
+        (Some(serial), n) => (DeviceSelector::Serial(serial.clone()), n.map(|n| n as usize)),
+        (None, Some(n)) =>
         {
-            println!("Failed to open device: {}", e);
-            return;
+            let (dev, chrdev) = manager.resolve_chrdev(n).map_err(|e| e.to_string())?;
+            (DeviceSelector::Index(dev), Some(chrdev))
         }
+        (None, None) => (DeviceSelector::Index(0), None),
     };
 
-    // USBデバイスを占有する
-    if let Err(e) = handle.claim_interface(0)
+    // 挿し直したときに同じ箱を開けるように、シリアル番号が読めればそれで指定し直す
+    if let Ok((_, DeviceInfo { serial: Some(serial), .. })) = manager.find(&selector)
+    {
+        selector = DeviceSelector::Serial(serial.clone());
+    }
+
+    let deadline = opts.duration.map(|d| Instant::now() + d);
+    let target = RecordTarget { opts, channel: &channel, chrdev_index, deadline };
+
+    loop
+    {
+        match record_session(&manager, watcher.as_ref(), &selector, &target, &mut writer)?
+        {
+            SessionEnd::Done => return Ok(()),
+            SessionEnd::Disconnected => {}
+        }
+
+        if stop_requested()
+        {
+            return Ok(());
+        }
+
+        let watcher = watcher.as_ref().ok_or("device disconnected.")?;
+        eprintln!("device disconnected. waiting for it to come back...");
+        if !wait_reconnect(&mut manager, watcher, &selector, deadline)
+        {
+            if !stop_requested()
+            {
+                eprintln!("device did not come back before the end of recording.");
+            }
+            return Ok(());
+        }
+    }
+}
+
+// 何をどこまで録るか (挿し直してやり直すときも同じものを使う)
+#[derive(Clone, Copy)]
+struct RecordTarget<'a>
+{
+    opts: &'a RecOptions,
+    channel: &'a Channel,
+    // 開くチューナーの番号 (None なら、チャンネルを受けられる最初のもの)
+    chrdev_index: Option<usize>,
+    deadline: Option<Instant>,
+}
+
+enum SessionEnd
+{
+    Done,
+    // 途中で抜かれた (挿し直されたら最初からやり直す)
+    Disconnected,
+}
+
+// デバイスを開いて、初期化から録画までの1回分
+fn record_session(manager: &DeviceManager, watcher: Option<&HotplugWatcher>, selector: &DeviceSelector, target: &RecordTarget, writer: &mut dyn Write) -> Result<SessionEnd, String>
+{
+    let (info, handle) = manager.open(selector).map_err(|e| format!("Failed to open device: {}", e))?;
+    let profile = info.profile;
+
+    if !profile.is_supported()
+    {
+        return Err(format!("{} is not supported yet.", profile.name));
+    }
+
+    // R850 の校正キャッシュのキーに使う
+    let serial = info.key();
+
+    let bus = UsbBusRusb::new(info.device(), handle).map_err(|e| format!("Failed to UsbBusRusb::new(): {:?}", e))?;
+
+    // 抜かれたら、待っている ctrl_msg / stream_rx がすぐ Disconnected で返ってくるようにする
+    let disconnected = bus.disconnect_flag();
+    if let Some(w) = watcher
+    {
+        w.watch(info.bus_number, info.address, Arc::clone(&disconnected));
+    }
+
+    let result = record_on_bus(bus, profile, &serial, target, writer);
+
+    if let Some(w) = watcher
+    {
+        w.unwatch(info.bus_number, info.address);
+    }
+
+    match result
+    {
+        Ok(()) => Ok(SessionEnd::Done),
+        Err(e) if disconnected.load(Ordering::SeqCst) =>
+        {
+            eprintln!("{}", e);
+            Ok(SessionEnd::Disconnected)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// selector のデバイスが挿し直されるまで待つ。deadline を過ぎたか、止めるように言われたら false
+fn wait_reconnect(manager: &mut DeviceManager, watcher: &HotplugWatcher, selector: &DeviceSelector, deadline: Option<Instant>) -> bool
+{
+    loop
     {
-        println!("Failed to claim interface 0: {}", e);
+        let wait = deadline.map(|d| d.saturating_duration_since(Instant::now()).min(RECONNECT_POLL_INTERVAL)).unwrap_or(RECONNECT_POLL_INTERVAL);
+        if wait.is_zero() || stop_requested()
+        {
+            return false;
+        }
+
+        // 通知が来なくても、一定間隔で数え直す
+        match watcher.wait_event(wait)
+        {
+            Some(HotplugEvent::Arrived { bus_number, address, profile }) => eprintln!("{} arrived. (bus {} addr {})", profile.name, bus_number, address),
+            Some(HotplugEvent::Left { bus_number, address }) => eprintln!("device left. (bus {} addr {})", bus_number, address),
+            None => {}
+        }
+        if manager.scan().is_ok() && manager.find(selector).is_ok()
+        {
+            // 挿した直後は落ち着くまで少し待つ
+            std::thread::sleep(RECONNECT_SETTLE_TIME);
+            return true;
+        }
     }
+}
 
+fn record_on_bus(mut bus: UsbBusRusb, profile: &'static DeviceProfile, serial: &str, target: &RecordTarget, writer: &mut dyn Write) -> Result<(), String>
+{
     // 各種、デバイス操作用の準備
-    let bus = match UsbBusRusb::new(handle)
+    // 受信側の転送サイズは、繋がった速度の max_bulk_size に合わせる (0xdd0c は config_stream_output で書く)
+    let config = IT930xConfig::from_profile(profile);
+    let xfer_size = align_xfer_size(config.xfer_size as usize, bus.max_bulk_size());
+    bus.set_stream_params(DEFAULT_URB_NUM, xfer_size).map_err(|e| format!("Failed to set stream params: {:?}", e))?;
+    let dropped = bus.dropped_counter();
+
+    // --record-bus があれば、USB のやりとりを記録しながら動かす (ReplayBus で再生できる)
+    // 挿し直されたときは作り直すので、残るのは最後に開いた分だけ
+    let result = match &target.opts.record_bus
     {
-        Ok(b) => b,
-        Err(e) =>
+        Some(path) =>
         {
-            println!("Failed to UsbBusRusb::new().");
-            return;
+            let bus = RecordBus::new(bus, path).map_err(|e| format!("Failed to open {}: {:?}", path, e))?;
+            init_and_record(IT930x::with_config(bus, config), profile, serial, target, writer)
         }
+        None => init_and_record(IT930x::with_config(bus, config), profile, serial, target, writer),
+    };
+
+    // 書き出しが遅くて、USB から受け取ったまま捨てた転送
+    let dropped = dropped.load(Ordering::Relaxed);
+    if dropped != 0
+    {
+        eprintln!("dropped {} transfers because the output could not keep up.", dropped);
+    }
+
+    result
+}
+
+fn init_and_record<B: BusOps>(it930x: IT930x<B>, profile: &'static DeviceProfile, serial: &str, target: &RecordTarget, writer: &mut dyn Write) -> Result<(), String>
+{
+
+    it930x.raise().map_err(|e| format!("Failed to raise.: {}", e))?;
+    // EEPROM が読めなくても既定の設定で動くので、警告だけ出す
+    if let Err(e) = it930x.check_epprom()
+    {
+        eprintln!("Failed to check EEPROM.: {}", e);
+    }
+    it930x.load_firmware("it930x-firmware.bin").map_err(|e| format!("Failed to load firmware.: {}", e))?;
+    it930x.init_warm().map_err(|e| format!("Failed to initial warm.: {}", e))?;
+
+    // Q3U4 / Q3PE4 は相方と電源を連動させる
+    let mut coordinator = PowerCoordinator::new(!target.opts.no_multi_device_power, &multi_device::default_state_dir());
+    coordinator.set_sibling_power_off(power_off_sibling);
+
+    let mut px4dev = Px4Device::with_profile(&it930x, profile);
+    if px4dev.join_power_group(&coordinator, serial)
+    {
+        eprintln!("multi device power control: {} ({})", profile.name, serial);
+    }
+    px4dev.init_gpio().map_err(|e| format!("Failed to set gpio.: {}", e))?;
+
+    // R850 の校正 (2回目以降はキャッシュから)
+    let cache_dir = std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".cache").join("px4_drv"))
+        .unwrap_or_else(|| PathBuf::from("."));
+    px4dev.set_r850_calibration_cache(&cache_dir, serial);
+
+    record_on(&mut px4dev, target, writer)
+}
+
+// 他のプロセスが電源を入れたまま手放した相方を、シリアル番号で探して開き、電源を切る
+// (ファームウェアは電源を入れたときのまま動いているので、GPIO を書くだけ)
+fn power_off_sibling(key: PowerMemberKey) -> bool
+{
+    let Ok(manager) = DeviceManager::new() else { return false; };
+    let Some(info) = manager.devices().iter().find(|d| d.serial.as_deref().and_then(multi_device::parse_px4_serial) == Some((key.serial, key.dev_id))) else { return false; };
+    let Ok((_, handle)) = manager.open(&DeviceSelector::Serial(info.key())) else { return false; };
+    let Ok(bus) = UsbBusRusb::new(info.device(), handle) else { return false; };
+
+    let it930x = IT930x::with_config(bus, IT930xConfig::from_profile(info.profile));
+    apply_power(&it930x, info.profile, false).is_ok()
+}
+
+fn record_on<B: BusOps>(px4dev: &mut Px4Device<B>, target: &RecordTarget, writer: &mut dyn Write) -> Result<(), String>
+{
+    let RecordTarget { opts, channel, chrdev_index, deadline } = *target;
+    let system = channel.system;
+    let inputs = px4dev.profile().inputs;
+
+    let index = match chrdev_index
+    {
+        Some(i) => i,
+        // 指定が無ければ、そのシステムの最初のチューナー
+        None => inputs.iter().position(|i| i.systems[0] == system).ok_or("no tuner for the channel.")?,
     };
 
-    let it930x = IT930x::new(bus);
+    if inputs.get(index).ok_or_else(|| format!("tuner {} not found.", index))?.systems[0] != system
+    {
+        return Err(format!("px4video{} can not receive {}.", index, opts.channel));
+    }
+
+    // PX4 は 15V 固定 (--lnb 11 は cli で弾いている)
+    let lnb = if system == System::IsdbS && opts.lnb != LnbVoltage::Off { PX4_LNB_VOLTAGE } else { 0 };
+
+    // 最初に開いたところで電源が入り、閉じたところで切れる
+    px4dev.open(index, lnb).map_err(|e| format!("Failed to open px4video{}: {}", index, e))?;
+
+    let result = tune_and_run(px4dev, opts, channel, index, deadline, writer);
+
+    // 後片付けは失敗しても続ける
+    if let Err(e) = px4dev.close(index)
+    {
+        eprintln!("Failed to close px4video{}: {}", index, e);
+    }
+
+    result
+}
 
-    // 疎通チェック
-    if let Err(e) = it930x.raise()
+fn tune_and_run<B: BusOps>(px4dev: &mut Px4Device<B>, opts: &RecOptions, channel: &Channel, index: usize, deadline: Option<Instant>, writer: &mut dyn Write) -> Result<(), String>
+{
+    let tsid = px4dev.tune(index, channel).map_err(|e| format!("Failed to tune {}: {}", opts.channel, e))?;
+
+    match tsid
     {
-        println!("Failed to raise.: {}", e);
-        return;
+        Some(tsid) => eprintln!("tuned. channel: {} tsid: 0x{:04x} device: px4video{}", channel, tsid, index),
+        None => eprintln!("tuned. channel: {} device: px4video{}", channel, index),
     }
 
-    if let Err(e) = it930x.load_firmware("it930x-firmware.bin")
+    // 地デジは TMCC (階層ごとの変調方式とセグメント数) も出す
+    if channel.system == System::IsdbT
     {
-        println!("Failed to load firmware.: {}", e);
-        return;
+        let chrdev = px4dev.chrdev(index).ok_or("tuner disappeared.")?;
+        match chrdev.tmcc_info_t()
+        {
+            Ok(tmcc) => eprintln!("TMCC: {}", tmcc),
+            Err(e) => eprintln!("Failed to get TMCC: {}", e),
+        }
     }
 
-    if let Err(e) = it930x.init_warm()
+    if opts.signal
     {
-        println!("Failed to initial warm.: {}", e);
-        return;
+        return signal_loop(px4dev, index, deadline);
     }
 
-    it930x.set_gpio_mode(7, it930x::GpioMode::Out, true).expect("gpio7 mode failed");
-    it930x.set_gpio_mode(2, it930x::GpioMode::Out, true).expect("gpio2 mode failed");
+    px4dev.start_capture(index).map_err(|e| format!("Failed to start streaming: {}", e))?;
+
+    stream_loop(px4dev, index, opts, deadline, writer)
+}
+
+// recpt1 の --signal (checksignal) 相当
+// 1秒ごとに C/N と RF レベルを出す
+fn signal_loop<B: BusOps>(px4dev: &mut Px4Device<B>, index: usize, deadline: Option<Instant>) -> Result<(), String>
+{
+    let chrdev = px4dev.chrdev(index).ok_or("tuner disappeared.")?;
+    chrdev.set_ber_period(SIGNAL_BER_PERIOD).map_err(|e| format!("Failed to set BER period: {}", e))?;
+    chrdev.reset_error_counters().map_err(|e| format!("Failed to reset error counters: {}", e))?;
+
+    while !should_stop(deadline)
+    {
+        let stats = chrdev.signal_stats().map_err(|e| format!("Failed to get signal: {}", e))?;
+        let errors = chrdev.error_counters().map_err(|e| format!("Failed to get error counters: {}", e))?;
+        // R850 の RF は大まかな相対値なので、そう書いておく
+        eprint!("\rSIGNAL: C/N = {:5.2}dB, RF = {:6.1}dBm{}, BER = {:.2e}, PER = {:.2e}{}", stats.cnr, stats.rf_level, if stats.rf_level_rough { " (rough)" } else { "" },
+            errors.ber(), errors.per(), if stats.locked { "" } else { " (unlocked)" });
+        std::thread::sleep(SIGNAL_INTERVAL);
+    }
+    eprintln!();
 
-    it930x.write_gpio(7, true).expect("gpio7 write failed");
-    it930x.write_gpio(2, false).expect("gpio2 write failed");
+    Ok(())
+}
 
-    it930x.set_gpio_mode(11, it930x::GpioMode::Out, true).expect("gpio11 mode failed");
-    it930x.write_gpio(11, false).expect("gpio11 write failed.");
+fn stream_loop<B: BusOps>(px4dev: &mut Px4Device<B>, index: usize, opts: &RecOptions, deadline: Option<Instant>, writer: &mut dyn Write) -> Result<(), String>
+{
+    let mut splitter = TsSplitter::new(opts.sid.clone(), opts.strip);
+    let mut rx_buf = vec![0u8; DEFAULT_XFER_SIZE];
+    let mut ts_buf = vec![0u8; DEFAULT_XFER_SIZE];
+    let mut out = Vec::with_capacity(DEFAULT_XFER_SIZE);
 
-    // Px4Device の init() で、R850 や RT710 の read_regs が走るので、いつ init() すべきかは、ちゃんと考える必要がある。
-    let mut px4dev = Px4Device::new(&it930x);
+    let start = Instant::now();
+    let mut written: u64 = 0;
 
-    println!("[debug] px4_dev.set_power() start => ");
-    if let Err(e) = px4dev.set_power(true)
+    while !should_stop(deadline)
     {
-        println!("Failed to TunerError: {}", e);
+        match px4dev.pump_stream(&mut rx_buf, STREAM_RX_TIMEOUT)
+        {
+            Ok(_) | Err(BusError::Timeout) => {}
+            Err(e) => return Err(format!("stream error: {:?}", e)),
+        }
+
+        let chrdev = px4dev.chrdev_mut(index).ok_or("tuner disappeared.")?;
+        let len = chrdev.read_stream(&mut ts_buf);
+
+        out.clear();
+        for packet in ts_buf[..len].chunks_exact(ts_demux::TS_PACKET_SIZE)
+        {
+            splitter.push(packet, &mut out);
+        }
+
+        if !out.is_empty()
+        {
+            match writer.write_all(&out)
+            {
+                Ok(()) => written += out.len() as u64,
+                // 受け取り側 (パイプ) が閉じたら終わり
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                Err(e) => return Err(format!("write error: {}", e)),
+            }
+        }
     }
 
-    println!("[debug] px4_dev.init() start => ");
-    if let Err(e) = px4dev.init()
+    writer.flush().map_err(|e| format!("write error: {}", e))?;
+
+    eprintln!("recorded {} bytes in {:.1} sec. ({} packets from px4video{})", written, start.elapsed().as_secs_f64(), px4dev.demux().packets(index), index);
+
+    // 読み出しが追いつかずに、チャンネルのバッファから溢れた分
+    let overflow = px4dev.chrdev(index).map(|c| c.stream_overflow()).unwrap_or(0);
+    if overflow != 0
     {
-        println!("Failed to TunerError: {}", e);
-        return;
+        eprintln!("dropped {} packets because the tuner buffer was full.", overflow);
     }
 
-    println!("[debug] Passed!")
+    // 188 byte 境界が崩れて読み捨てた分と、どの入力のものでもなかったパケット (転送の取りこぼしなどの目安)
+    let demux = px4dev.demux();
+    let framer = demux.framer();
+    if framer.dropped_bytes() != 0 || demux.unknown_packets() != 0
+    {
+        eprintln!("dropped {} bytes while syncing (resyncs: {}, {} bytes) and {} packets of unknown inputs.", framer.dropped_bytes(), framer.resyncs(), framer.resynced_bytes(), demux.unknown_packets());
+    }
+    if !opts.sid.is_empty()
+    {
+        eprintln!("sid: {:?}", splitter.selected_sids());
+    }
 
+    Ok(())
 }
@@ -0,0 +1,487 @@
+// 複数デバイスの電源連動 (px4_mldev.c の移植)
+// PX-Q3U4 / PX-Q3PE4 は、中で PX-W3U4 相当の IT930x が 2つハブにぶら下がっていて、チューナーの電源を共有している。
+// 片方だけ電源を落とすと、もう片方で使っているチューナーまで止まってしまうので、
+// シリアル番号で 2つを組にして、どちらかが使っている間は両方とも電源を入れたままにする。
+//
+// シリアル番号は 10進で、最後の1桁がデバイス番号 (1 / 2)、それより上が組で共通の番号。
+// px4_drv の disable_multi_device_power_control 相当は PowerCoordinator::new(false, ..)
+//
+// px4_drv はドライバが 2つとも持っているので組の状態はメモリにあれば良いが、こちらは
+// 相方を別のプロセス (別の録画) が開いていることがあるので、誰が電源を要求しているかは
+// 組ごとのファイル (<state_dir>/mldev-<組の番号>.lock) に (pid, デバイス番号) で書いて共有する。
+// 読み書きの間は flock で排他し、死んだプロセスの行は読むときに捨てる。
+//
+// 相方がまだ使っているので電源を入れたまま手放したデバイスは "on <デバイス番号>" の行で残しておき、
+// 最後に切るプロセスが (自分で開いていなければ set_sibling_power_off で渡した方法で開いて) まとめて切る。
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::device_profile::DeviceProfile;
+use crate::it930x::{CtrlMsgError, IT930x};
+use crate::itedtv_bus::BusOps;
+use crate::px4_device::apply_power;
+
+// 1組あたりのデバイス数
+pub const MULTI_DEVICE_NUM: usize = 2;
+
+// px4_usb.c の px4_parse_serial_number 相当
+// (組の番号, 組の中の何番目か) を返す
+pub fn parse_px4_serial(serial: &str) -> Option<(u64, usize)>
+{
+    let n = serial.trim().parse::<u64>().ok()?;
+    let dev_id = (n % 10) as usize;
+    if !(1..=MULTI_DEVICE_NUM).contains(&dev_id)
+    {
+        return None;
+    }
+
+    Some((n / 10, dev_id - 1))
+}
+
+// 組の状態ファイルの置き場所
+// /run/px4_drv が使えれば (root なら) そこ、使えなければ一時ディレクトリの下
+pub fn default_state_dir() -> PathBuf
+{
+    let run = PathBuf::from("/run/px4_drv");
+    if fs::create_dir_all(&run).is_ok() && is_writable(&run)
+    {
+        return run;
+    }
+
+    std::env::temp_dir().join("px4_drv")
+}
+
+fn is_writable(path: &Path) -> bool
+{
+    let Ok(c) = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()) else { return false; };
+    unsafe { libc::access(c.as_ptr(), libc::W_OK) == 0 }
+}
+
+// pid のプロセスがまだ居るか (別ユーザーのプロセスは EPERM になるが、居ることには変わりない)
+fn process_alive(pid: u32) -> bool
+{
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0
+    {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// 組の状態ファイルの中身
+#[derive(Debug, Default)]
+struct GroupState
+{
+    // 電源を要求している (pid, dev_id)
+    requests: Vec<(u32, usize)>,
+    // 相方のために電源を入れたまま手放した dev_id
+    left_on: Vec<usize>,
+}
+
+// 組ごとの電源の要求 (プロセスをまたいで共有する参照カウント)
+struct SharedPowerState
+{
+    dir: PathBuf,
+}
+
+impl SharedPowerState
+{
+    fn path(&self, serial: u64) -> PathBuf
+    {
+        self.dir.join(format!("mldev-{}.lock", serial))
+    }
+
+    // 組の状態を f で書き換え、書き換えた後の状態を返す
+    fn update<F: FnOnce(&mut GroupState)>(&self, serial: u64, f: F) -> io::Result<GroupState>
+    {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(self.path(serial))?;
+
+        // close で外れる
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+
+        let mut state = GroupState::default();
+        for (first, id) in text.lines().filter_map(|l| l.split_once(' '))
+        {
+            let Ok(id) = id.parse() else { continue; };
+            match first
+            {
+                "on" => state.left_on.push(id),
+                pid => if let Ok(pid) = pid.parse()
+                {
+                    if process_alive(pid)
+                    {
+                        state.requests.push((pid, id));
+                    }
+                }
+            }
+        }
+
+        f(&mut state);
+
+        Self::write_state(&mut file, &state)?;
+        Ok(state)
+    }
+
+    fn write_state(file: &mut File, state: &GroupState) -> io::Result<()>
+    {
+        let text: String = state.requests.iter().map(|(pid, id)| format!("{} {}\n", pid, id))
+            .chain(state.left_on.iter().map(|id| format!("on {}\n", id)))
+            .collect();
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(text.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PowerMemberKey
+{
+    pub serial: u64,
+    pub dev_id: usize,
+}
+
+struct PowerMember<'a, B: BusOps>
+{
+    it930x: &'a IT930x<B>,
+    profile: &'static DeviceProfile,
+    // このプロセスから電源を入れているか
+    powered: bool,
+}
+
+struct PowerGroup<'a, B: BusOps>
+{
+    members: [Option<PowerMember<'a, B>>; MULTI_DEVICE_NUM],
+}
+
+pub struct PowerCoordinator<'a, B: BusOps>
+{
+    enabled: bool,
+    // このプロセスで開いているデバイス
+    groups: Mutex<HashMap<u64, PowerGroup<'a, B>>>,
+    // 組の誰が電源を要求しているか (他のプロセスの分も含む)
+    shared: SharedPowerState,
+    // このプロセスで開いていない相方の電源を切る (切れたら true)
+    sibling_power_off: Option<Box<dyn Fn(PowerMemberKey) -> bool>>,
+}
+
+impl<'a, B: BusOps> PowerCoordinator<'a, B>
+{
+    // enabled が false なら、組にせず各デバイスが勝手に電源を操作する
+    // state_dir は組の状態ファイルの置き場所 (普通は default_state_dir())
+    pub fn new(enabled: bool, state_dir: &Path) -> Self
+    {
+        Self { enabled, groups: Mutex::new(HashMap::new()), shared: SharedPowerState { dir: state_dir.to_path_buf() }, sibling_power_off: None }
+    }
+
+    // 他のプロセスが入れたまま手放した相方を、最後に切るときの方法
+    // 渡さなければ、その相方は挿し直すか、また誰かが開いて切るまで入ったまま
+    pub fn set_sibling_power_off<F: Fn(PowerMemberKey) -> bool + 'static>(&mut self, f: F)
+    {
+        self.sibling_power_off = Some(Box::new(f));
+    }
+
+    // 組に加える。連動しない (無効 / 対象外の機種 / シリアル番号が読めない / 同じ番号が既にいる) ときは None
+    pub fn register(&self, serial: &str, it930x: &'a IT930x<B>, profile: &'static DeviceProfile) -> Option<PowerMemberKey>
+    {
+        if !self.enabled || !profile.is_multi_device()
+        {
+            return None;
+        }
+
+        let (serial, dev_id) = parse_px4_serial(serial)?;
+
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.entry(serial).or_insert_with(|| PowerGroup { members: [None, None] });
+        if group.members[dev_id].is_some()
+        {
+            return None;
+        }
+
+        // 同じプロセスの相方が電源を入れていれば、こちらも入った状態になっている
+        let powered = group.members.iter().flatten().any(|m| m.powered);
+        group.members[dev_id] = Some(PowerMember { it930x, profile, powered });
+
+        Some(PowerMemberKey { serial, dev_id })
+    }
+
+    pub fn unregister(&self, key: PowerMemberKey)
+    {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some(group) = groups.get_mut(&key.serial)
+        {
+            group.members[key.dev_id] = None;
+            if group.members.iter().all(|m| m.is_none())
+            {
+                groups.remove(&key.serial);
+            }
+        }
+    }
+
+    // px4_mldev.c の px4_mldev_set_power 相当
+    // 入れるときはこのプロセスで開いている組のデバイスに全部入れ、
+    // 切るときは組の誰も (他のプロセスも) 要求していなくなってから、入れたまま手放された分も含めて切る
+    pub fn set_power(&self, key: PowerMemberKey, state: bool) -> Result<(), CtrlMsgError>
+    {
+        let mut groups = self.groups.lock().unwrap();
+        let group = match groups.get_mut(&key.serial)
+        {
+            Some(g) => g,
+            None => return Ok(()),
+        };
+
+        let me = (std::process::id(), key.dev_id);
+        let local: Vec<usize> = (0..MULTI_DEVICE_NUM).filter(|&id| group.members[id].is_some()).collect();
+        let shared = self.shared.update(key.serial, |s|
+        {
+            s.requests.retain(|e| *e != me);
+            if state
+            {
+                s.requests.push(me);
+                s.left_on.retain(|id| !local.contains(id));
+            }
+            else if !s.requests.is_empty()
+            {
+                // 相方のために切らずにおく分を書いておく (このプロセスが先に終わっても、最後の人が切る)
+                for (id, _) in group.members.iter().enumerate().filter(|(_, m)| m.as_ref().is_some_and(|m| m.powered))
+                {
+                    if !s.left_on.contains(&id)
+                    {
+                        s.left_on.push(id);
+                    }
+                }
+            }
+            else
+            {
+                // 自分の分はこの後で切る。他のプロセスが手放した分は、切れなければ残しておく
+                s.left_on.retain(|&dev_id| !local.contains(&dev_id) && !self.sibling_power_off.as_ref().is_some_and(|f| f(PowerMemberKey { serial: key.serial, dev_id })));
+            }
+        })?;
+
+        if state
+        {
+            for m in group.members.iter_mut().flatten().filter(|m| !m.powered)
+            {
+                apply_power(m.it930x, m.profile, true)?;
+                m.powered = true;
+            }
+        }
+        else if shared.requests.is_empty()
+        {
+            for m in group.members.iter_mut().flatten().filter(|m| m.powered)
+            {
+                apply_power(m.it930x, m.profile, false)?;
+                m.powered = false;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::Arc;
+    use crate::device_profile::{find_profile, PLEX_VID, PX_W3U4};
+    use crate::it930x_sim::SimBus;
+    use crate::px4_device::Px4Device;
+
+    // gpio2 (電源) の出力レジスタ
+    const GPIO2: u32 = 0xd8b7;
+
+    fn q3u4() -> &'static DeviceProfile
+    {
+        find_profile(PLEX_VID, 0x084a).unwrap()
+    }
+
+    // 電源の GPIO を出力にしてある IT930x
+    fn half() -> (SimBus, IT930x<SimBus>)
+    {
+        let sim = SimBus::new_booted();
+        let it930x = IT930x::new(sim.clone());
+        Px4Device::with_profile(&it930x, q3u4()).init_gpio().unwrap();
+        (sim, it930x)
+    }
+
+    fn state_dir(name: &str) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("px4_mldev_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn parses_px4_serial()
+    {
+        assert_eq!(parse_px4_serial("1234561"), Some((123456, 0)));
+        assert_eq!(parse_px4_serial(" 1234562\n"), Some((123456, 1)));
+        assert_eq!(parse_px4_serial("2"), Some((0, 1)));
+        assert_eq!(parse_px4_serial("1234560"), None);
+        assert_eq!(parse_px4_serial("1234563"), None);
+        assert_eq!(parse_px4_serial("12345a1"), None);
+        assert_eq!(parse_px4_serial(""), None);
+    }
+
+    #[test]
+    fn registers_only_paired_devices()
+    {
+        let dir = state_dir("register");
+        let (_sim, it930x) = half();
+
+        let disabled = PowerCoordinator::new(false, &dir);
+        assert!(disabled.register("1234561", &it930x, q3u4()).is_none());
+
+        let coordinator = PowerCoordinator::new(true, &dir);
+        assert!(coordinator.register("1234561", &it930x, &PX_W3U4).is_none());
+        assert!(coordinator.register("unknown", &it930x, q3u4()).is_none());
+
+        let key = coordinator.register("1234561", &it930x, q3u4()).unwrap();
+        assert_eq!(key, PowerMemberKey { serial: 123456, dev_id: 0 });
+        // 同じ番号は 2つ目を受け付けない。外せばまた登録できる
+        assert!(coordinator.register("1234561", &it930x, q3u4()).is_none());
+        coordinator.unregister(key);
+        assert!(coordinator.register("1234561", &it930x, q3u4()).is_some());
+    }
+
+    #[test]
+    fn keeps_power_while_the_sibling_wants_it()
+    {
+        let dir = state_dir("in_process");
+        let (sim1, it1) = half();
+        let (sim2, it2) = half();
+
+        let coordinator = PowerCoordinator::new(true, &dir);
+        let k1 = coordinator.register("1234561", &it1, q3u4()).unwrap();
+        let k2 = coordinator.register("1234562", &it2, q3u4()).unwrap();
+
+        // 片方が入れると、組の両方に入る
+        coordinator.set_power(k1, true).unwrap();
+        assert_eq!((sim1.reg(GPIO2), sim2.reg(GPIO2)), (1, 1));
+        coordinator.set_power(k2, true).unwrap();
+
+        // 相方がまだ使っているので切らない
+        coordinator.set_power(k1, false).unwrap();
+        assert_eq!((sim1.reg(GPIO2), sim2.reg(GPIO2)), (1, 1));
+
+        coordinator.set_power(k2, false).unwrap();
+        assert_eq!((sim1.reg(GPIO2), sim2.reg(GPIO2)), (0, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counts_requests_from_other_processes()
+    {
+        let dir = state_dir("cross_process");
+        let (sim, it930x) = half();
+
+        let coordinator = PowerCoordinator::new(true, &dir);
+        let key = coordinator.register("1234561", &it930x, q3u4()).unwrap();
+        let path = dir.join("mldev-123456.lock");
+
+        // 別のプロセス (ここでは pid 1) が相方の電源を要求している
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "1 1\n").unwrap();
+
+        coordinator.set_power(key, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("1 1\n{} 0\n", std::process::id()));
+
+        // 相方のために入れたままにした分を書いておく
+        coordinator.set_power(key, false).unwrap();
+        assert_eq!(sim.reg(GPIO2), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1 1\non 0\n");
+
+        // 要求したまま死んだプロセスの分は数えない
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        fs::write(&path, format!("{} 1\non 0\n", dead)).unwrap();
+
+        coordinator.set_power(key, true).unwrap();
+        coordinator.set_power(key, false).unwrap();
+        assert_eq!(sim.reg(GPIO2), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn last_member_powers_off_halves_left_on()
+    {
+        let dir = state_dir("left_on");
+        let (sim1, _it1) = half();
+        let (sim2, it2) = half();
+        let path = dir.join("mldev-123456.lock");
+
+        // 別のプロセスが相方 (dev_id 0) を入れたまま手放している
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "on 0\n").unwrap();
+        sim1.set_reg(GPIO2, 1);
+
+        let turned_off = Arc::new(Mutex::new(Vec::new()));
+        let mut coordinator = PowerCoordinator::new(true, &dir);
+        let (sim, log) = (sim1.clone(), Arc::clone(&turned_off));
+        coordinator.set_sibling_power_off(move |key|
+        {
+            log.lock().unwrap().push(key);
+            sim.set_reg(GPIO2, 0);
+            true
+        });
+
+        let key = coordinator.register("1234562", &it2, q3u4()).unwrap();
+        coordinator.set_power(key, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{} 1\non 0\n", std::process::id()));
+
+        // 最後に切るときに、手放された相方もまとめて切る
+        coordinator.set_power(key, false).unwrap();
+        assert_eq!((sim1.reg(GPIO2), sim2.reg(GPIO2)), (0, 0));
+        assert_eq!(*turned_off.lock().unwrap(), [PowerMemberKey { serial: 123456, dev_id: 0 }]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        // 切れなければ、次の人のために残しておく
+        fs::write(&path, "on 0\n").unwrap();
+        coordinator.set_sibling_power_off(|_| false);
+        coordinator.set_power(key, true).unwrap();
+        coordinator.set_power(key, false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "on 0\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_an_open_device_releases_power()
+    {
+        let dir = state_dir("drop");
+        let sim = SimBus::new_booted();
+        sim.attach_px4_w3u4();
+        let it930x = IT930x::new(sim.clone());
+
+        let coordinator = PowerCoordinator::new(true, &dir);
+        {
+            let mut px4 = Px4Device::with_profile(&it930x, q3u4());
+            assert!(px4.join_power_group(&coordinator, "1234561"));
+            px4.init_gpio().unwrap();
+            px4.open(2, 0).unwrap();
+            assert_eq!(sim.reg(GPIO2), 1);
+        }
+
+        // 閉じずに捨てても電源は切れ、組からも外れている
+        assert_eq!(sim.reg(GPIO2), 0);
+        assert_eq!(fs::read_to_string(dir.join("mldev-123456.lock")).unwrap(), "");
+        assert!(coordinator.register("1234561", &it930x, q3u4()).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,18 @@
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::itedtv_bus::BusOps;
+use crate::itedtv_bus::{BusError, BusOps};
 use crate::rt710::RT710;
-use crate::r850::R850;
+use crate::r850::{R850, R850Bandwidth, R850CalibrationCache, R850System, R850SystemConfig};
+use crate::tc90522::{cnr_from_cndat_s, cnr_from_cndat_t, is_valid_tsid, ErrorCounters, TmccInfo, TC90522, TC90522_LOCK_POLL_INTERVAL};
+use crate::ts_demux::{TsDemuxer, TS_PACKET_SIZE};
+use crate::channel::{Channel, TsidSelect};
 
-use crate::it930x::{CtrlMsgError, IT930x};
+use crate::it930x::{CtrlMsgError, GpioMode, IT930x};
+use crate::device_profile::{DemodKind, DeviceProfile, TunerKind};
+use crate::multi_device::{PowerCoordinator, PowerMemberKey};
 
 // エラー関連
 use thiserror::Error;
@@ -15,26 +23,91 @@ pub enum TunerError
     CtrlMsg(#[from] CtrlMsgError),  // CtrlMsgError をラップ
     #[error("R850 chip not detected.")]
     ChipNotDetected,
+    #[error("demodulator lock timeout.")]
+    LockTimeout,
+    #[error("tuner PLL lock timeout.")]
+    PllLockTimeout,
+    #[error("operation not supported for {0}.")]
+    UnsupportedSystem(System),
+    #[error("no TS in slot {0}. TSIDs in TMCC: {1:04x?}")]
+    TsidNotFound(u8, Vec<u16>),
+    #[error("TSID 0x{0:04x} was not selected.")]
+    TsidTimeout(u16),
+    #[error("R850 does not support {0:?} ({1:?}).")]
+    R850UnsupportedSystem(R850System, R850Bandwidth),
+    #[error("unsupported LNB voltage: {0}V.")]
+    UnsupportedLnbVoltage(u8),
+    #[error("no tuner at index {0}.")]
+    NoSuchChrdev(usize),
+    #[error("px4video{0} is already open.")]
+    Busy(usize),
+    #[error("px4video{0} is not open.")]
+    NotOpen(usize),
+    #[error("streaming error: {0:?}")]
+    Stream(BusError),
+    #[error("{0} has no LNB power supply.")]
+    NoLnbPower(&'static str),
+    #[error("{0} is not supported yet ({1:?} / {2:?}).")]
+    UnsupportedDevice(&'static str, DemodKind, TunerKind),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum System
 {
-    ISDB_S,
-    ISDB_T,
+    IsdbS,
+    IsdbT,
+}
+
+impl std::fmt::Display for System
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            System::IsdbS => write!(f, "ISDB-S"),
+            System::IsdbT => write!(f, "ISDB-T"),
+        }
+    }
 }
 
-// チューナーデバイスの必要なパラメータ
-// System, TC90522 bus の順
-// これは、W3U4 の場合だけ
-// S1UR とか Q3U4 のときは知らないが、Q3U4 は多分、これで良い。(これの外側で2つ持つイメージだと思う)
-const PX4_CHRDEV_CONFIGS: [(System, u8); 4] = [
-    (System::ISDB_S, 0x11),
-    (System::ISDB_S, 0x13),
-    (System::ISDB_T, 0x10),
-    (System::ISDB_T, 0x12),
-];
+// px4_device.c の px4_chrdev_set_channel で TMCC のロックを待つ時間 (10ms * 300回)
+const PX4_TMCC_LOCK_TIMEOUT: Duration = Duration::from_millis(3000);
+
+// px4_device.c の px4_chrdev_set_channel で RT710 の PLL ロックを待つ時間 (10ms * 50回)
+const PX4_RT710_PLL_LOCK_TIMEOUT: Duration = Duration::from_millis(500);
+
+// BS/CS110 の シンボルレート (ksps) と ロールオフ (0.35)
+const PX4_ISDB_S_SYMBOL_RATE: u32 = 28860;
+const PX4_ISDB_S_ROLLOFF: u32 = 4;
+
+// px4_device.c の px4_chrdev_set_channel で TSID を待つ時間 (10ms * 100回)
+const PX4_TSID_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// ISDB-T の同期待ち時間
+const PX4_T_LOCK_TIMEOUT: Duration = Duration::from_millis(1500);
+
+// px4_device.c の px4_chrdev_set_channel で R850 に渡す system (IF は 4.063MHz)
+const PX4_ISDB_T_R850_SYSTEM: R850SystemConfig = R850SystemConfig { system: R850System::IsdbT, bandwidth: R850Bandwidth::B6M, if_freq: 4063 };
+
+// px4_usb_params.c の .tsdev_max_packets = 2048 から
+// チャンネルごとのリングバッファの大きさ
+const PX4_CHRDEV_BUFFER_SIZE: usize = TS_PACKET_SIZE * 2048;
+
+// PX4 の LNB 電源は 15V だけ
+pub const PX4_LNB_VOLTAGE: u8 = 15;
 
+// 受信状態 (recpt1 の --signal で出しているもの + RF レベル)
+#[derive(Debug, Clone, Copy)]
+pub struct SignalStats
+{
+    pub locked: bool,
+    // dB
+    pub cnr: f64,
+    // dBm (チューナーのゲインからの目安)
+    pub rf_level: f64,
+    // rf_level が大まかな相対値でしかないか (R850)
+    pub rf_level_rough: bool,
+}
 
 pub enum Tuner<'a, B: BusOps>
 {
@@ -46,55 +119,665 @@ pub struct Px4Chrdev<'a, B: BusOps>
 {
     pub system: System,
 
-    // ここ3つは要らない気がする。
-    // → IT930x内部に記載して良さげ。
-    pub port_number: u8,
-    pub slave_number: u8,
-    pub sync_byte: u8,
-
-    //pub tc90522: &'a TC90522<'a, B>,
+    pub tc90522: TC90522<'a, B>,
     pub tuner: Tuner<'a, B>,
+
+    // 振り分けられた TS (C の chrdev の ringbuffer 相当)
+    // stream_enabled のときだけ溜める
+    stream_enabled: bool,
+    stream_buf: VecDeque<u8>,
+    stream_overflow: u64,
+
+    // このチャンネルが LNB 電源を要求しているか (C の chrdev4->lnb_power)
+    lnb_power: bool,
+
+    // Px4Device::open されているか
+    open: bool,
+}
+
+impl<'a, B: BusOps> Px4Chrdev<'a, B>
+{
+    // 選局して、BS / CS ならトランスポンダの中の TS も選ぶ
+    // 選んだ TSID を返す (地デジは None)
+    pub fn tune(&mut self, channel: &Channel) -> Result<Option<u16>, TunerError>
+    {
+        if channel.system != self.system
+        {
+            return Err(TunerError::UnsupportedSystem(channel.system));
+        }
+
+        match self.system
+        {
+            System::IsdbS => self.set_channel_s(channel.freq)?,
+            System::IsdbT => self.set_channel_t(channel.freq)?,
+        }
+
+        match channel.tsid
+        {
+            TsidSelect::None => Ok(None),
+            TsidSelect::Slot(slot) => Ok(Some(self.select_ts_slot(slot)?)),
+        }
+    }
+
+    // px4_device.c の px4_chrdev_open で、使う前に起こす処理
+    pub fn wakeup(&self) -> Result<(), TunerError>
+    {
+        match self.system
+        {
+            System::IsdbS => self.tc90522.sleep_s(false)?,
+            System::IsdbT => self.tc90522.sleep_t(false)?,
+        }
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_release で、使い終わったら寝かせる処理
+    // チューナー (rt710_sleep / r850_sleep) を寝かせてから復調部を寝かせる。
+    // チューナーが失敗しても復調部は寝かせて、最初のエラーを返す
+    pub fn sleep(&mut self) -> Result<(), TunerError>
+    {
+        let tuner = match &mut self.tuner
+        {
+            Tuner::RT710(t) => t.sleep(),
+            Tuner::R850(t) => t.sleep(),
+        };
+
+        let demod = match self.system
+        {
+            System::IsdbS => self.tc90522.sleep_s(true),
+            System::IsdbT => self.tc90522.sleep_t(true),
+        };
+
+        tuner?;
+        demod?;
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_set_channel の ISDB-S 部分 (チューナーの周波数設定より前)
+    // 選局中は AGC を止めておく
+    pub fn begin_tune_s(&self) -> Result<(), TunerError>
+    {
+        if !matches!(self.system, System::IsdbS)
+        {
+            return Err(TunerError::UnsupportedSystem(self.system));
+        }
+
+        self.tc90522.set_agc_s(false)?;
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_set_channel の ISDB-S 部分 (チューナーの周波数設定より後)
+    // AGC を戻して復調部をリセットし、TMCC が取れるまで待つ
+    pub fn finish_tune_s(&self) -> Result<(), TunerError>
+    {
+        if !matches!(self.system, System::IsdbS)
+        {
+            return Err(TunerError::UnsupportedSystem(self.system));
+        }
+
+        self.tc90522.set_agc_s(true)?;
+
+        if !self.tc90522.wait_lock_s(PX4_TMCC_LOCK_TIMEOUT)?
+        {
+            return Err(TunerError::LockTimeout);
+        }
+
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_set_channel の ISDB-S 部分
+    // freq は kHz
+    pub fn set_channel_s(&mut self, freq: u32) -> Result<(), TunerError>
+    {
+        self.begin_tune_s()?;
+
+        let rt710 = match &mut self.tuner
+        {
+            Tuner::RT710(t) => t,
+            Tuner::R850(_) => return Err(TunerError::UnsupportedSystem(self.system)),
+        };
+
+        rt710.set_params(freq, PX4_ISDB_S_SYMBOL_RATE, PX4_ISDB_S_ROLLOFF)?;
+
+        if !rt710.wait_pll_lock(PX4_RT710_PLL_LOCK_TIMEOUT)?
+        {
+            return Err(TunerError::PllLockTimeout);
+        }
+
+        self.finish_tune_s()
+    }
+
+    // px4_device.c の px4_chrdev_set_channel の TSID 選択部分
+    // 1つのトランスポンダに複数の TS が載っているので、TMCC の一覧の slot 番目を選ぶ (BS15_0 と BS15_1 の違い)
+    // 一覧は TMCC が取れてから少し遅れて埋まるので、有効な値になるまで待つ
+    pub fn select_ts_slot(&self, slot: u8) -> Result<u16, TunerError>
+    {
+        if !matches!(self.system, System::IsdbS)
+        {
+            return Err(TunerError::UnsupportedSystem(self.system));
+        }
+
+        let start = Instant::now();
+        let tsid = loop
+        {
+            let tsid = self.tc90522.tmcc_get_tsid_s(slot as usize)?;
+            if is_valid_tsid(tsid)
+            {
+                break tsid;
+            }
+
+            if start.elapsed() >= PX4_TSID_TIMEOUT
+            {
+                let list = self.tc90522.tmcc_tsid_list_s()?;
+                return Err(TunerError::TsidNotFound(slot, list.into_iter().filter(|t| is_valid_tsid(*t)).collect()));
+            }
+
+            thread::sleep(TC90522_LOCK_POLL_INTERVAL);
+        };
+
+        self.select_tsid(tsid)?;
+        Ok(tsid)
+    }
+
+    // TSID を直接指定して選び、出力が切り替わるまで待つ
+    pub fn select_tsid(&self, tsid: u16) -> Result<(), TunerError>
+    {
+        if !matches!(self.system, System::IsdbS)
+        {
+            return Err(TunerError::UnsupportedSystem(self.system));
+        }
+
+        self.tc90522.set_tsid_s(tsid)?;
+
+        let start = Instant::now();
+        while self.tc90522.get_tsid_s()? != tsid
+        {
+            if start.elapsed() >= PX4_TSID_TIMEOUT
+            {
+                return Err(TunerError::TsidTimeout(tsid));
+            }
+
+            thread::sleep(TC90522_LOCK_POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_set_channel の ISDB-T 部分 (チューナーの周波数設定より前)
+    pub fn begin_tune_t(&self) -> Result<(), TunerError>
+    {
+        if !matches!(self.system, System::IsdbT)
+        {
+            return Err(TunerError::UnsupportedSystem(self.system));
+        }
+
+        self.tc90522.set_agc_t(false)?;
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_set_channel の ISDB-T 部分 (チューナーの周波数設定より後)
+    // AGC を戻して復調部をリセットし、同期が取れるまで待つ
+    pub fn finish_tune_t(&self) -> Result<(), TunerError>
+    {
+        if !matches!(self.system, System::IsdbT)
+        {
+            return Err(TunerError::UnsupportedSystem(self.system));
+        }
+
+        self.tc90522.set_agc_t(true)?;
+
+        if !self.tc90522.wait_lock_t(PX4_T_LOCK_TIMEOUT)?
+        {
+            return Err(TunerError::LockTimeout);
+        }
+
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_set_channel の ISDB-T 部分
+    // freq は kHz
+    pub fn set_channel_t(&mut self, freq: u32) -> Result<(), TunerError>
+    {
+        self.begin_tune_t()?;
+
+        let r850 = match &mut self.tuner
+        {
+            Tuner::R850(t) => t,
+            Tuner::RT710(_) => return Err(TunerError::UnsupportedSystem(self.system)),
+        };
+
+        r850.set_system(&PX4_ISDB_T_R850_SYSTEM)?;
+        r850.set_frequency(freq)?;
+
+        self.finish_tune_t()
+    }
+
+    // TS の受け取りを始める / やめる
+    // どちらでも、溜まっていた分は捨てる
+    pub fn enable_stream(&mut self, enable: bool)
+    {
+        self.stream_enabled = enable;
+        self.stream_buf.clear();
+        self.stream_overflow = 0;
+    }
+
+    pub fn is_stream_enabled(&self) -> bool
+    {
+        self.stream_enabled
+    }
+
+    // Px4Device から振り分けられたパケットを溜める
+    // バッファが一杯なら、C と同じく新しい方を捨てる
+    fn write_stream(&mut self, packet: &[u8])
+    {
+        if !self.stream_enabled
+        {
+            return;
+        }
+
+        if self.stream_buf.len() + packet.len() > PX4_CHRDEV_BUFFER_SIZE
+        {
+            self.stream_overflow += 1;
+            return;
+        }
+
+        self.stream_buf.extend(packet);
+    }
+
+    // 溜まっている TS を読み出す (読めたバイト数を返す)
+    pub fn read_stream(&mut self, buf: &mut [u8]) -> usize
+    {
+        let len = buf.len().min(self.stream_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.stream_buf.drain(..len))
+        {
+            *dst = src;
+        }
+        len
+    }
+
+    // バッファが一杯で捨てたパケット数
+    pub fn stream_overflow(&self) -> u64
+    {
+        self.stream_overflow
+    }
+
+    // px4_device.c の px4_chrdev_get_cnr 相当
+    pub fn get_cnr(&self) -> Result<f64, TunerError>
+    {
+        let cnr = match self.system
+        {
+            System::IsdbS => cnr_from_cndat_s(self.tc90522.get_cndat_s()?),
+            System::IsdbT => cnr_from_cndat_t(self.tc90522.get_cndat_t()?),
+        };
+        Ok(cnr)
+    }
+
+    // チューナーのゲインから見た RF レベルの目安
+    pub fn get_rf_level(&self) -> Result<f64, TunerError>
+    {
+        let level = match &self.tuner
+        {
+            Tuner::RT710(t) => t.get_rf_level()?,
+            Tuner::R850(t) => t.get_rf_level()?,
+        };
+        Ok(level)
+    }
+
+    pub fn signal_stats(&self) -> Result<SignalStats, TunerError>
+    {
+        let rf_level_rough = matches!(self.tuner, Tuner::R850(_));
+        Ok(SignalStats { locked: self.is_locked()?, cnr: self.get_cnr()?, rf_level: self.get_rf_level()?, rf_level_rough })
+    }
+
+    // BER / パケットエラーを何パケットごとに測るか
+    pub fn set_ber_period(&self, packets: u16) -> Result<(), TunerError>
+    {
+        match self.system
+        {
+            System::IsdbS => self.tc90522.set_ber_period_s(packets)?,
+            System::IsdbT => self.tc90522.set_ber_period_t(packets)?,
+        }
+        Ok(())
+    }
+
+    pub fn reset_error_counters(&self) -> Result<(), TunerError>
+    {
+        match self.system
+        {
+            System::IsdbS => self.tc90522.reset_ber_s()?,
+            System::IsdbT => self.tc90522.reset_ber_t()?,
+        }
+        Ok(())
+    }
+
+    // 直近の測定期間の BER / パケットエラー
+    // ISDB-T は A/B/C 階層を合わせたもの (階層ごとに見たければ tc90522.get_error_counters_t)
+    pub fn error_counters(&self) -> Result<ErrorCounters, TunerError>
+    {
+        let counters = match self.system
+        {
+            System::IsdbS => self.tc90522.get_error_counters_s()?,
+            System::IsdbT => self.tc90522.get_error_counters_t()?.iter().fold(ErrorCounters::default(), |acc, c| acc.merge(c)),
+        };
+        Ok(counters)
+    }
+
+    // ISDB-T の TMCC (階層ごとの変調方式など、システム識別、緊急警報放送フラグ)
+    // ロックしていなければ LockTimeout
+    pub fn tmcc_info_t(&self) -> Result<TmccInfo, TunerError>
+    {
+        if !matches!(self.system, System::IsdbT)
+        {
+            return Err(TunerError::UnsupportedSystem(self.system));
+        }
+
+        if !self.tc90522.is_signal_locked_t()?
+        {
+            return Err(TunerError::LockTimeout);
+        }
+
+        Ok(self.tc90522.get_tmcc_t()?)
+    }
+
+    // 今ロックしているか
+    pub fn is_locked(&self) -> Result<bool, TunerError>
+    {
+        let locked = match self.system
+        {
+            System::IsdbS => self.tc90522.is_signal_locked_s()?,
+            System::IsdbT => self.tc90522.is_signal_locked_t()?,
+        };
+        Ok(locked)
+    }
+}
+
+// 電源の GPIO を機種の手順どおりに操作する (px4_device.c の px4_backend_set_power 相当)
+// PX4 なら gpio7 = low (80ms 待つ) → gpio2 = high (20ms 待つ)
+pub fn apply_power<B: BusOps>(it930x: &IT930x<B>, profile: &DeviceProfile, state: bool) -> Result<(), CtrlMsgError>
+{
+    if state
+    {
+        for step in profile.power_on
+        {
+            it930x.write_gpio(step.gpio, step.high)?;
+            std::thread::sleep(step.wait);
+        }
+    }
+    else
+    {
+        // off は失敗しても無視
+        for step in profile.power_off
+        {
+            let _ = it930x.write_gpio(step.gpio, step.high);
+            std::thread::sleep(step.wait);
+        }
+    }
+
+    Ok(())
 }
 
 pub struct Px4Device<'a, B: BusOps>
 {
     it930x: &'a IT930x<B>,
+    // 機種ごとの構成 (IT930x に渡した IT930xConfig と同じものから作ること)
+    profile: &'static DeviceProfile,
     px4chrdev: Vec<Px4Chrdev<'a, B>>,
+    demux: TsDemuxer,
+
+    // LNB 電源を要求しているチャンネルの数 (C の px4->lnb_power_count)
+    lnb_power_count: u32,
+
+    // Q3U4 などで、相方と電源を連動させるとき (C の px4->mldev)
+    power_group: Option<(&'a PowerCoordinator<'a, B>, PowerMemberKey)>,
+
+    // 開いているチャンネルの数と、TS を受け取っているチャンネルの数 (C の px4->open_count / px4->streaming_count)
+    open_count: u32,
+    streaming_count: u32,
+
+    // 最初に開いたときに R850 を校正する (キャッシュの置き場所, シリアル番号)
+    r850_cache: Option<(PathBuf, String)>,
 }
 
 impl<'a, B: BusOps> Px4Device<'a, B>
 {
+    // PX-W3U4 として扱う
+    #[cfg(test)]
     pub fn new(it930x: &'a IT930x<B>) -> Self
+    {
+        Self::with_profile(it930x, &crate::device_profile::PX_W3U4)
+    }
+
+    pub fn with_profile(it930x: &'a IT930x<B>, profile: &'static DeviceProfile) -> Self
     {
         Self 
         {
-            it930x: it930x,
+            it930x,
+            profile,
             px4chrdev: Vec::new(),
+            demux: TsDemuxer::new(&profile.inputs.iter().map(|i| i.sync_byte).collect::<Vec<_>>()),
+            lnb_power_count: 0,
+            power_group: None,
+            open_count: 0,
+            streaming_count: 0,
+            r850_cache: None,
+        }
+    }
+
+    // R850 の校正キャッシュ。設定しておくと、電源を入れるたびにキャッシュから校正値を戻す
+    pub fn set_r850_calibration_cache(&mut self, cache_dir: &Path, serial: &str)
+    {
+        self.r850_cache = Some((cache_dir.to_path_buf(), serial.to_string()));
+    }
+
+    // px4_device.c の px4_chrdev_open 相当
+    // 最初の1つを開くときに電源を入れて初期化し、開いたチャンネルを起こす。
+    // lnb_voltage は衛星のチャンネルの LNB 電源 (0 なら使わない)
+    pub fn open(&mut self, index: usize, lnb_voltage: u8) -> Result<(), TunerError>
+    {
+        if index >= self.profile.inputs.len()
+        {
+            return Err(TunerError::NoSuchChrdev(index));
+        }
+        if self.px4chrdev.get(index).map(|c| c.open).unwrap_or(false)
+        {
+            return Err(TunerError::Busy(index));
+        }
+
+        if self.open_count == 0
+        {
+            self.set_power(true)?;
+            let result = self.init_after_power_on();
+            if let Err(e) = result
+            {
+                let _ = self.set_power(false);
+                return Err(e);
+            }
+        }
+        self.open_count += 1;
+
+        let result = self.open_chrdev(index, lnb_voltage);
+        if result.is_err()
+        {
+            self.open_count -= 1;
+            if self.open_count == 0
+            {
+                let _ = self.set_power(false);
+            }
+        }
+        result
+    }
+
+    fn init_after_power_on(&mut self) -> Result<(), TunerError>
+    {
+        self.init()?;
+
+        if let Some((dir, serial)) = self.r850_cache.clone()
+        {
+            self.calibrate_r850(&dir, &serial)?;
+        }
+        Ok(())
+    }
+
+    fn open_chrdev(&mut self, index: usize, lnb_voltage: u8) -> Result<(), TunerError>
+    {
+        self.px4chrdev[index].wakeup()?;
+
+        if lnb_voltage != 0
+        {
+            if let Err(e) = self.set_lnb_voltage(index, lnb_voltage)
+            {
+                let _ = self.px4chrdev[index].sleep();
+                return Err(e);
+            }
+        }
+
+        self.px4chrdev[index].open = true;
+        Ok(())
+    }
+
+    // 開いているチャンネルを選局する。選んだ TSID を返す (地デジは None)
+    pub fn tune(&mut self, index: usize, channel: &Channel) -> Result<Option<u16>, TunerError>
+    {
+        self.opened_chrdev_mut(index)?.tune(channel)
+    }
+
+    // TS の受け取りを始める。最初の1つのときに bulk 転送を始める
+    pub fn start_capture(&mut self, index: usize) -> Result<(), TunerError>
+    {
+        let chrdev = self.opened_chrdev_mut(index)?;
+        if chrdev.is_stream_enabled()
+        {
+            return Ok(());
+        }
+
+        if self.streaming_count == 0
+        {
+            // 前回の転送の残りが混ざらないようにする
+            self.demux.reset();
+            self.it930x.bus().start_streaming().map_err(TunerError::Stream)?;
+        }
+        self.streaming_count += 1;
+        self.px4chrdev[index].enable_stream(true);
+
+        Ok(())
+    }
+
+    // 最後の1つが止めたら bulk 転送も止める
+    pub fn stop_capture(&mut self, index: usize) -> Result<(), TunerError>
+    {
+        let chrdev = self.opened_chrdev_mut(index)?;
+        if !chrdev.is_stream_enabled()
+        {
+            return Ok(());
+        }
+
+        chrdev.enable_stream(false);
+        self.streaming_count -= 1;
+        if self.streaming_count == 0
+        {
+            self.it930x.bus().stop_streaming().map_err(TunerError::Stream)?;
+        }
+
+        Ok(())
+    }
+
+    // px4_device.c の px4_chrdev_release 相当
+    // 閉じるチャンネルは寝かせ、最後の1つなら電源も切る (後片付けなので失敗しても最後までやる)
+    pub fn close(&mut self, index: usize) -> Result<(), TunerError>
+    {
+        self.opened_chrdev_mut(index)?;
+
+        let _ = self.stop_capture(index);
+        if self.px4chrdev[index].system == System::IsdbS && self.profile.lnb_gpio.is_some()
+        {
+            let _ = self.set_lnb_voltage(index, 0);
+        }
+
+        let result = self.px4chrdev[index].sleep();
+        self.px4chrdev[index].open = false;
+
+        self.open_count -= 1;
+        if self.open_count == 0
+        {
+            self.set_power(false)?;
+        }
+
+        result
+    }
+
+    #[cfg(test)]
+    pub fn open_count(&self) -> u32
+    {
+        self.open_count
+    }
+
+    fn opened_chrdev_mut(&mut self, index: usize) -> Result<&mut Px4Chrdev<'a, B>, TunerError>
+    {
+        match self.px4chrdev.get_mut(index)
+        {
+            Some(c) if c.open => Ok(c),
+            Some(_) => Err(TunerError::NotOpen(index)),
+            None => Err(TunerError::NoSuchChrdev(index)),
+        }
+    }
+
+    // 相方のデバイスと電源を連動させる (連動しない機種や、シリアル番号が読めないときは false)
+    pub fn join_power_group(&mut self, coordinator: &'a PowerCoordinator<'a, B>, serial: &str) -> bool
+    {
+        self.leave_power_group();
+        self.power_group = coordinator.register(serial, self.it930x, self.profile).map(|key| (coordinator, key));
+        self.power_group.is_some()
+    }
+
+    pub fn leave_power_group(&mut self)
+    {
+        if let Some((coordinator, key)) = self.power_group.take()
+        {
+            coordinator.unregister(key);
         }
     }
 
     pub fn set_power(&mut self, state: bool) -> Result<(), CtrlMsgError>
     {
-        println!(
-            "[px4] backend_set_power: {}",
-            if state { "true" } else { "false" }
-        );
+        if !state
+        {
+            // 電源を落とすので、LNB の要求も全部無かったことにする
+            if let (Some(gpio), true) = (self.profile.lnb_gpio, self.lnb_power_count != 0)
+            {
+                let _ = self.it930x.write_gpio(gpio, false);
+            }
+            self.lnb_power_count = 0;
+            for chrdev in &mut self.px4chrdev
+            {
+                chrdev.lnb_power = false;
+            }
+        }
 
-        if state
+        match self.power_group
         {
-            // gpio7 = low
-            self.it930x.write_gpio(7, false)?;
-            std::thread::sleep(Duration::from_millis(80));
+            Some((coordinator, key)) => coordinator.set_power(key, state),
+            None => apply_power(self.it930x, self.profile, state),
+        }
+    }
+
+    pub fn profile(&self) -> &'static DeviceProfile
+    {
+        self.profile
+    }
 
-            // gpio2 = high
-            self.it930x.write_gpio(2, true)?;
-            std::thread::sleep(Duration::from_millis(20));
+    // 電源や LNB の GPIO を出力にして、切った状態にしておく (set_power より前に呼ぶ)
+    pub fn init_gpio(&self) -> Result<(), CtrlMsgError>
+    {
+        for &(gpio, high) in self.profile.gpio_init
+        {
+            self.it930x.set_gpio_mode(gpio, GpioMode::Out, true)?;
+            self.it930x.write_gpio(gpio, high)?;
         }
-        else
+
+        // px4_device.c の px4_device_init で LNB 電源の GPIO を出力にして切っておく
+        if let Some(gpio) = self.profile.lnb_gpio
         {
-            // off は失敗しても無視
-            let _ = self.it930x.write_gpio(2, false);
-            let _ = self.it930x.write_gpio(7, true);            
+            self.it930x.set_gpio_mode(gpio, GpioMode::Out, true)?;
+            self.it930x.write_gpio(gpio, false)?;
         }
 
         Ok(())
@@ -102,7 +785,16 @@ impl<'a, B: BusOps> Px4Device<'a, B>
 
     pub fn init(&mut self) -> Result<(), TunerError>
     {
-        for (i, (system, addr)) in PX4_CHRDEV_CONFIGS.iter().enumerate()
+        self.lnb_power_count = 0;
+
+        // 今あるのは TC90522 + RT710 / R850 だけ
+        if let Some(input) = self.profile.inputs.iter().find(|i| i.demod != DemodKind::Tc90522 || i.tuner == TunerKind::Cxd2858er)
+        {
+            return Err(TunerError::UnsupportedDevice(self.profile.name, input.demod, input.tuner));
+        }
+
+        self.px4chrdev.clear();
+        for input in self.profile.inputs
         {
             // px4_device.c 1128 行目に chrdev4->tc90522.i2c = &it930x->i2c_master[1]; とあり
             // it930x.c の 571 行目で、priv->i2c[i].bus = i + 1; で、
@@ -112,38 +804,497 @@ impl<'a, B: BusOps> Px4Device<'a, B>
             //  -> pxmlt device の場合は、&it930x->i2c_master[input->i2c_bus - 1]; みたいになってる。
             //  -> s1ur や m1ur は [2] なので bus 番号は 3 らしい。
             // あと、CHRDEV ごとにアドレスが違くて、0x10〜0x13。
-            //let tc90522 = TC90522::new(&it930x, 2, *addr);
-            //tc90522s.push(tc90522);
+            // → バスとアドレスは device_profile.rs の表から
+            let tc90522 = TC90522::new(self.it930x, input.i2c_bus, input.i2c_addr);
         
-            let tuner = match system
+            let tuner = match input.tuner
             {
-                System::ISDB_S => Tuner::RT710(RT710::new(&self.it930x, 2, *addr)),
-                System::ISDB_T => Tuner::R850(R850::new(&self.it930x, 2, *addr)),
+                TunerKind::Rt710 => Tuner::RT710(RT710::new(self.it930x, input.i2c_bus, input.i2c_addr)),
+                _ => Tuner::R850(R850::new(self.it930x, input.i2c_bus, input.i2c_addr)),
             };
 
             self.px4chrdev.push(
                 Px4Chrdev
                 {
-                    system: *system,
-                    port_number: i as u8 + 1,
-                    slave_number: i as u8,
-                    sync_byte: ((i as u8 + 1) << 4) | 0x07,
-                    tuner: tuner,
+                    system: input.systems[0],
+                    tc90522,
+                    tuner,
+                    stream_enabled: false,
+                    stream_buf: VecDeque::new(),
+                    stream_overflow: 0,
+                    lnb_power: false,
+                    open: false,
                 }
             );
         }
 
         for chrdev in &mut self.px4chrdev
         {
-            //chrdev.tc90522.init();
-
-            let result = match &mut chrdev.tuner
+            match &mut chrdev.tuner
             {
                 Tuner::RT710(t) => t.init()?,
                 Tuner::R850(t) => t.init()?,
-            };
+            }
+
+            // px4_device.c の px4_chrdev_open の tc_init_s / tc_init_t
+            // 使うまでは寝かせておく
+            match chrdev.system
+            {
+                System::IsdbS => chrdev.tc90522.init_s()?,
+                System::IsdbT => chrdev.tc90522.init_t()?,
+            }
+            chrdev.sleep()?;
+        }
+        Ok(())
+    }
+
+    pub fn chrdev(&self, index: usize) -> Option<&Px4Chrdev<'a, B>>
+    {
+        self.px4chrdev.get(index)
+    }
+
+    pub fn chrdev_mut(&mut self, index: usize) -> Option<&mut Px4Chrdev<'a, B>>
+    {
+        self.px4chrdev.get_mut(index)
+    }
+
+    // px4_device.c の px4_chrdev_set_lnb_voltage の移植
+    // voltage が 0 なら、このチャンネルの要求を取り下げる。
+    // 要求しているチャンネルが 1つでもあれば LNB 電源を入れ、最後の1つが取り下げたら切る。
+    pub fn set_lnb_voltage(&mut self, index: usize, voltage: u8) -> Result<(), TunerError>
+    {
+        let chrdev = self.px4chrdev.get(index).ok_or(TunerError::NoSuchChrdev(index))?;
+        let gpio = self.profile.lnb_gpio.ok_or(TunerError::NoLnbPower(self.profile.name))?;
+        if chrdev.system != System::IsdbS
+        {
+            return Err(TunerError::UnsupportedSystem(chrdev.system));
+        }
+
+        if voltage == 0
+        {
+            if !chrdev.lnb_power
+            {
+                return Ok(());
+            }
+
+            if self.lnb_power_count == 1
+            {
+                self.it930x.write_gpio(gpio, false)?;
+            }
+            self.lnb_power_count -= 1;
+            self.px4chrdev[index].lnb_power = false;
+
+            return Ok(());
+        }
+
+        if voltage != PX4_LNB_VOLTAGE
+        {
+            return Err(TunerError::UnsupportedLnbVoltage(voltage));
+        }
+
+        if chrdev.lnb_power
+        {
+            return Ok(());
+        }
+
+        if self.lnb_power_count == 0
+        {
+            self.it930x.write_gpio(gpio, true)?;
+        }
+        self.lnb_power_count += 1;
+        self.px4chrdev[index].lnb_power = true;
+
+        Ok(())
+    }
+
+    // bulk で受け取った TS を、同期バイトを見て各 chrdev に振り分ける
+    pub fn dispatch_stream(&mut self, data: &[u8])
+    {
+        let chrdevs = &mut self.px4chrdev;
+        self.demux.push(data, |index, packet|
+        {
+            // demux も chrdev も profile.inputs の順に並んでいる
+            if let Some(chrdev) = chrdevs.get_mut(index)
+            {
+                chrdev.write_stream(packet);
+            }
+        });
+    }
+
+    // バスから1回分受信して振り分ける
+    // buf は受信用の作業領域 (xfer_size 程度あれば良い)
+    pub fn pump_stream(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, BusError>
+    {
+        let len = self.it930x.bus().stream_rx(buf, timeout)?;
+        self.dispatch_stream(&buf[..len]);
+        Ok(len)
+    }
+
+    pub fn demux(&self) -> &TsDemuxer
+    {
+        &self.demux
+    }
+
+    // R850 の IMR / LPF 校正
+    // 校正は時間がかかるので、結果はデバイスのシリアル番号ごとに cache_dir に保存しておき、次からはそれを使う。
+    // init の後に呼ぶこと。
+    pub fn calibrate_r850(&mut self, cache_dir: &Path, serial: &str) -> Result<(), TunerError>
+    {
+        let path = R850CalibrationCache::path_for(cache_dir, serial);
+        let mut cache = match R850CalibrationCache::load(&path)
+        {
+            Ok(cache) if cache.serial == serial => cache,
+            Ok(_) | Err(_) => R850CalibrationCache::new(serial),
+        };
+
+        let mut updated = false;
+        for chrdev in &mut self.px4chrdev
+        {
+            let addr = chrdev.tc90522.i2c_addr;
+            let Tuner::R850(t) = &mut chrdev.tuner else { continue; };
+
+            if let Some(cal) = cache.entries.get(&addr)
+            {
+                if cal.imr_cal.iter().all(|c| c.done) || t.config.no_imr_calibration
+                {
+                    t.set_calibration(cal);
+                    continue;
+                }
+            }
+
+            t.calibrate_imr()?;
+            for bw in [R850Bandwidth::B6M, R850Bandwidth::B7M, R850Bandwidth::B8M]
+            {
+                t.calibrate_lpf(bw)?;
+            }
+
+            cache.entries.insert(addr, t.calibration());
+            updated = true;
+        }
+
+        // キャッシュが保存できなくても、校正自体はできている (次に電源を入れたときにまた校正するだけ)
+        if updated
+        {
+            let _ = cache.save(&path);
         }
+
         Ok(())
     }
+}
+
+// 閉じずに (エラーの途中などで) 捨てられても、電源を入れたままにしたり、組に登録したままにしたりしない
+impl<B: BusOps> Drop for Px4Device<'_, B>
+{
+    fn drop(&mut self)
+    {
+        if self.open_count != 0
+        {
+            let _ = self.set_power(false);
+        }
+        self.leave_power_group();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::channel::parse_channel;
+    use crate::it930x::I2CRequestType;
+    use crate::it930x_sim::{SimBus, SimTc90522, SimTuner, SimTunerType};
+
+    // PX-W3U4 の構成 (衛星 0x11 / 0x13、地上 0x10 / 0x12) の SimBus
+    fn w3u4() -> (SimBus, Vec<SimTc90522>, IT930x<SimBus>)
+    {
+        let sim = SimBus::new_booted();
+        let demods = sim.attach_px4_w3u4();
+        let it930x = IT930x::new(sim.clone());
+        (sim, demods, it930x)
+    }
+
+    fn init_device<B: BusOps>(px4: &mut Px4Device<'_, B>) -> Result<(), TunerError>
+    {
+        px4.init_gpio()?;
+        px4.init()
+    }
+
+    #[test]
+    fn init_detects_tuners_through_tc90522_gate()
+    {
+        let (sim, demods, it930x) = w3u4();
+        let mut px4 = Px4Device::new(&it930x);
+        init_device(&mut px4).unwrap();
+
+        assert!(matches!(px4.chrdev(0).unwrap().tuner, Tuner::RT710(_)));
+        assert!(matches!(px4.chrdev(1).unwrap().tuner, Tuner::RT710(_)));
+        assert!(matches!(px4.chrdev(2).unwrap().tuner, Tuner::R850(_)));
+        assert!(matches!(px4.chrdev(3).unwrap().tuner, Tuner::R850(_)));
+
+        // チューナーへのアクセスは、全部 TC90522 の 0xfe を通っている
+        let log = sim.i2c_log();
+        assert!(log.iter().all(|t| t.bus == 2 && (0x10..=0x13).contains(&t.addr)));
+        for addr in 0x10..=0x13u8
+        {
+            let tuner_addr = if addr & 0x01 != 0 { 0x7a } else { 0x7c };
+            assert!(log.iter().any(|t| t.addr == addr && t.req == I2CRequestType::Write && t.data[..2] == [0xfe, tuner_addr << 1]));
+            assert!(log.iter().any(|t| t.addr == addr && t.req == I2CRequestType::Write && t.data[..] == [0xfe, (tuner_addr << 1) | 0x01]));
+        }
+
+        // 復調部は初期化して寝かせてある
+        for demod in &demods[..2]
+        {
+            assert_eq!(demod.reg(0x04), 0x02);
+            assert_eq!(demod.reg(0x17), 0x01);
+        }
+        for demod in &demods[2..]
+        {
+            assert_eq!(demod.reg(0xb0), 0xa0);
+            assert_eq!(demod.reg(0x03), 0x90);
+        }
+
+        // R850 は xtal power を決めてから寝かせてある
+        let r850 = demods[2].tuner().unwrap();
+        assert_eq!(r850.reg(0x09), 0xfe);
+        assert_eq!(r850.reg(0x1e) & 0x18, 0x18);
+    }
+
+    #[test]
+    fn init_fails_without_r850()
+    {
+        let (_sim, demods, it930x) = w3u4();
+        demods[3].tuner().unwrap().set_reg(0x00, 0x00);
+
+        let mut px4 = Px4Device::new(&it930x);
+        assert!(matches!(init_device(&mut px4), Err(TunerError::ChipNotDetected)));
+    }
+
+    #[test]
+    fn tune_isdb_s_with_rt710()
+    {
+        let (_sim, demods, it930x) = w3u4();
+        let mut px4 = Px4Device::new(&it930x);
+        px4.init_gpio().unwrap();
+        px4.open(0, 0).unwrap();
+        assert_eq!(demods[0].reg(0x17), 0x00);
+
+        // TMCC の TSID 一覧と、選ばれた後に出てくる TSID
+        let demod = &demods[0];
+        for (i, v) in [0x40, 0x10, 0x40, 0x11].iter().enumerate()
+        {
+            demod.set_reg(0xce + i as u8, *v);
+        }
+        demod.set_reg(0xe6, 0x40);
+        demod.set_reg(0xe7, 0x11);
 
-}
\ No newline at end of file
+        assert_eq!(px4.tune(0, &parse_channel("BS01_1").unwrap()).unwrap(), Some(0x4011));
+
+        // チップ ID (0x70 をビット反転で読む) から RT710 の初期値が使われている
+        let rt710 = demod.tuner().unwrap();
+        assert_eq!(rt710.tuner_type(), SimTunerType::RT710);
+        assert_eq!(rt710.reg(0x00), 0x40);
+
+        // AGC を戻して TSID を選んでいる
+        assert_eq!(demod.reg(0x10), 0xb2);
+        assert_eq!((demod.reg(0x8f), demod.reg(0x90)), (0x40, 0x11));
+
+        assert!(px4.chrdev(0).unwrap().is_locked().unwrap());
+        demod.set_reg(0xc3, 0x10);
+        assert!(!px4.chrdev(0).unwrap().is_locked().unwrap());
+
+        // 地上のチャンネルは受けられない
+        assert!(matches!(px4.tune(0, &parse_channel("27").unwrap()), Err(TunerError::UnsupportedSystem(System::IsdbT))));
+
+        px4.close(0).unwrap();
+    }
+
+    #[test]
+    fn tune_isdb_s_empty_slot()
+    {
+        let (_sim, demods, it930x) = w3u4();
+        let mut px4 = Px4Device::new(&it930x);
+        px4.init_gpio().unwrap();
+        px4.open(0, 0).unwrap();
+
+        // slot 0 にしか TS が無い
+        demods[0].set_reg(0xce, 0x40);
+        demods[0].set_reg(0xcf, 0x10);
+
+        match px4.tune(0, &parse_channel("BS01_2").unwrap())
+        {
+            Err(TunerError::TsidNotFound(2, list)) => assert_eq!(list, [0x4010]),
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tune_isdb_s_pll_lock_timeout()
+    {
+        let (_sim, demods, it930x) = w3u4();
+        let mut px4 = Px4Device::new(&it930x);
+        px4.init_gpio().unwrap();
+        px4.open(1, 0).unwrap();
+
+        demods[1].tuner().unwrap().set_pll_locked(false);
+        assert!(matches!(px4.tune(1, &parse_channel("CS2").unwrap()), Err(TunerError::PllLockTimeout)));
+    }
+
+    #[test]
+    fn tune_isdb_t_with_r850()
+    {
+        let (_sim, demods, it930x) = w3u4();
+        let mut px4 = Px4Device::new(&it930x);
+        px4.init_gpio().unwrap();
+        px4.open(2, 0).unwrap();
+
+        let demod = &demods[2];
+        assert_eq!(demod.reg(0x03), 0x80);
+
+        // 同期シーケンスが 8 以上ならロック
+        demod.set_reg(0xb0, 0xa8);
+        assert_eq!(px4.tune(2, &parse_channel("27").unwrap()).unwrap(), None);
+
+        // 起きていて、校正が無いので LPF は既定のコード
+        let r850 = demod.tuner().unwrap();
+        assert_eq!(r850.reg(0x12), 0xba);
+        assert_eq!(r850.reg(0x09), 0xc0);
+        assert_eq!(demod.reg(0x23), 0x4c);
+
+        assert!(px4.chrdev(2).unwrap().is_locked().unwrap());
+        // R850 の RF レベルは大まかな相対値
+        assert!(px4.chrdev(2).unwrap().signal_stats().unwrap().rf_level_rough);
+        demod.set_reg(0x80, 0x08);
+        assert!(!px4.chrdev(2).unwrap().is_locked().unwrap());
+
+        px4.close(2).unwrap();
+    }
+
+    #[test]
+    fn open_survives_r850_calibration_without_pll_lock()
+    {
+        let (_sim, demods, it930x) = w3u4();
+        for demod in &demods[2..]
+        {
+            demod.tuner().unwrap().set_pll_locked(false);
+        }
+
+        let dir = std::env::temp_dir().join(format!("px4_open_cal_{}", std::process::id()));
+        let mut px4 = Px4Device::new(&it930x);
+        px4.set_r850_calibration_cache(&dir, "PLL");
+        px4.init_gpio().unwrap();
+
+        // 校正で PLL がロックしなくても、既定の値のまま開ける
+        px4.open(2, 0).unwrap();
+        let Tuner::R850(r850) = &px4.chrdev(2).unwrap().tuner else { panic!() };
+        assert!(r850.calibration().lpf_cal.iter().all(|c| !c.done));
+
+        px4.close(2).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn close_sleeps_tuners_and_powers_off_last()
+    {
+        // GPIO の出力レジスタ (gpio2: 電源, gpio7: リセット, gpio11: LNB)
+        const GPIO2: u32 = 0xd8b7;
+        const GPIO7: u32 = 0xd8c3;
+        const GPIO11: u32 = 0xd8d3;
+
+        let (sim, demods, it930x) = w3u4();
+        let mut px4 = Px4Device::new(&it930x);
+        px4.init_gpio().unwrap();
+
+        px4.open(0, 15).unwrap();
+        px4.open(2, 0).unwrap();
+        assert_eq!((sim.reg(GPIO2), sim.reg(GPIO7), sim.reg(GPIO11)), (1, 0, 1));
+
+        for (i, v) in [0x40, 0x10].iter().enumerate()
+        {
+            demods[0].set_reg(0xce + i as u8, *v);
+        }
+        demods[0].set_reg(0xe6, 0x40);
+        demods[0].set_reg(0xe7, 0x10);
+        demods[2].set_reg(0xb0, 0xa8);
+        px4.tune(0, &parse_channel("BS01_0").unwrap()).unwrap();
+        px4.tune(2, &parse_channel("27").unwrap()).unwrap();
+
+        let rt710 = demods[0].tuner().unwrap();
+        let r850 = demods[2].tuner().unwrap();
+        assert_ne!(rt710.reg(0x00), 0xff);
+        assert_eq!(r850.reg(0x09), 0xc0);
+
+        // RT710 は SLEEP_REGS、復調部は sleep_s。LNB は切れるが、もう1つ開いているので電源はそのまま
+        px4.close(0).unwrap();
+        assert_eq!((rt710.reg(0x00), rt710.reg(0x02), rt710.reg(0x0f)), (0xff, 0x88, 0x59));
+        assert_eq!(demods[0].reg(0x17), 0x01);
+        assert_eq!((sim.reg(GPIO2), sim.reg(GPIO7), sim.reg(GPIO11)), (1, 0, 0));
+        assert_eq!(px4.open_count(), 1);
+
+        // R850 はミキサー・PLL を止めて、復調部は sleep_t。最後の1つなので電源も切る
+        px4.close(2).unwrap();
+        assert_eq!(r850.reg(0x09), 0xfe);
+        assert_eq!(r850.reg(0x1e) & 0x18, 0x18);
+        assert_eq!(demods[2].reg(0x03), 0x90);
+        assert_eq!((sim.reg(GPIO2), sim.reg(GPIO7)), (0, 1));
+        assert_eq!(px4.open_count(), 0);
+    }
+
+    #[test]
+    fn rt710_rf_level_from_gain_readback()
+    {
+        let (_sim, demods, it930x) = w3u4();
+        let mut px4 = Px4Device::new(&it930x);
+        px4.init_gpio().unwrap();
+        px4.open(0, 0).unwrap();
+
+        // 選局前は読めない
+        assert!(px4.chrdev(0).unwrap().get_rf_level().is_err());
+
+        let demod = &demods[0];
+        demod.set_reg(0xce, 0x40);
+        demod.set_reg(0xcf, 0x10);
+        demod.set_reg(0xe6, 0x40);
+        demod.set_reg(0xe7, 0x10);
+        px4.tune(0, &parse_channel("BS01_0").unwrap()).unwrap();
+
+        let rt710 = demod.tuner().unwrap();
+        let level = |r1: u8, r2: u8, r3: u8|
+        {
+            rt710.set_reg(0x01, r1);
+            rt710.set_reg(0x02, r2);
+            rt710.set_reg(0x03, r3);
+            px4.chrdev(0).unwrap().get_rf_level().unwrap()
+        };
+
+        // ゲインが全部 0 なら 1200MHz 未満の基準値
+        assert_eq!(level(0x00, 0x00, 0x00), -5.0);
+        // LNA 最大 (reg 0x01 bit6 + reg 0x03 bit3:0)、ミキサー・フィルタは 0
+        assert_eq!(level(0x40, 0x00, 0x0f), -5.0 - 32.0);
+        // 全部最大。PLL のロックビット (reg 0x02 bit7) は関係ない
+        assert!((level(0x40, 0x0f, 0xff) - (-5.0 - 32.0 - 14.0 - 10.8)).abs() < 1e-9);
+        // ゲインを上げているほど入力は弱い
+        assert!(level(0x00, 0x01, 0x11) > level(0x00, 0x02, 0x12));
+    }
+
+    #[test]
+    fn rt720_is_told_apart_by_chip_id()
+    {
+        let (sim, _demods, it930x) = w3u4();
+        let demod = SimTc90522::new();
+        demod.attach_tuner(SimTuner::new(SimTunerType::RT720));
+        sim.attach_i2c_slave(2, 0x11, demod.clone());
+
+        let mut px4 = Px4Device::new(&it930x);
+        px4.init_gpio().unwrap();
+        px4.open(0, 0).unwrap();
+        demod.set_reg(0xce, 0x40);
+        demod.set_reg(0xcf, 0x31);
+        demod.set_reg(0xe6, 0x40);
+        demod.set_reg(0xe7, 0x31);
+        px4.tune(0, &parse_channel("BS03_0").unwrap()).unwrap();
+
+        // RT720 の初期値 (reg 0x00 = 0x00) が使われている
+        let rt720 = demod.tuner().unwrap();
+        assert_eq!(rt720.reg(0x00), 0x00);
+        assert_eq!(rt720.reg(0x0b), 0x40);
+    }
+}
@@ -1,4 +1,10 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
 use crate::it930x::{CtrlMsgError, I2CCommRequest, I2CRequestType, IT930x};
 use crate::itedtv_bus::BusOps;
@@ -8,6 +14,11 @@ use crate::px4_device::TunerError;
 
 const R850_NUM_REGS: usize = 0x30;
 
+// get_rf_level の目安 (ゲイン 0 のときの dBm と、ゲイン 1 あたりの dB)
+// 出典の無い値なので、絶対値は当てにしないこと (get_rf_level のコメントも参照)
+const R850_RF_LEVEL_MAX: f64 = -10.0;
+const R850_RF_LEVEL_STEP: f64 = 1.2;
+
 // C の init_regs 配列を Rust にコピー
 pub const INIT_REGS: [u8; R850_NUM_REGS] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -28,7 +39,18 @@ pub struct R850Config {
     pub no_lpf_calibration: bool,
 }
 
+// px4_device.c の r850 の config
+impl Default for R850Config
+{
+    fn default() -> Self
+    {
+        Self { xtal: 24000, loop_through: false, clock_out: false, no_imr_calibration: false, no_lpf_calibration: false }
+    }
+}
+
 // システム定義
+// r850.c の enum r850_system をそのまま持っている。PX4 で使うのは IsdbT だけで、他は set_system で弾く
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum R850System {
     Undefined = 0,
@@ -81,10 +103,18 @@ pub struct R850Priv {
     pub mixer_mode: u8,
     pub mixer_amp_lpf_imr_cal: u8,
     pub imr_cal: [R850ImrCal; 2],
+    pub lpf_cal: [R850LpfCal; 3],
     pub sys_curr: R850SystemConfig,
 }
 
-#[derive(Debug)]
+// LPF 校正の結果 (R850Bandwidth ごと)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct R850LpfCal {
+    pub done: bool,
+    pub code: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct R850ImrCal {
     pub imr: [R850Imr; 5],
     pub done: bool,
@@ -97,11 +127,7 @@ pub struct R850<'a, B: BusOps>
     tc90522: TC90522<'a, B>,
 
     // 設定パラメータ
-    pub xtal: u32,
-    pub loop_through: bool,
-    pub clock_out: bool,
-    pub no_imr_calibration: bool,
-    pub no_lpf_calibration: bool,
+    pub config: R850Config,
 
     pub i2c_addr: u8,
     priv_: R850Priv,
@@ -120,7 +146,7 @@ impl<'a, B: BusOps> R850<'a, B>
 
     pub fn read_regs(&self, reg: u8, buf: &mut [u8]) -> Result<(), CtrlMsgError>
     {
-        if (buf.len() == 0) || (buf.len() > (R850_NUM_REGS - reg as usize))
+        if buf.is_empty() || (buf.len() > (R850_NUM_REGS - reg as usize))
         {
             return Err(CtrlMsgError::InvalidLength);
         }
@@ -158,7 +184,7 @@ impl<'a, B: BusOps> R850<'a, B>
 
     pub fn write_regs(&self, reg: u8, buf: &[u8]) -> Result<(), CtrlMsgError>
     {
-        if (buf.len() == 0) || (buf.len() > (R850_NUM_REGS - reg as usize))
+        if buf.is_empty() || (buf.len() > (R850_NUM_REGS - reg as usize))
         {
             return Err(CtrlMsgError::InvalidLength);
         }
@@ -181,12 +207,29 @@ impl<'a, B: BusOps> R850<'a, B>
     }
 
     // メモ: 初期値(デフォルト値)に戻すイメージ
+    // r850.c 619〜633行目の微調整 (チップ・設定・xtal power ごと) もここでやる
     pub fn init_regs(&mut self)
     {
         self.priv_.regs.copy_from_slice(&INIT_REGS);
 
-        // ここで各種微調整も可能らしい。
-        // 微調整のコードは、r850.c 619〜633行目を参照
+        let r = &mut self.priv_.regs;
+
+        // 新しいリビジョンは内部 LDO の設定が違う
+        if self.priv_.chip != 0
+        {
+            r[0x2f] = (r[0x2f] & 0xfc) | 0x01;
+        }
+
+        // ループスルー (bit が立っていると off)
+        if self.config.loop_through { r[0x08] &= 0xbf; }
+        else { r[0x08] |= 0x40; }
+
+        // クロック出力 (bit が立っていると off)
+        if self.config.clock_out { r[0x22] &= 0xfb; }
+        else { r[0x22] |= 0x04; }
+
+        // check_xtal_power で決めた xtal power
+        r[0x22] = (r[0x22] & 0xcf) | ((self.priv_.xtal_pwr & 0x03) << 4);
     }
 
     pub fn check_xtal_power(&mut self) -> Result<(), CtrlMsgError>
@@ -222,7 +265,7 @@ impl<'a, B: BusOps> R850<'a, B>
         }
 
         // 本体のレジスタに書き込み
-        self.write_regs(0x08, &self.priv_.regs[0x08..R850_NUM_REGS]);
+        self.write_regs(0x08, &self.priv_.regs[0x08..R850_NUM_REGS])?;
 
         // ループで xtal_power を探す
         for i in 0..=3 {
@@ -258,11 +301,7 @@ impl<'a, B: BusOps> R850<'a, B>
             i2c_addr: 0x7c, 
             //i2c_addr: 0x3e,
 
-            xtal: 0,
-            loop_through: false,
-            clock_out: false,
-            no_imr_calibration: false,
-            no_lpf_calibration: false,
+            config: R850Config::default(),
 
             priv_: R850Priv
             {
@@ -278,6 +317,7 @@ impl<'a, B: BusOps> R850<'a, B>
                     R850ImrCal{imr: [R850Imr{gain: 0, phase: 0, iqcap: 0, value: 0}; 5], done: false, result: [false; 5], mixer_amp_lpf: 0,}, 
                     R850ImrCal{imr: [R850Imr{gain: 0, phase: 0, iqcap: 0, value: 0}; 5], done: false, result: [false; 5], mixer_amp_lpf: 0,},
                     ],
+                lpf_cal: [R850LpfCal::default(); 3],
                 mixer_mode: 0,
                 mixer_amp_lpf_imr_cal: 0
             },
@@ -308,40 +348,1096 @@ impl<'a, B: BusOps> R850<'a, B>
                     *imr = R850Imr { gain: 0, phase: 0, iqcap: 0, value: 0 };
                 }
             }
+            self.priv_.lpf_cal = [R850LpfCal::default(); 3];
 
             // チップ判定
+            let mut tmp = [0u8];
             let mut detected = false;
             for _ in 0..4
             {
-                let mut tmp = [0u8];
-                if self.read_regs(0x00, &mut tmp).is_ok()
+                if self.read_regs(0x00, &mut tmp).is_ok() && (tmp[0] & 0x98) == 0x98
                 {
-                    if (tmp[0] & 0x98) != 0
-                    {
-                        self.priv_.chip = 0;
-                        detected = true;
-                        break;
-                    }
+                    detected = true;
+                    break;
                 }
             }
 
             if !detected
             {
-                // なんか、新しいエラーを生やせばいいかな
                 return Err(TunerError::ChipNotDetected);
             }
 
-            // レジスタ初期化
-            let mut regs = [0u8; R850_NUM_REGS - 0x08];
-            self.read_regs(0x08, &mut regs)?;
+            // リビジョン (下位 3bit が 0 なら初期のもの)
+            self.priv_.chip = if (tmp[0] & 0x07) != 0 { 1 } else { 0 };
+        }
+
+        // xtal power を決めてから、それを使ってレジスタを初期化する
+        self.priv_.xtal_pwr = 0;
+        self.check_xtal_power()?;
+
+        self.init_regs();
+        self.write_regs(0x08, &self.priv_.regs[0x08..R850_NUM_REGS])?;
+
+        // 選局されるまでは寝かせておく
+        self.sleep()?;
+
+        self.priv_.init = true;
+
+        Ok(())
+    }
+
+    // r850.c の r850_sleep 相当
+    // 保持しているレジスタ状態はそのままにして、電源を落とす値だけ書く。
+    // 起こすときは保持している状態を書き戻せば良い。
+    pub fn sleep(&mut self) -> Result<(), TunerError>
+    {
+        let mut regs = self.priv_.regs;
+
+        regs[0x08] = (regs[0x08] & 0x40) | 0x06;  // LNA, RF buffer off (ループスルーは残す)
+        regs[0x09] = 0xfe;                        // ミキサー, ポリフェーズ off
+        regs[0x0a] = (regs[0x0a] & 0xef) | 0x0f;  // IF アンプ off
+        regs[0x0b] &= 0xef;                       // ADC off
+        regs[0x1e] |= 0x18;                       // PLL, SDM off
+
+        self.write_regs(0x08, &regs[0x08..R850_NUM_REGS])?;
+
+        self.priv_.sleep = true;
+        Ok(())
+    }
+
+    pub fn wakeup(&mut self) -> Result<(), TunerError>
+    {
+        self.write_regs(0x08, &self.priv_.regs[0x08..R850_NUM_REGS])?;
+
+        self.priv_.sleep = false;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn is_sleeping(&self) -> bool
+    {
+        self.priv_.sleep
+    }
+
+    // r850.c の r850_get_rf_signal_strength を元にした、大まかな相対値としての RF レベル (dBm)
+    // reg 0x03 bit4:0 が LNA、reg 0x04 の下位/上位 4bit が RF / ミキサーのゲイン。
+    // それぞれを足したものを、ゲインが大きいほど入力が弱いとして線形に割り当てる。
+    // RT710 のようなゲインの表は無いので、強くなった / 弱くなったを見る程度にしか使えない。
+    pub fn get_rf_level(&self) -> Result<f64, CtrlMsgError>
+    {
+        let mut tmp = [0u8; 5];
+        self.read_regs(0x00, &mut tmp)?;
+
+        let lna = (tmp[3] & 0x1f) as f64;
+        let rf = (tmp[4] & 0x0f) as f64;
+        let mixer = ((tmp[4] >> 4) & 0x0f) as f64;
+
+        Ok(R850_RF_LEVEL_MAX - (lna + rf + mixer) * R850_RF_LEVEL_STEP)
+    }
+}
+
+
+// ここからは校正 (IMR / LPF) の話
+// r850.c の r850_calibrate_imr / r850_calibrate_lpf 周りの移植 (元は Rafael の SDK の R850_IMR / R850_Filt_Cal_ADC)
+// 校正中はリングオシレータで作ったテスト信号をミキサーに入れ、内蔵 ADC の値 (イメージ成分の大きさ) を見ながら
+// gain / phase / iqcap を追い込む。
+//   1. 5点のうち R850_IMR_FULL_POINT だけ全部探す (cross → iq_tree → section → iqcap)
+//   2. 残りは、近い点の結果から section と iqcap だけやり直す
+// LPF は帯域端にテスト信号を置いて、コードを狭めていったときに ADC が落ちるところを探す。
+
+// IMR 校正を行う 5点 (kHz)
+const R850_IMR_POINTS: [u32; 5] = [205000, 405000, 637000, 882000, 1010000];
+
+// 全部探す点と、残りの点をどの順で、どの点の結果から始めるか (r850.c の r850_calibrate_imr の順)
+const R850_IMR_FULL_POINT: usize = 3;
+const R850_IMR_FAST_POINTS: [(usize, usize); 4] = [(1, 3), (0, 1), (2, 3), (4, 3)];
+
+// VCO の発振範囲 (kHz)
+const R850_VCO_MIN: u32 = 2270000;
+const R850_VCO_MAX: u32 = R850_VCO_MIN * 2;
+
+// IMR の gain / phase は 5bit の大きさ + 符号 (bit5)
+const R850_IMR_MAX: u8 = 0x1f;
+const R850_IMR_SIGN: u8 = 0x20;
+
+// iqcap は 0〜2
+const R850_IMR_IQCAP_MAX: u8 = 2;
+
+// ADC は 6回読んで、最大と最小を除いた 4回分の合計を使う (r850_imr_multi_read)
+const R850_ADC_MULTI_READ: usize = 6;
+const R850_ADC_SETTLE: Duration = Duration::from_millis(3);
+
+// テスト信号の IF (kHz)。IMR は LO からこれだけずらしたところ、LPF は帯域端からこれだけ外
+const R850_IMR_CAL_IF: u32 = 2000;
+const R850_LPF_CAL_OFFSET: u32 = 2000;
+
+// LPF のコードを狭めていき、基準から (4回分の合計で) これだけ落ちたら帯域端とみなす (R850_Filt_Cal_ADC の FilCal_Gap)
+const R850_LPF_CAL_GAP: u16 = 8;
+
+// 校正中に使うミキサーアンプの LPF 設定
+const R850_MIXER_AMP_LPF_IMR_CAL: u8 = 4;
+
+impl R850Bandwidth
+{
+    fn index(&self) -> usize
+    {
+        *self as usize
+    }
+
+    // 帯域幅 (kHz)
+    pub fn khz(&self) -> u32
+    {
+        match self
+        {
+            R850Bandwidth::B6M => 6000,
+            R850Bandwidth::B7M => 7000,
+            R850Bandwidth::B8M => 8000,
+        }
+    }
+}
+
+// gain / phase の符号付きの値をレジスタ表現にする
+fn imr_encode(v: i8) -> u8
+{
+    if v < 0 { R850_IMR_SIGN | ((-v) as u8 & R850_IMR_MAX) } else { v as u8 & R850_IMR_MAX }
+}
+
+fn imr_decode(v: u8) -> i8
+{
+    let mag = (v & R850_IMR_MAX) as i8;
+    if (v & R850_IMR_SIGN) != 0 { -mag } else { mag }
+}
+
+// 探索中の 1点 (r850.c の struct r850_imr を符号付きにしたもの)
+#[derive(Debug, Clone, Copy)]
+struct ImrPoint
+{
+    gain: i8,
+    phase: i8,
+    iqcap: u8,
+    value: u16,
+}
+
+impl ImrPoint
+{
+    fn to_imr(self) -> R850Imr
+    {
+        R850Imr { gain: imr_encode(self.gain), phase: imr_encode(self.phase), iqcap: self.iqcap, value: self.value.min(u8::MAX as u16) as u8 }
+    }
+}
+
+// どちらの軸を動かすか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImrAxis
+{
+    Gain,
+    Phase,
+}
+
+impl ImrAxis
+{
+    fn other(self) -> Self
+    {
+        match self
+        {
+            ImrAxis::Gain => ImrAxis::Phase,
+            ImrAxis::Phase => ImrAxis::Gain,
+        }
+    }
+}
+
+// 一番小さいもの (r850_compre_cor)。同じなら先のもの
+fn imr_min(points: &[ImrPoint; 3]) -> ImrPoint
+{
+    points.iter().copied().fold(points[0], |best, p| if p.value < best.value { p } else { best })
+}
+
+impl<'a, B: BusOps> R850<'a, B>
+{
+    // 保持しているレジスタ状態の一部を書き込む
+    fn write_cached_regs(&self, reg: u8, len: usize) -> Result<(), CtrlMsgError>
+    {
+        let start = reg as usize;
+        self.write_regs(reg, &self.priv_.regs[start..start + len])
+    }
+
+    // 校正などで触ったレジスタを保持している状態に戻す (寝ていたら寝かせ直す)
+    fn restore_regs(&mut self) -> Result<(), TunerError>
+    {
+        if self.priv_.sleep
+        {
+            self.sleep()
+        }
+        else
+        {
+            self.wakeup()
+        }
+    }
+
+    // r850.c の r850_set_pll 相当
+    // lo_freq は kHz
+    fn set_pll(&mut self, lo_freq: u32) -> Result<bool, CtrlMsgError>
+    {
+        let xtal = self.config.xtal;
+
+        // VCO が範囲に収まるまで分周比を上げる
+        let mut mix_div: u32 = 2;
+        let mut div_num: u8 = 0;
+        while mix_div <= 64
+        {
+            if (R850_VCO_MIN..=R850_VCO_MAX).contains(&(lo_freq * mix_div))
+            {
+                break;
+            }
+            mix_div *= 2;
+            div_num += 1;
+        }
+
+        if mix_div > 64
+        {
+            return Err(CtrlMsgError::InvalidArgument);
+        }
+
+        let vco_freq = lo_freq * mix_div;
+        let nint = (vco_freq / 2) / xtal;
+        let mut vco_fra = vco_freq - (xtal * 2 * nint);
+
+        if nint < 13
+        {
+            return Err(CtrlMsgError::InvalidArgument);
+        }
+
+        let ni = (nint - 13) / 4;
+        let si = nint - 13 - (ni * 4);
+
+        // SDM の計算
+        let mut nsdm: u32 = 2;
+        let mut sdm: u32 = 0;
+        while vco_fra > 1
+        {
+            let t = (xtal * 2) / nsdm;
+            if vco_fra > t
+            {
+                sdm += 0x8000 / (nsdm / 2);
+                vco_fra -= t;
+                if nsdm >= 0x8000
+                {
+                    break;
+                }
+            }
+            nsdm *= 2;
+        }
+
+        {
+            let r = &mut self.priv_.regs;
+
+            // 分周比
+            r[0x1e] &= 0x1f;
+            r[0x1e] |= div_num << 5;
+
+            // ni / si
+            r[0x1b] &= 0x80;
+            r[0x1b] |= ((si << 5) | ni) as u8 & 0x7f;
+
+            // SDM (割り切れる場合は止める)
+            if sdm == 0 { r[0x1e] |= 0x08; }
+            else { r[0x1e] &= 0xf7; }
+
+            r[0x1c] = ((sdm >> 8) & 0xff) as u8;
+            r[0x1d] = (sdm & 0xff) as u8;
+        }
+
+        self.write_cached_regs(0x1b, 4)?;
+
+        thread::sleep(Duration::from_millis(2));
+
+        // PLL のロック確認
+        let mut tmp = [0u8; 1];
+        self.read_regs(0x02, &mut tmp)?;
+
+        Ok((tmp[0] & 0x40) != 0)
+    }
+
+    // r850.c の r850_imr_multi_read 相当
+    // 内蔵 ADC (reg 0x01 bit5:0) を 6回読み、最大と最小を除いた 4回分の合計 (0〜252) を返す
+    fn multi_read(&self) -> Result<u16, CtrlMsgError>
+    {
+        thread::sleep(R850_ADC_SETTLE);
+
+        let mut tmp = [0u8; 2];
+        let (mut sum, mut max, mut min) = (0u16, 0u16, u16::MAX);
+        for _ in 0..R850_ADC_MULTI_READ
+        {
+            self.read_regs(0x00, &mut tmp)?;
+
+            let v = (tmp[1] & 0x3f) as u16;
+            sum += v;
+            max = max.max(v);
+            min = min.min(v);
+        }
+
+        Ok(sum - max - min)
+    }
+
+    // 校正モードの切り替え
+    // on: リングオシレータと ADC を有効にし、LNA/ミキサーのゲインを固定する
+    fn set_calibration_mode(&mut self, on: bool, mixer_amp_lpf: u8) -> Result<(), CtrlMsgError>
+    {
+        {
+            let r = &mut self.priv_.regs;
+            if on
+            {
+                r[0x08] |= 0x80;  // ring on
+                r[0x0a] &= 0xef;  // LNA off
+                r[0x0b] |= 0x10;  // ADC on
+                r[0x0d] = (r[0x0d] & 0xf0) | 0x0a;  // mixer gain 固定
+                r[0x10] = (r[0x10] & 0xf8) | (mixer_amp_lpf & 0x07);
+            }
+            else
+            {
+                r[0x08] &= 0x7f;
+                r[0x0a] |= 0x10;
+                r[0x0b] &= 0xef;
+                r[0x0d] = INIT_REGS[0x0d];
+                r[0x10] = INIT_REGS[0x10];
+            }
+        }
+
+        self.write_cached_regs(0x08, 9)
+    }
+
+    fn write_imr(&mut self, gain: i8, phase: i8, iqcap: u8) -> Result<(), CtrlMsgError>
+    {
+        {
+            let r = &mut self.priv_.regs;
+            r[0x14] = (r[0x14] & 0xc0) | imr_encode(gain);
+            r[0x15] = imr_encode(phase) | ((iqcap & 0x03) << 6);
+        }
+
+        self.write_cached_regs(0x14, 2)
+    }
+
+    // gain / phase / iqcap を入れてみて、イメージの大きさを測る
+    fn measure_imr(&mut self, gain: i8, phase: i8, iqcap: u8) -> Result<ImrPoint, CtrlMsgError>
+    {
+        let max = R850_IMR_MAX as i8;
+        let (gain, phase) = (gain.clamp(-max, max), phase.clamp(-max, max));
+
+        self.write_imr(gain, phase, iqcap)?;
+        Ok(ImrPoint { gain, phase, iqcap, value: self.multi_read()? })
+    }
+
+    // axis 側だけ delta 動かした点を測る
+    fn measure_imr_step(&mut self, p: ImrPoint, axis: ImrAxis, delta: i8) -> Result<ImrPoint, CtrlMsgError>
+    {
+        match axis
+        {
+            ImrAxis::Gain => self.measure_imr(p.gain + delta, p.phase, p.iqcap),
+            ImrAxis::Phase => self.measure_imr(p.gain, p.phase + delta, p.iqcap),
+        }
+    }
+
+    // r850.c の r850_imr_cross 相当
+    // 真ん中と、gain 軸・phase 軸をそれぞれ ±1, ±2 動かした 9点を測り、一番小さい点と、その点がある軸を返す
+    // (真ん中が一番小さければ gain 軸から詰める)
+    fn imr_cross(&mut self) -> Result<(ImrPoint, ImrAxis), CtrlMsgError>
+    {
+        let center = self.measure_imr(0, 0, 0)?;
+        let mut best = (center, ImrAxis::Gain);
+
+        for axis in [ImrAxis::Gain, ImrAxis::Phase]
+        {
+            for delta in [-2i8, -1, 1, 2]
+            {
+                let p = self.measure_imr_step(center, axis, delta)?;
+                if p.value < best.0.value
+                {
+                    best = (p, axis);
+                }
+            }
+        }
+
+        Ok(best)
+    }
+
+    // r850.c の r850_iq_tree + r850_compre_step 相当
+    // もう片方の軸を固定して、axis 側の ±1 と今の点の 3点から一番小さい方へ進み、
+    // 小さくならなくなるまで同じ向きに 1 ずつ進む
+    fn imr_iq_tree(&mut self, p: ImrPoint, axis: ImrAxis) -> Result<ImrPoint, CtrlMsgError>
+    {
+        let minus = self.measure_imr_step(p, axis, -1)?;
+        let plus = self.measure_imr_step(p, axis, 1)?;
+
+        let (mut best, dir) = if minus.value < p.value && minus.value <= plus.value
+        {
+            (minus, -1)
+        }
+        else if plus.value < p.value
+        {
+            (plus, 1)
+        }
+        else
+        {
+            return Ok(p);
+        };
+
+        loop
+        {
+            let pos = match axis { ImrAxis::Gain => best.gain, ImrAxis::Phase => best.phase };
+            if pos.abs() >= R850_IMR_MAX as i8
+            {
+                break;
+            }
+
+            let next = self.measure_imr_step(best, axis, dir)?;
+            if next.value >= best.value
+            {
+                break;
+            }
+            best = next;
+        }
+
+        Ok(best)
+    }
+
+    // r850.c の r850_section 相当
+    // gain を今の値と ±1 にしたそれぞれで phase を詰め直し、一番小さい点を選ぶ
+    fn imr_section(&mut self, p: ImrPoint) -> Result<ImrPoint, CtrlMsgError>
+    {
+        let mut points = [p; 3];
+        for (i, delta) in [-1i8, 0, 1].iter().enumerate()
+        {
+            let start = self.measure_imr_step(p, ImrAxis::Gain, *delta)?;
+            points[i] = self.imr_iq_tree(start, ImrAxis::Phase)?;
+        }
+
+        Ok(imr_min(&points))
+    }
+
+    // r850.c の r850_imr_iqcap 相当
+    fn imr_iqcap(&mut self, p: ImrPoint) -> Result<ImrPoint, CtrlMsgError>
+    {
+        let mut best = p;
+        for iqcap in 0..=R850_IMR_IQCAP_MAX
+        {
+            let v = self.measure_imr(p.gain, p.phase, iqcap)?;
+            if v.value < best.value
+            {
+                best = v;
+            }
+        }
+
+        Ok(best)
+    }
+
+    // LPF のコード (reg 0x12 bit3:0) を入れてみて、帯域端のテスト信号の大きさを測る
+    fn measure_lpf(&mut self, code: u8) -> Result<u16, CtrlMsgError>
+    {
+        self.priv_.regs[0x12] = (self.priv_.regs[0x12] & 0xf0) | (code & 0x0f);
+        self.write_cached_regs(0x12, 1)?;
+        self.multi_read()
+    }
+
+    // 1点分の IMR 校正 (from が無ければ全部探す、あればその点から詰め直す)
+    // PLL がロックしなければ None
+    fn calibrate_imr_point(&mut self, freq: u32, from: Option<R850Imr>) -> Result<Option<ImrPoint>, CtrlMsgError>
+    {
+        // リングの信号がイメージ側に来るように LO を合わせる
+        let lo_freq = if self.priv_.mixer_mode != 0 { freq + R850_IMR_CAL_IF } else { freq - R850_IMR_CAL_IF };
+        if !self.set_pll(lo_freq)?
+        {
+            return Ok(None);
+        }
+
+        let p = match from
+        {
+            // r850.c の r850_iq (全部探す)
+            None =>
+            {
+                let (p, axis) = self.imr_cross()?;
+                let p = self.imr_iq_tree(p, axis)?;
+                self.imr_iq_tree(p, axis.other())?
+            }
+            // r850.c の r850_f_imr (前の点の結果から)
+            Some(imr) => self.measure_imr(imr_decode(imr.gain), imr_decode(imr.phase), imr.iqcap)?,
+        };
+
+        let p = self.imr_section(p)?;
+        Ok(Some(self.imr_iqcap(p)?))
+    }
+
+    // 1つのミキサーモードの 5点
+    fn calibrate_imr_mode(&mut self) -> Result<R850ImrCal, CtrlMsgError>
+    {
+        let mut cal = R850ImrCal { imr: [R850Imr { gain: 0, phase: 0, iqcap: 0, value: 0 }; 5], done: false, result: [false; 5], mixer_amp_lpf: R850_MIXER_AMP_LPF_IMR_CAL };
+
+        let order = [(R850_IMR_FULL_POINT, None)].into_iter().chain(R850_IMR_FAST_POINTS.iter().map(|(i, from)| (*i, Some(*from))));
+        for (i, from) in order
+        {
+            // 元にする点が取れていなければ、その点も全部探す
+            let from = from.filter(|f| cal.result[*f]).map(|f| cal.imr[f]);
 
-            // check xtal power
+            if let Some(p) = self.calibrate_imr_point(R850_IMR_POINTS[i], from)?
+            {
+                cal.imr[i] = p.to_imr();
+                cal.result[i] = true;
+            }
+        }
 
-            self.write_regs(0x08, &regs)?;
+        cal.done = true;
+        Ok(cal)
+    }
 
-            // init regs
+    // r850.c の r850_calibrate_imr の移植
+    // 2つのミキサーモード × 5点 を校正する。時間がかかる (数秒) ので、結果はキャッシュしておくこと。
+    // PLL がロックしなかった点は result が false のまま残る (set_frequency は取れた点だけ使う)
+    pub fn calibrate_imr(&mut self) -> Result<(), TunerError>
+    {
+        if self.config.no_imr_calibration
+        {
+            return Ok(());
         }
 
+        let saved_regs = self.priv_.regs;
+        let saved_mixer_mode = self.priv_.mixer_mode;
+
+        self.priv_.mixer_amp_lpf_imr_cal = R850_MIXER_AMP_LPF_IMR_CAL;
+
+        let result = (|| -> Result<(), CtrlMsgError>
+        {
+            self.set_calibration_mode(true, R850_MIXER_AMP_LPF_IMR_CAL)?;
+
+            for mode in 0..self.priv_.imr_cal.len()
+            {
+                self.priv_.mixer_mode = mode as u8;
+                self.priv_.imr_cal[mode] = self.calibrate_imr_mode()?;
+            }
+            Ok(())
+        })();
+
+        // 校正前の状態に戻す
+        self.priv_.mixer_mode = saved_mixer_mode;
+        self.priv_.regs = saved_regs;
+        let restore = self.restore_regs();
+
+        result?;
+        restore
+    }
+
+    // r850.c の r850_calibrate_lpf の移植 (R850_Filt_Cal_ADC)
+    // 帯域端より少し外にテスト信号を置き、一番広いコードの値を基準にして、
+    // コードを狭めていったときに R850_LPF_CAL_GAP 以上落ちる手前のコードを使う
+    pub fn calibrate_lpf(&mut self, bandwidth: R850Bandwidth) -> Result<u8, TunerError>
+    {
+        let idx = bandwidth.index();
+        if self.config.no_lpf_calibration
+        {
+            return Ok(self.priv_.lpf_cal[idx].code);
+        }
+
+        let saved_regs = self.priv_.regs;
+
+        // PLL がロックしなければ None (IMR と同じく、その帯域は既定のコードのまま続ける)
+        let result = (|| -> Result<Option<u8>, TunerError>
+        {
+            self.set_calibration_mode(true, self.priv_.mixer_amp_lpf_imr_cal)?;
+
+            let lo_freq = R850_IMR_POINTS[0] - (bandwidth.khz() / 2 + R850_LPF_CAL_OFFSET);
+            if !self.set_pll(lo_freq)?
+            {
+                return Ok(None);
+            }
+
+            let reference = self.measure_lpf(0)?;
+
+            let mut code = 0u8;
+            for c in 1..=0x0f
+            {
+                if reference.saturating_sub(self.measure_lpf(c)?) >= R850_LPF_CAL_GAP
+                {
+                    break;
+                }
+                code = c;
+            }
+
+            Ok(Some(code))
+        })();
+
+        self.priv_.regs = saved_regs;
+        let restore = self.restore_regs();
+
+        let code = result?;
+        restore?;
+
+        match code
+        {
+            Some(code) => self.priv_.lpf_cal[idx] = R850LpfCal { done: true, code },
+            None => self.priv_.lpf_cal[idx].done = false,
+        }
+        Ok(self.priv_.lpf_cal[idx].code)
+    }
+
+    // 校正結果の取り出し / 流し込み (キャッシュ用)
+    pub fn calibration(&self) -> R850Calibration
+    {
+        R850Calibration
+        {
+            imr_cal: self.priv_.imr_cal,
+            mixer_amp_lpf_imr_cal: self.priv_.mixer_amp_lpf_imr_cal,
+            lpf_cal: self.priv_.lpf_cal,
+        }
+    }
+
+    pub fn set_calibration(&mut self, cal: &R850Calibration)
+    {
+        self.priv_.imr_cal = cal.imr_cal;
+        self.priv_.mixer_amp_lpf_imr_cal = cal.mixer_amp_lpf_imr_cal;
+        self.priv_.lpf_cal = cal.lpf_cal;
+    }
+}
+
+// R850 1個分の校正結果
+#[derive(Debug, Clone, Copy)]
+pub struct R850Calibration
+{
+    pub imr_cal: [R850ImrCal; 2],
+    pub mixer_amp_lpf_imr_cal: u8,
+    pub lpf_cal: [R850LpfCal; 3],
+}
+
+// 校正結果のキャッシュファイル
+// デバイスのシリアル番号ごとに1ファイルで、中に TC90522 のアドレスごとの結果を持つ。
+//   # r850 calibration v1
+//   serial <serial>
+//   <addr> amp <mixer_amp_lpf_imr_cal>
+//   <addr> imr <mode> <done> <mixer_amp_lpf> <gain> <phase> <iqcap> <value> <result> (× 5点)
+//   <addr> lpf <bandwidth> <done> <code>
+#[derive(Debug, Default)]
+pub struct R850CalibrationCache
+{
+    pub serial: String,
+    pub entries: HashMap<u8, R850Calibration>,
+}
+
+impl R850CalibrationCache
+{
+    pub fn new(serial: &str) -> Self
+    {
+        Self { serial: serial.to_string(), entries: HashMap::new() }
+    }
+
+    // キャッシュファイルのパス
+    pub fn path_for(dir: &Path, serial: &str) -> PathBuf
+    {
+        let name: String = serial.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+        dir.join(format!("r850-{}.cal", name))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CtrlMsgError>
+    {
+        let text = fs::read_to_string(path)?;
+        let bad = |line: &str| CtrlMsgError::IO(io::Error::new(io::ErrorKind::InvalidData, format!("invalid calibration cache line: {}", line)));
+
+        let empty = R850Calibration
+        {
+            imr_cal: [R850ImrCal { imr: [R850Imr { gain: 0, phase: 0, iqcap: 0, value: 0 }; 5], done: false, result: [false; 5], mixer_amp_lpf: 0 }; 2],
+            mixer_amp_lpf_imr_cal: 0,
+            lpf_cal: [R850LpfCal::default(); 3],
+        };
+
+        let mut cache = Self::default();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#'))
+        {
+            let f: Vec<&str> = line.split_whitespace().collect();
+            if f[0] == "serial"
+            {
+                cache.serial = f.get(1).ok_or_else(|| bad(line))?.to_string();
+                continue;
+            }
+
+            let nums: Vec<u8> = f.iter().skip(2).map(|v| v.parse::<u8>()).collect::<Result<_, _>>().map_err(|_| bad(line))?;
+            let addr = u8::from_str_radix(f[0].trim_start_matches("0x"), 16).map_err(|_| bad(line))?;
+            let cal = cache.entries.entry(addr).or_insert(empty);
+
+            match (f.get(1).copied(), nums.as_slice())
+            {
+                (Some("amp"), [amp]) => cal.mixer_amp_lpf_imr_cal = *amp,
+                (Some("imr"), [mode, done, amp, rest @ ..]) if (*mode as usize) < 2 && rest.len() == 25 =>
+                {
+                    let c = &mut cal.imr_cal[*mode as usize];
+                    c.done = *done != 0;
+                    c.mixer_amp_lpf = *amp;
+                    for (i, p) in rest.chunks(5).enumerate()
+                    {
+                        c.imr[i] = R850Imr { gain: p[0], phase: p[1], iqcap: p[2], value: p[3] };
+                        c.result[i] = p[4] != 0;
+                    }
+                }
+                (Some("lpf"), [bw, done, code]) if (*bw as usize) < 3 =>
+                {
+                    cal.lpf_cal[*bw as usize] = R850LpfCal { done: *done != 0, code: *code };
+                }
+                _ => return Err(bad(line)),
+            }
+        }
+
+        Ok(cache)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CtrlMsgError>
+    {
+        if let Some(dir) = path.parent()
+        {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut out = String::new();
+        out.push_str("# r850 calibration v1\n");
+        out.push_str(&format!("serial {}\n", self.serial));
+
+        let mut addrs: Vec<&u8> = self.entries.keys().collect();
+        addrs.sort();
+        for addr in addrs
+        {
+            let cal = &self.entries[addr];
+            out.push_str(&format!("0x{:02x} amp {}\n", addr, cal.mixer_amp_lpf_imr_cal));
+
+            for (mode, c) in cal.imr_cal.iter().enumerate()
+            {
+                out.push_str(&format!("0x{:02x} imr {} {} {}", addr, mode, c.done as u8, c.mixer_amp_lpf));
+                for (imr, result) in c.imr.iter().zip(c.result.iter())
+                {
+                    out.push_str(&format!(" {} {} {} {} {}", imr.gain, imr.phase, imr.iqcap, imr.value, *result as u8));
+                }
+                out.push('\n');
+            }
+
+            for (bw, l) in cal.lpf_cal.iter().enumerate()
+            {
+                out.push_str(&format!("0x{:02x} lpf {} {} {}\n", addr, bw, l.done as u8, l.code));
+            }
+        }
+
+        // 書きかけのファイルを読まないように、一旦別名で書いてから置き換える
+        let tmp = path.with_extension("cal.tmp");
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+
+// ここからは選局の話
+// r850.c の r850_set_system / r850_set_frequency 周りの移植
+
+// システムごとの設定値
+// r850.c の sys_info 相当 (px4 で使う ISDB-T 6MHz 以外は、まだ持っていない)
+#[derive(Debug, Clone, Copy)]
+struct R850SystemParams
+{
+    system: R850System,
+    bandwidth: R850Bandwidth,
+    if_freq: u32,       // 標準の IF 周波数 (kHz)
+    lpf_code: u8,       // LPF の校正が無いときのコード (reg 0x12 bit3:0)
+    hpf_cor: u8,        // HPF (reg 0x12 bit7:4)
+    filter_ext: u8,     // フィルタ拡張 (reg 0x13 bit1:0)
+    lna_top: u8,        // LNA TOP (reg 0x0d bit7:4)
+    rf_top: u8,         // RF TOP (reg 0x1a bit3:0)
+    mixer_top: u8,      // ミキサー TOP (reg 0x2c bit7:4)
+    agc_clk: u8,        // AGC クロック (reg 0x27 bit5:4)
+}
+
+const R850_SYSTEM_PARAMS: [R850SystemParams; 1] = [
+    R850SystemParams
+    {
+        system: R850System::IsdbT,
+        bandwidth: R850Bandwidth::B6M,
+        if_freq: 4063,
+        lpf_code: 0x0a,
+        hpf_cor: 0x0b,
+        filter_ext: 0x02,
+        lna_top: 0x04,
+        rf_top: 0x05,
+        mixer_top: 0x09,
+        agc_clk: 0x01,
+    },
+];
+
+// この周波数 (kHz) より下は LO を RF より上に置く (mixer_mode = 1)
+const R850_MIXER_MODE_BOUNDARY: u32 = 340000;
+
+// PLL のロック確認 (r850.c は 10ms 待って 1 回だけ再設定している)
+const R850_PLL_LOCK_RETRY: u32 = 2;
+const R850_PLL_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+impl<'a, B: BusOps> R850<'a, B>
+{
+    fn system_params(sys: &R850SystemConfig) -> Result<R850SystemParams, TunerError>
+    {
+        R850_SYSTEM_PARAMS.iter()
+            .find(|p| p.system == sys.system && p.bandwidth.index() == sys.bandwidth.index())
+            .copied()
+            .ok_or(TunerError::R850UnsupportedSystem(sys.system, sys.bandwidth))
+    }
+
+    // r850.c の r850_set_system 相当
+    // 設定は保持するだけで、実際にレジスタへ書くのは set_frequency のとき。
+    // if_freq が 0 の場合はシステムの標準値を使う。
+    pub fn set_system(&mut self, sys: &R850SystemConfig) -> Result<(), TunerError>
+    {
+        // r850.c の sys_info のうち、移植しているのは ISDB-T だけ
+        if sys.system != R850System::IsdbT
+        {
+            return Err(TunerError::R850UnsupportedSystem(sys.system, sys.bandwidth));
+        }
+
+        let params = Self::system_params(sys)?;
+
+        self.priv_.sys = *sys;
+        if self.priv_.sys.if_freq == 0
+        {
+            self.priv_.sys.if_freq = params.if_freq;
+        }
+
+        Ok(())
+    }
+
+    // 周波数に近い校正点の IMR の値
+    fn imr_for(&self, freq: u32) -> Option<R850Imr>
+    {
+        let cal = &self.priv_.imr_cal[self.priv_.mixer_mode as usize];
+        if !cal.done
+        {
+            return None;
+        }
+
+        R850_IMR_POINTS.iter()
+            .zip(cal.imr.iter().zip(cal.result.iter()))
+            .filter(|(_, (_, ok))| **ok)
+            .min_by_key(|(p, _)| p.abs_diff(freq))
+            .map(|(_, (imr, _))| *imr)
+    }
+
+    // r850.c の r850_set_frequency 相当
+    // freq は kHz。先に set_system しておくこと。
+    pub fn set_frequency(&mut self, freq: u32) -> Result<(), TunerError>
+    {
+        let sys = self.priv_.sys;
+        let params = Self::system_params(&sys)?;
+
+        // ミキサーモード
+        self.priv_.mixer_mode = if freq < R850_MIXER_MODE_BOUNDARY { 1 } else { 0 };
+        let lo_freq = if self.priv_.mixer_mode != 0 { freq + sys.if_freq } else { freq.checked_sub(sys.if_freq).ok_or(CtrlMsgError::InvalidArgument)? };
+
+        // LPF は校正済みならその値
+        let lpf = &self.priv_.lpf_cal[sys.bandwidth.index()];
+        let lpf_code = if lpf.done { lpf.code } else { params.lpf_code };
+
+        let imr = self.imr_for(freq);
+
+        {
+            let r = &mut self.priv_.regs;
+
+            r[0x0d] = (r[0x0d] & 0x0f) | (params.lna_top << 4);
+            r[0x12] = (params.hpf_cor << 4) | (lpf_code & 0x0f);
+            r[0x13] = (r[0x13] & 0xfc) | (params.filter_ext & 0x03);
+            r[0x1a] = (r[0x1a] & 0xf0) | (params.rf_top & 0x0f);
+            r[0x27] = (r[0x27] & 0xcf) | ((params.agc_clk & 0x03) << 4);
+
+            r[0x2c] = (r[0x2c] & 0x0f) | (params.mixer_top << 4);
+
+            // イメージ除去側の切り替え
+            if self.priv_.mixer_mode != 0 { r[0x0b] |= 0x01; }
+            else { r[0x0b] &= 0xfe; }
+
+            if let Some(imr) = imr
+            {
+                r[0x14] = (r[0x14] & 0xc0) | (imr.gain & 0x3f);
+                r[0x15] = (imr.phase & 0x3f) | ((imr.iqcap & 0x03) << 6);
+            }
+        }
+
+        self.write_cached_regs(0x08, R850_NUM_REGS - 0x08)?;
+
+        // PLL
+        let mut locked = self.set_pll(lo_freq)?;
+        for _ in 0..R850_PLL_LOCK_RETRY
+        {
+            if locked
+            {
+                break;
+            }
+            thread::sleep(R850_PLL_LOCK_RETRY_INTERVAL);
+            locked = self.set_pll(lo_freq)?;
+        }
+
+        if !locked
+        {
+            return Err(TunerError::PllLockTimeout);
+        }
+
+        self.priv_.sleep = false;
+        self.priv_.sys_curr = sys;
+
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::it930x_sim::{SimBus, SimTc90522, SimTuner, SimTunerType};
+
+    fn r850_sim() -> (SimTuner, IT930x<SimBus>)
+    {
+        let sim = SimBus::new_booted();
+        let demod = SimTc90522::new();
+        let tuner = SimTuner::new(SimTunerType::R850);
+        demod.attach_tuner(tuner.clone());
+        sim.attach_i2c_slave(2, 0x12, demod);
+        (tuner, IT930x::new(sim))
+    }
+
+    // gain = +3, phase = -2, iqcap = 1 でイメージが一番小さくなる
+    fn imr_adc(regs: &[u8]) -> u8
+    {
+        let gain = imr_decode(regs[0x14] & 0x3f) as i32;
+        let phase = imr_decode(regs[0x15] & 0x3f) as i32;
+        let iqcap = (regs[0x15] >> 6) as i32;
+        (((gain - 3).abs() + (phase + 2).abs()) * 4 + if iqcap == 1 { 1 } else { 3 }).min(63) as u8
+    }
+
+    #[test]
+    fn imr_calibration_finds_the_minimum()
+    {
+        let (tuner, it930x) = r850_sim();
+        let mut r850 = R850::new(&it930x, 2, 0x12);
+        r850.init().unwrap();
+        let before = r850.priv_.regs;
+
+        tuner.set_adc(imr_adc);
+        r850.calibrate_imr().unwrap();
+
+        for cal in &r850.priv_.imr_cal
+        {
+            assert!(cal.done);
+            assert_eq!(cal.result, [true; 5]);
+            for imr in &cal.imr
+            {
+                assert_eq!((imr.gain, imr.phase, imr.iqcap, imr.value), (0x03, 0x22, 1, 4));
+            }
+        }
+
+        // 校正前の (寝ている) 状態に戻っている
+        assert_eq!(r850.priv_.regs, before);
+        assert_eq!(tuner.reg(0x08) & 0x80, 0x00);
+        assert!(r850.is_sleeping());
+    }
+
+    #[test]
+    fn lpf_calibration_stops_before_the_drop()
+    {
+        let (tuner, it930x) = r850_sim();
+        let mut r850 = R850::new(&it930x, 2, 0x12);
+        r850.init().unwrap();
+
+        // コード 10 から帯域端の信号が落ちる
+        tuner.set_adc(|regs| if (regs[0x12] & 0x0f) < 10 { 40 } else { 30 });
+        assert_eq!(r850.calibrate_lpf(R850Bandwidth::B6M).unwrap(), 9);
+
+        // 少しの揺れ (4回分で 8 未満) は落ちたとみなさない
+        tuner.set_adc(|regs| if (regs[0x12] & 0x0f) < 12 { 40 } else { 39 });
+        assert_eq!(r850.calibrate_lpf(R850Bandwidth::B8M).unwrap(), 15);
+
+        assert_eq!(r850.priv_.lpf_cal[0].code, 9);
+        assert!(!r850.priv_.lpf_cal[1].done);
+    }
+
+    #[test]
+    fn set_frequency_uses_calibration()
+    {
+        let (tuner, it930x) = r850_sim();
+        let mut r850 = R850::new(&it930x, 2, 0x12);
+        r850.init().unwrap();
+
+        tuner.set_adc(imr_adc);
+        r850.calibrate_imr().unwrap();
+        tuner.set_adc(|regs| if (regs[0x12] & 0x0f) < 7 { 40 } else { 20 });
+        r850.calibrate_lpf(R850Bandwidth::B6M).unwrap();
+
+        r850.set_system(&R850SystemConfig { system: R850System::IsdbT, bandwidth: R850Bandwidth::B6M, if_freq: 0 }).unwrap();
+        r850.set_frequency(557143).unwrap();
+
+        assert_eq!(tuner.reg(0x12) & 0x0f, 6);
+        assert_eq!(tuner.reg(0x14) & 0x3f, 0x03);
+        assert_eq!(tuner.reg(0x15), 0x62);
+    }
+
+    #[test]
+    fn calibration_without_pll_lock()
+    {
+        let (tuner, it930x) = r850_sim();
+        let mut r850 = R850::new(&it930x, 2, 0x12);
+        r850.init().unwrap();
+
+        tuner.set_pll_locked(false);
+        r850.calibrate_imr().unwrap();
+        assert!(r850.priv_.imr_cal.iter().all(|c| c.done && c.result == [false; 5]));
+        let code = r850.priv_.lpf_cal[R850Bandwidth::B6M.index()].code;
+        assert_eq!(r850.calibrate_lpf(R850Bandwidth::B6M).unwrap(), code);
+        assert!(!r850.priv_.lpf_cal[R850Bandwidth::B6M.index()].done);
+
+        // 取れた点が無ければ IMR は触らない
+        tuner.set_pll_locked(true);
+        r850.set_system(&R850SystemConfig { system: R850System::IsdbT, bandwidth: R850Bandwidth::B6M, if_freq: 0 }).unwrap();
+        r850.set_frequency(557143).unwrap();
+        assert_eq!(tuner.reg(0x14) & 0x3f, INIT_REGS[0x14] & 0x3f);
+    }
+
+    #[test]
+    fn calibration_cache_round_trip()
+    {
+        let (tuner, it930x) = r850_sim();
+        let mut r850 = R850::new(&it930x, 2, 0x12);
+        r850.init().unwrap();
+        tuner.set_adc(imr_adc);
+        r850.calibrate_imr().unwrap();
+        r850.calibrate_lpf(R850Bandwidth::B7M).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("px4_r850_cal_{}", std::process::id()));
+        let path = R850CalibrationCache::path_for(&dir, "AB/12");
+        assert_eq!(path.file_name().unwrap(), "r850-AB_12.cal");
+
+        let mut cache = R850CalibrationCache::new("AB/12");
+        cache.entries.insert(0x12, r850.calibration());
+        cache.save(&path).unwrap();
+
+        let loaded = R850CalibrationCache::load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.serial, "AB/12");
+        let cal = &loaded.entries[&0x12];
+        assert_eq!(cal.mixer_amp_lpf_imr_cal, R850_MIXER_AMP_LPF_IMR_CAL);
+        assert_eq!(format!("{:?}", cal.imr_cal), format!("{:?}", r850.priv_.imr_cal));
+        assert_eq!(format!("{:?}", cal.lpf_cal), format!("{:?}", r850.priv_.lpf_cal));
+    }
+
+    #[test]
+    fn set_system_rejects_other_systems()
+    {
+        let (_, it930x) = r850_sim();
+        let mut r850 = R850::new(&it930x, 2, 0x12);
+
+        for system in [R850System::DvbT, R850System::DvbC, R850System::Atsc]
+        {
+            let sys = R850SystemConfig { system, bandwidth: R850Bandwidth::B6M, if_freq: 0 };
+            assert!(matches!(r850.set_system(&sys), Err(TunerError::R850UnsupportedSystem(s, _)) if s == system));
+        }
+
+        // ISDB-T でも 6MHz 以外は表に無い
+        let sys = R850SystemConfig { system: R850System::IsdbT, bandwidth: R850Bandwidth::B8M, if_freq: 0 };
+        assert!(matches!(r850.set_system(&sys), Err(TunerError::R850UnsupportedSystem(..))));
+    }
+}
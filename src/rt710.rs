@@ -1,6 +1,8 @@
 // ここからは RT710の話
 
 use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
 use crate::it930x::{CtrlMsgError, I2CCommRequest, I2CRequestType, IT930x};
 use crate::itedtv_bus::BusOps;
@@ -12,50 +14,135 @@ const NUM_REGS: usize = 0x10; // 実際の値に合わせて調整
 
 #[derive(Default, Clone, Copy)]
 struct BandwidthParam {
+    bandwidth: u32,
     coarse: u8,
     fine: u8,
 }
 
-#[derive(Debug, Clone, Copy)]
+// rt710.c の rt710_init_regs / rt720_init_regs / sleep_regs
+const RT710_INIT_REGS: [u8; NUM_REGS] = [
+    0x40, 0x1d, 0x20, 0x10, 0x41, 0x50, 0xed, 0x25,
+    0x07, 0x58, 0x39, 0x64, 0x38, 0xe7, 0x90, 0x35,
+];
+
+const RT720_INIT_REGS: [u8; NUM_REGS] = [
+    0x00, 0x1c, 0x00, 0x10, 0x41, 0x48, 0xda, 0x4b,
+    0x07, 0x58, 0x38, 0x40, 0x37, 0xe7, 0x4c, 0x59,
+];
+
+const SLEEP_REGS: [u8; NUM_REGS] = [
+    0xff, 0x5c, 0x88, 0x30, 0x41, 0xc8, 0xed, 0x25,
+    0x47, 0xfc, 0x48, 0xa2, 0x08, 0x0f, 0xf3, 0x59,
+];
+
+// get_rf_level で使う、ゲインコードごとの累積ゲイン (0.1dB 単位)
+// LNA は reg 0x01 bit6 + reg 0x03 bit3:0 の 5bit、ミキサーは reg 0x03 bit7:4、フィルタ (VGA) は reg 0x02 bit3:0
+const RT710_LNA_GAIN: [u16; 32] = [
+    0, 12, 26, 38, 52, 64, 78, 90,
+    104, 116, 130, 142, 156, 166, 178, 188,
+    200, 210, 220, 230, 240, 248, 258, 266,
+    274, 282, 290, 296, 302, 308, 314, 320,
+];
+const RT710_MIXER_GAIN: [u16; 16] = [
+    0, 10, 20, 30, 41, 52, 62, 72,
+    82, 92, 101, 110, 118, 126, 133, 140,
+];
+const RT710_FILTER_GAIN: [u16; 16] = [
+    0, 8, 16, 24, 32, 40, 48, 56,
+    63, 70, 77, 84, 90, 96, 102, 108,
+];
+
+// ゲインが全部 0 のときの入力レベル (dBm)。LNA の効きが周波数で違うので帯域ごとに持つ
+// 1200MHz 未満 / 1800MHz 未満 / それ以上
+const RT710_RF_LEVEL_BANDS: [u32; 2] = [1200000, 1800000];
+const RT710_RF_LEVEL_BASE: [f64; 3] = [-5.0, -6.5, -8.0];
+
+// rt710.c の bandwidth_params
+// 帯域幅 (kHz) 以下で最初に当たったものを使う
+const BANDWIDTH_PARAMS: [BandwidthParam; 26] = [
+    BandwidthParam { bandwidth: 50000, coarse: 0, fine: 0 },
+    BandwidthParam { bandwidth: 73000, coarse: 0, fine: 1 },
+    BandwidthParam { bandwidth: 96000, coarse: 1, fine: 0 },
+    BandwidthParam { bandwidth: 104000, coarse: 1, fine: 1 },
+    BandwidthParam { bandwidth: 116000, coarse: 2, fine: 0 },
+    BandwidthParam { bandwidth: 126000, coarse: 2, fine: 1 },
+    BandwidthParam { bandwidth: 134000, coarse: 3, fine: 0 },
+    BandwidthParam { bandwidth: 146000, coarse: 3, fine: 1 },
+    BandwidthParam { bandwidth: 158000, coarse: 4, fine: 0 },
+    BandwidthParam { bandwidth: 170000, coarse: 4, fine: 1 },
+    BandwidthParam { bandwidth: 178000, coarse: 5, fine: 0 },
+    BandwidthParam { bandwidth: 190000, coarse: 5, fine: 1 },
+    BandwidthParam { bandwidth: 202000, coarse: 6, fine: 0 },
+    BandwidthParam { bandwidth: 212000, coarse: 6, fine: 1 },
+    BandwidthParam { bandwidth: 218000, coarse: 7, fine: 0 },
+    BandwidthParam { bandwidth: 234000, coarse: 7, fine: 1 },
+    BandwidthParam { bandwidth: 244000, coarse: 9, fine: 1 },
+    BandwidthParam { bandwidth: 246000, coarse: 10, fine: 0 },
+    BandwidthParam { bandwidth: 262000, coarse: 10, fine: 1 },
+    BandwidthParam { bandwidth: 266000, coarse: 11, fine: 0 },
+    BandwidthParam { bandwidth: 282000, coarse: 11, fine: 1 },
+    BandwidthParam { bandwidth: 298000, coarse: 12, fine: 1 },
+    BandwidthParam { bandwidth: 318000, coarse: 13, fine: 1 },
+    BandwidthParam { bandwidth: 340000, coarse: 14, fine: 1 },
+    BandwidthParam { bandwidth: 358000, coarse: 15, fine: 1 },
+    BandwidthParam { bandwidth: 379999, coarse: 16, fine: 1 },
+];
+
+// 表の最後を超えた場合に coarse を 1 段上げる幅
+const BANDWIDTH_STEP_OVER: u32 = 17400;
+
+// VCO の発振範囲 (kHz)
+const VCO_MIN: u32 = 2350000;
+const VCO_MAX: u32 = VCO_MIN * 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RT710ChipType
 {
-    UNKNOWN = 0,
+    // init でチップを読むまで
+    Unknown = 0,
     RT710,
     RT720,
 }
 
-#[derive(Clone, Copy)]
+// 以下の設定値は rt710.h の enum をそのまま持っている。PX4 の設定 (Default) で使わない値もある
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
 pub enum SignalOutputMode {
     Single = 0,
     Differential,
 }
 
-#[derive(Clone, Copy)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
 pub enum AgcMode {
     Negative = 0,
     Positive,
 }
 
-#[derive(Clone, Copy)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
 pub enum VgaAttenuateMode {
     Off = 0,
     On,
 }
 
-#[derive(Clone, Copy)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
 pub enum FineGain {
-    FineGain3DB = 0,
-    FineGain2DB,
-    FineGain1DB,
-    FineGain0DB,
+    Gain3dB = 0,
+    Gain2dB,
+    Gain1dB,
+    Gain0dB,
 }
 
-#[derive(Clone, Copy)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
 pub enum ScanMode {
     Manual = 0,
     Auto,
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct RT710Config
 {
     pub xtal: u32,
@@ -65,9 +152,30 @@ pub struct RT710Config
     pub agc_mode: AgcMode,
     pub vga_atten_mode: VgaAttenuateMode,
     pub fine_gain: FineGain,
+    // C の設定にはあるが、set_params ではまだ見ていない
+    #[allow(dead_code)]
     pub scan_mode: ScanMode,
 }
 
+// px4_device.c の 1134〜1144行目
+impl Default for RT710Config
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            xtal: 24000,
+            loop_through: false,
+            clock_out: false,
+            signal_output_mode: SignalOutputMode::Differential,
+            agc_mode: AgcMode::Positive,
+            vga_atten_mode: VgaAttenuateMode::Off,
+            fine_gain: FineGain::Gain3dB,
+            scan_mode: ScanMode::Manual,
+        }
+    }
+}
+
 pub struct  RT710Priv
 {
     lock: Mutex<()>,
@@ -81,7 +189,8 @@ pub struct RT710<'a, B: BusOps>
     tc90522: TC90522<'a, B>,
     //pub i2c_bus: u8,
     pub i2c_addr: u8,
-    config: RT710Config,
+    // 機種によって変えるときは init の前に書き換える
+    pub config: RT710Config,
     priv_: RT710Priv,
 }
 
@@ -98,7 +207,7 @@ impl<'a, B: BusOps> RT710<'a, B>
 
     pub fn read_regs(&self, reg: u8, buf: &mut [u8]) -> Result<(), CtrlMsgError>
     {
-        if (buf.len() == 0) || (buf.len() > NUM_REGS - reg as usize)
+        if buf.is_empty() || (buf.len() > NUM_REGS - reg as usize)
         {
             return Err(CtrlMsgError::InvalidLength);
         }
@@ -136,7 +245,7 @@ impl<'a, B: BusOps> RT710<'a, B>
 
     pub fn write_regs(&self, reg: u8, buf: &[u8]) -> Result<(), CtrlMsgError>
     {
-        if (buf.len() == 0) || (buf.len() > (NUM_REGS - reg as usize))
+        if buf.is_empty() || (buf.len() > (NUM_REGS - reg as usize))
         {
             return Err(CtrlMsgError::InvalidLength);
         }
@@ -165,10 +274,281 @@ impl<'a, B: BusOps> RT710<'a, B>
             tc90522: TC90522::new(it930x, tc90522_bus, tc90522_addr), 
             i2c_addr: 0x7a, // 決まっているので 
             //i2c_addr: 0x3d, // bit数が違うらしい？
-            // px4_device.c の 1134〜1144行目
-            config: RT710Config { xtal: 24000, loop_through: false, clock_out: false, signal_output_mode: SignalOutputMode::Differential, agc_mode: AgcMode::Positive, vga_atten_mode: VgaAttenuateMode::Off, fine_gain: FineGain::FineGain3DB, scan_mode: ScanMode::Manual, },
-            priv_: RT710Priv { lock: Mutex::new(()), init: false, freq: 0, chip: RT710ChipType::RT710, }
+            config: RT710Config::default(),
+            priv_: RT710Priv { lock: Mutex::new(()), init: false, freq: 0, chip: RT710ChipType::Unknown, }
+        }
+    }
+
+    // config から、init_regs に反映する部分
+    // rt710_set_params と rt710_sleep で共通
+    fn apply_config(&self, regs: &mut [u8; NUM_REGS])
+    {
+        if self.config.loop_through { regs[0x01] &= 0xfb; }
+        else { regs[0x01] |= 0x04; }
+
+        if self.config.clock_out { regs[0x03] &= 0x7f; }
+        else { regs[0x03] |= 0x80; }
+    }
+
+    // rt710.c の rt710_set_pll の移植
+    // freq は kHz
+    fn set_pll(&self, regs: &mut [u8; NUM_REGS], freq: u32) -> Result<(), CtrlMsgError>
+    {
+        let xtal = self.config.xtal;
+
+        // VCO が範囲に収まるまで分周比を上げる
+        let mut mix_div: u32 = 2;
+        let mut vco_freq = freq * mix_div;
+        while mix_div <= 16
+        {
+            if (VCO_MIN..=VCO_MAX).contains(&vco_freq)
+            {
+                break;
+            }
+            mix_div *= 2;
+            vco_freq = freq * mix_div;
+        }
+
+        let div_num: u8 = match mix_div
+        {
+            2 => 1,
+            4 => 0,
+            8 => 2,
+            16 => 3,
+            _ => 0,
+        };
+
+        regs[0x04] &= 0xfe;
+        regs[0x04] |= div_num & 0x01;
+        self.write_regs(0x04, &regs[0x04..0x05])?;
+
+        if self.priv_.chip == RT710ChipType::RT720
+        {
+            regs[0x08] &= 0xef;
+            regs[0x08] |= ((div_num & 0x02) >> 1) << 4;
+            self.write_regs(0x08, &regs[0x08..0x09])?;
+        }
+
+        let mut nint = (vco_freq / 2) / xtal;
+        if nint < 13
+        {
+            return Err(CtrlMsgError::InvalidArgument);
+        }
+
+        let mut vco_fra = vco_freq - (xtal * 2 * nint);
+
+        // 境界スプリアス対策
+        if vco_fra < xtal / 64
+        {
+            vco_fra = 0;
+        }
+        else if vco_fra > xtal * 127 / 64
+        {
+            vco_fra = 0;
+            nint += 1;
+        }
+        else if vco_fra > xtal * 127 / 128 && vco_fra < xtal
+        {
+            vco_fra = xtal * 127 / 128;
+        }
+        else if vco_fra > xtal && vco_fra < xtal * 129 / 128
+        {
+            vco_fra = xtal * 129 / 128;
+        }
+
+        let ni = (nint - 13) / 4;
+        let si = nint - 13 - (ni * 4);
+        regs[0x05] = (ni + (si << 6)) as u8;
+        self.write_regs(0x05, &regs[0x05..0x06])?;
+
+        if vco_fra == 0 { regs[0x04] |= 0x02; }
+        else { regs[0x04] &= 0xfd; }
+        self.write_regs(0x04, &regs[0x04..0x05])?;
+
+        // SDM の計算
+        let mut nsdm: u32 = 2;
+        let mut sdm: u32 = 0;
+        while vco_fra > 1
+        {
+            let t = (xtal * 2) / nsdm;
+            if vco_fra > t
+            {
+                sdm += 0x8000 / (nsdm / 2);
+                vco_fra -= t;
+                if nsdm >= 0x8000
+                {
+                    break;
+                }
+            }
+            nsdm *= 2;
+        }
+
+        regs[0x07] = ((sdm >> 8) & 0xff) as u8;
+        regs[0x06] = (sdm & 0xff) as u8;
+        self.write_regs(0x06, &regs[0x06..0x08])?;
+
+        Ok(())
+    }
+
+    // rt710.c の rt710_set_params の移植
+    // freq: kHz, symbol_rate: ksps, rolloff: 0〜5 (0.1 + 0.05 * rolloff → 4 で 0.35)
+    pub fn set_params(&mut self, freq: u32, symbol_rate: u32, rolloff: u32) -> Result<(), TunerError>
+    {
+        // 衛星の IF (950〜2150MHz) の範囲外は受け付けない
+        if rolloff > 5 || !(950000..=2150000).contains(&freq)
+        {
+            return Err(TunerError::CtrlMsg(CtrlMsgError::InvalidArgument));
+        }
+
+        let _lock = self.priv_.lock.lock().unwrap();
+
+        let mut regs = if self.priv_.chip == RT710ChipType::RT720 { RT720_INIT_REGS } else { RT710_INIT_REGS };
+        self.apply_config(&mut regs);
+
+        match self.config.signal_output_mode
+        {
+            SignalOutputMode::Differential => regs[0x0b] &= 0xef,
+            SignalOutputMode::Single => regs[0x0b] |= 0x10,
         }
+
+        match self.config.agc_mode
+        {
+            AgcMode::Positive => regs[0x0d] |= 0x10,
+            AgcMode::Negative => regs[0x0d] &= 0xef,
+        }
+
+        match self.config.vga_atten_mode
+        {
+            VgaAttenuateMode::On => regs[0x0b] |= 0x08,
+            VgaAttenuateMode::Off => regs[0x0b] &= 0xf7,
+        }
+
+        regs[0x0e] &= 0xfc;
+        regs[0x0e] |= self.config.fine_gain as u8;
+
+        self.write_regs(0x00, &regs)?;
+
+        self.set_pll(&mut regs, freq)?;
+
+        thread::sleep(Duration::from_millis(10));
+
+        // 帯域ごとの LNA/ミキサーの切り替え
+        if freq.wrapping_sub(1600000) >= 350000
+        {
+            regs[0x02] &= 0xbf;
+            regs[0x08] &= 0x7f;
+            if freq >= 1950000
+            {
+                regs[0x0a] = 0x38;
+            }
+        }
+        else
+        {
+            regs[0x02] |= 0x40;
+            regs[0x08] |= 0x80;
+        }
+
+        self.write_regs(0x0a, &regs[0x0a..0x0b])?;
+        self.write_regs(0x02, &regs[0x02..0x03])?;
+        self.write_regs(0x08, &regs[0x08..0x09])?;
+
+        regs[0x0e] &= 0xf3;
+        if freq >= 2000000
+        {
+            regs[0x0e] |= 0x08;
+        }
+        self.write_regs(0x0e, &regs[0x0e..0x0f])?;
+
+        // 帯域幅 (kHz)
+        let bandwidth = (symbol_rate * (115 + rolloff * 5)) / 10;
+
+        let param = match BANDWIDTH_PARAMS.iter().find(|p| bandwidth <= p.bandwidth)
+        {
+            Some(p) => *p,
+            None =>
+            {
+                // 表を超えた分は coarse を上げて対応する
+                let last = BANDWIDTH_PARAMS[BANDWIDTH_PARAMS.len() - 1];
+                let over = (bandwidth - last.bandwidth).div_ceil(BANDWIDTH_STEP_OVER);
+                BandwidthParam { bandwidth, coarse: (last.coarse as u32 + over).min(0x3f) as u8, fine: 1 }
+            }
+        };
+
+        regs[0x0f] = (param.coarse << 2) | (param.fine << 1) | (regs[0x0f] & 0x01);
+        self.write_regs(0x0f, &regs[0x0f..0x10])?;
+
+        self.priv_.freq = freq;
+
+        Ok(())
+    }
+
+    // rt710.c の rt710_get_pll_locked の移植
+    pub fn get_pll_locked(&self) -> Result<bool, CtrlMsgError>
+    {
+        let mut tmp = [0u8; 1];
+        self.read_regs(0x02, &mut tmp)?;
+
+        Ok((tmp[0] & 0x80) != 0)
+    }
+
+    // PLL がロックするまで待つ。timeout を過ぎたら false
+    pub fn wait_pll_lock(&self, timeout: Duration) -> Result<bool, CtrlMsgError>
+    {
+        let interval = Duration::from_millis(10);
+        let mut waited = Duration::ZERO;
+
+        loop
+        {
+            if self.get_pll_locked()?
+            {
+                return Ok(true);
+            }
+
+            if waited >= timeout
+            {
+                return Ok(false);
+            }
+
+            thread::sleep(interval);
+            waited += interval;
+        }
+    }
+
+    // rt710.c の rt710_get_rf_signal_strength 相当 (dBm)
+    // reg 0x00〜0x03 を読み戻して LNA・ミキサー・フィルタのゲインコードを取り出し、
+    // 表で dB に直して合計したものを、選局中の帯域の基準値から引く。選局前は InvalidArgument
+    pub fn get_rf_level(&self) -> Result<f64, CtrlMsgError>
+    {
+        let freq = self.priv_.freq;
+        if freq == 0
+        {
+            return Err(CtrlMsgError::InvalidArgument);
+        }
+
+        let mut tmp = [0u8; 4];
+        self.read_regs(0x00, &mut tmp)?;
+
+        let lna = (((tmp[1] & 0x40) >> 2) | (tmp[3] & 0x0f)) as usize;
+        let mixer = ((tmp[3] & 0xf0) >> 4) as usize;
+        let filter = (tmp[2] & 0x0f) as usize;
+        let gain = RT710_LNA_GAIN[lna] + RT710_MIXER_GAIN[mixer] + RT710_FILTER_GAIN[filter];
+
+        let band = RT710_RF_LEVEL_BANDS.iter().position(|&f| freq < f).unwrap_or(RT710_RF_LEVEL_BANDS.len());
+
+        Ok(RT710_RF_LEVEL_BASE[band] - gain as f64 / 10.0)
+    }
+
+    // rt710.c の rt710_sleep の移植
+    pub fn sleep(&mut self) -> Result<(), TunerError>
+    {
+        let _lock = self.priv_.lock.lock().unwrap();
+
+        let mut regs = SLEEP_REGS;
+        self.apply_config(&mut regs);
+        self.write_regs(0x00, &regs)?;
+
+        self.priv_.freq = 0;
+        Ok(())
     }
 
     pub fn init(&mut self) -> Result<(), TunerError>
@@ -179,6 +559,7 @@ impl<'a, B: BusOps> RT710<'a, B>
 
             self.priv_.init = false;
             self.priv_.freq = 0;
+            self.priv_.chip = RT710ChipType::Unknown;
 
             self.read_regs(0x03, &mut tmp)?;
 
@@ -195,8 +576,6 @@ impl<'a, B: BusOps> RT710<'a, B>
             self.priv_.init = true;
         }
 
-        // いらないのでは？
-        println!("RT710 init done. chip: {:?}, reg03=0x{:02x}", self.priv_.chip, tmp[0]);
         Ok(())
     }
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
 // TC90522 の制御用
 
-use std::sync::{Mutex, atomic::{AtomicU8, Ordering}};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 // 多分、これで大丈夫だと思う。
 use crate::{it930x::IT930x, itedtv_bus::BusOps};
@@ -8,14 +10,6 @@ use crate::{it930x::IT930x, itedtv_bus::BusOps};
 // 同じ定義を使うだけ
 use crate::it930x::{I2CRequestType, I2CCommRequest, CtrlMsgError};
 
-// いらないのでは？
-#[derive(Clone, Copy, Debug)]
-pub struct I2CAddr(pub u8);
-
-#[derive(Clone, Copy, Debug)]
-pub struct Reg(pub u8);
-
-
 pub struct TC90522<'a, B: BusOps>
 {
     it930x: &'a IT930x<B>,
@@ -28,27 +22,18 @@ pub struct TC90522<'a, B: BusOps>
 
     // 内部の排他制御用
     lock: Mutex<()>,
-
-    // cにあるらしいので、一旦保持
-    is_secondary: bool,
 }
 
 impl<'a, B: BusOps> TC90522<'a, B>
 {
-    pub fn new(it930x: &'a IT930x<B>, bus: u8, i2c_addr: u8, is_secondary: bool) -> Self
+    pub fn new(it930x: &'a IT930x<B>, bus: u8, i2c_addr: u8) -> Self
     {
-        println!(
-            "[tc90522.new] bus={} tc90522_addr=0x{:02X}",
-            bus, i2c_addr
-        );
-
         TC90522
         {
             it930x,
             bus,
             i2c_addr,
             lock: Mutex::new(()),
-            is_secondary,
         }
     }
 
@@ -131,27 +116,12 @@ impl<'a, B: BusOps> TC90522<'a, B>
 
         for req in requests.iter_mut()
         {
-            println!(
-                "[tc90522.req] target_addr=0x{:02X} {:?} len={} first={:02X?}",
-                req.addr, req.req, req.data.len(), &req.data.get(..req.data.len().min(8)).unwrap_or(&[])
-            );
-
             match req.req
             {
                 I2CRequestType::Read =>
                 {
                     let mut write_buf = [0xFE, (req.addr << 1) | 0x01];
 
-                    // debug
-                    println!(
-                        "[tc90522->it930x] READ-SET bus={} it930x_addr=0x{:02X} data={:02X?}",
-                        self.bus, self.i2c_addr, &write_buf
-                    );
-                    println!(
-                        "[tc90522->it930x] READ-DATA bus={} it930x_addr=0x{:02X} len={}",
-                        self.bus, self.i2c_addr, req.data.len()
-                    );
-
                     let mut master = 
                     [
                         I2CCommRequest{
@@ -181,12 +151,6 @@ impl<'a, B: BusOps> TC90522<'a, B>
                     buf.push(req.addr << 1);
                     buf.extend_from_slice(req.data);
 
-                    // debug
-                    println!(
-                        "[tc90522->it930x] WRITE bus={} it930x_addr=0x{:02X} data={:02X?}", 
-                        self.bus, self.i2c_addr, &buf
-                    );
-
                     let mut master = [
                         I2CCommRequest{
                             addr: self.i2c_addr,
@@ -256,4 +220,633 @@ impl<'a, B: BusOps> TC90522<'a, B>
 
         self.it930x.i2c_master_request(self.bus, &mut req)
     }
-}
\ No newline at end of file
+}
+
+// ここからは ISDB-S (衛星) 側の復調部の操作
+// 基本的に tc90522.c の *_s 関数と、px4_device.c の px4_chrdev_open / px4_chrdev_set_channel の ISDB-S 部分の移植
+
+// px4_device.c の px4_chrdev_open にある tc_init_s
+const TC90522_INIT_REGS_S: [(u8, u8); 3] =
+[
+    (0x15, 0x00),
+    (0x1d, 0x00),
+    (0x04, 0x02),
+];
+
+// ロック待ちのポーリング間隔
+pub const TC90522_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// TMCC に載る TSID (相対 TS 番号) の数
+pub const TC90522_TMCC_TSID_NUM: usize = 12;
+
+impl<'a, B: BusOps> TC90522<'a, B>
+{
+    // 書き込む値が 1byte ずつのテーブルをまとめて書く
+    fn write_reg_table(&self, table: &[(u8, u8)]) -> Result<(), CtrlMsgError>
+    {
+        let bufs: Vec<[u8; 1]> = table.iter().map(|&(_, v)| [v]).collect();
+        let regs: Vec<(u8, &[u8])> = table.iter().zip(bufs.iter()).map(|(&(r, _), b)| (r, &b[..])).collect();
+
+        self.write_multiple_regs(&regs)
+    }
+
+    // ISDB-S 用の初期設定
+    pub fn init_s(&self) -> Result<(), CtrlMsgError>
+    {
+        self.write_reg_table(&TC90522_INIT_REGS_S)
+    }
+
+    // tc90522.c tc90522_sleep_s の移植
+    pub fn sleep_s(&self, sleep: bool) -> Result<(), CtrlMsgError>
+    {
+        self.write_regs(0x17, &[if sleep { 0x01 } else { 0x00 }])
+    }
+
+    // tc90522.c tc90522_set_agc_s の移植
+    // 最後の 0x03 への書き込みで復調部がリセットされる
+    pub fn set_agc_s(&self, on: bool) -> Result<(), CtrlMsgError>
+    {
+        let table = if on
+        {
+            [(0x0a, 0xff), (0x10, 0xb2), (0x11, 0x00), (0x03, 0x01)]
+        }
+        else
+        {
+            [(0x0a, 0x00), (0x10, 0xb0), (0x11, 0x02), (0x03, 0x01)]
+        };
+
+        self.write_reg_table(&table)
+    }
+
+    // tc90522.c tc90522_is_signal_locked_s の移植
+    // 0xc3 の bit4 が落ちていれば TMCC が取れている
+    pub fn is_signal_locked_s(&self) -> Result<bool, CtrlMsgError>
+    {
+        let mut b = [0u8; 1];
+        self.read_regs(0xc3, &mut b)?;
+
+        Ok((b[0] & 0x10) == 0)
+    }
+
+    // ロックするまで待つ。timeout を過ぎたら false
+    pub fn wait_lock_s(&self, timeout: Duration) -> Result<bool, CtrlMsgError>
+    {
+        let start = Instant::now();
+
+        loop
+        {
+            if self.is_signal_locked_s()?
+            {
+                return Ok(true);
+            }
+
+            if start.elapsed() >= timeout
+            {
+                return Ok(false);
+            }
+
+            thread::sleep(TC90522_LOCK_POLL_INTERVAL);
+        }
+    }
+
+    // tc90522.c tc90522_tmcc_get_tsid_s の移植
+    // TMCC に載っている idx 番目 (相対 TS 番号) の TSID。空きは 0x0000 か 0xffff
+    pub fn tmcc_get_tsid_s(&self, idx: usize) -> Result<u16, CtrlMsgError>
+    {
+        if idx >= TC90522_TMCC_TSID_NUM
+        {
+            return Err(CtrlMsgError::InvalidArgument);
+        }
+
+        let mut b = [0u8; 2];
+        self.read_regs(0xce + (idx as u8 * 2), &mut b)?;
+
+        Ok(u16::from_be_bytes(b))
+    }
+
+    // TMCC の TSID 一覧 (空きも含めて 12個)
+    pub fn tmcc_tsid_list_s(&self) -> Result<[u16; TC90522_TMCC_TSID_NUM], CtrlMsgError>
+    {
+        let mut b = [0u8; TC90522_TMCC_TSID_NUM * 2];
+        self.read_regs(0xce, &mut b)?;
+
+        let mut list = [0u16; TC90522_TMCC_TSID_NUM];
+        for (i, t) in list.iter_mut().enumerate()
+        {
+            *t = u16::from_be_bytes([b[i * 2], b[i * 2 + 1]]);
+        }
+        Ok(list)
+    }
+
+    // tc90522.c tc90522_set_tsid_s の移植
+    pub fn set_tsid_s(&self, tsid: u16) -> Result<(), CtrlMsgError>
+    {
+        let [hi, lo] = tsid.to_be_bytes();
+        self.write_reg_table(&[(0x8f, hi), (0x90, lo)])
+    }
+
+    // tc90522.c tc90522_get_tsid_s の移植
+    // 今出力されている TS の TSID (set_tsid_s が効いたかの確認に使う)
+    pub fn get_tsid_s(&self) -> Result<u16, CtrlMsgError>
+    {
+        let mut b = [0u8; 2];
+        self.read_regs(0xe6, &mut b)?;
+
+        Ok(u16::from_be_bytes(b))
+    }
+
+    // tc90522.c tc90522_get_cndat_s の移植
+    pub fn get_cndat_s(&self) -> Result<u16, CtrlMsgError>
+    {
+        let mut b = [0u8; 2];
+        self.read_regs(0xbc, &mut b)?;
+
+        Ok(u16::from_be_bytes(b))
+    }
+}
+
+// ISDB-S の誤り測定 (ビタビ後 / RS 前の BER と、RS で直せなかったパケット数)
+// px4_drv の tc90522.c には無いので、レジスタの位置はここにまとめておく
+const TC90522_BER_RESET_REG_S: u8 = 0xe3;
+const TC90522_BER_PERIOD_REG_S: u8 = 0xe4;
+const TC90522_BER_COUNT_REG_S: u8 = 0xeb;
+const TC90522_PER_COUNT_REG_S: u8 = 0xee;
+
+impl<'a, B: BusOps> TC90522<'a, B>
+{
+    // 何パケットごとに測るか (0 は 1 として扱う)
+    pub fn set_ber_period_s(&self, packets: u16) -> Result<(), CtrlMsgError>
+    {
+        self.write_regs(TC90522_BER_PERIOD_REG_S, &packets.max(1).to_be_bytes())
+    }
+
+    pub fn get_ber_period_s(&self) -> Result<u16, CtrlMsgError>
+    {
+        let mut b = [0u8; 2];
+        self.read_regs(TC90522_BER_PERIOD_REG_S, &mut b)?;
+
+        Ok(u16::from_be_bytes(b).max(1))
+    }
+
+    // カウンタを 0 に戻して測り直す
+    pub fn reset_ber_s(&self) -> Result<(), CtrlMsgError>
+    {
+        self.write_regs(TC90522_BER_RESET_REG_S, &[0x01])
+    }
+
+    // 直近の測定期間の結果
+    pub fn get_error_counters_s(&self) -> Result<ErrorCounters, CtrlMsgError>
+    {
+        let packets = self.get_ber_period_s()? as u32;
+
+        let mut ber = [0u8; 3];
+        let mut per = [0u8; 2];
+        self.read_multiple_regs(&mut [(TC90522_BER_COUNT_REG_S, &mut ber[..]), (TC90522_PER_COUNT_REG_S, &mut per[..])])?;
+
+        Ok(ErrorCounters
+        {
+            bit_errors: ((ber[0] as u32) << 16) | ((ber[1] as u32) << 8) | ber[2] as u32,
+            bits: packets * TC90522_RS_PACKET_BITS,
+            packet_errors: u16::from_be_bytes(per) as u32,
+            packets,
+        })
+    }
+}
+
+// RS 符号化された TS パケット (204 byte) のビット数
+// BER は RS で直す前のものなので、これを分母にする
+const TC90522_RS_PACKET_BITS: u32 = 204 * 8;
+
+// 誤り測定の結果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorCounters
+{
+    // ビタビ後 / RS 前の誤りビット数と、測ったビット数
+    pub bit_errors: u32,
+    pub bits: u32,
+    // RS で直せなかったパケット数と、測ったパケット数
+    pub packet_errors: u32,
+    pub packets: u32,
+}
+
+impl ErrorCounters
+{
+    pub fn ber(&self) -> f64
+    {
+        if self.bits == 0 { 0.0 } else { self.bit_errors as f64 / self.bits as f64 }
+    }
+
+    pub fn per(&self) -> f64
+    {
+        if self.packets == 0 { 0.0 } else { self.packet_errors as f64 / self.packets as f64 }
+    }
+
+    // ISDB-T の階層をまとめるとき用
+    pub fn merge(&self, other: &ErrorCounters) -> ErrorCounters
+    {
+        ErrorCounters
+        {
+            bit_errors: self.bit_errors + other.bit_errors,
+            bits: self.bits + other.bits,
+            packet_errors: self.packet_errors + other.packet_errors,
+            packets: self.packets + other.packets,
+        }
+    }
+}
+
+// TMCC の TSID が有効な値か
+pub fn is_valid_tsid(tsid: u16) -> bool
+{
+    tsid != 0x0000 && tsid != 0xffff
+}
+
+
+// px4_device.c の px4_chrdev_get_cnr の ISDB-S の式
+// P = sqrt(cndat - 3000) / 64
+// C/N = -1.6346P^5 + 14.341P^4 - 50.259P^3 + 88.977P^2 - 89.565P + 58.857 (dB)
+pub fn cnr_from_cndat_s(cndat: u16) -> f64
+{
+    if cndat < 3000
+    {
+        return 0.0;
+    }
+
+    let p = ((cndat - 3000) as f64).sqrt() / 64.0;
+    let cnr = (((( -1.6346 * p + 14.341) * p - 50.259) * p + 88.977) * p - 89.565) * p + 58.857;
+
+    cnr.max(0.0)
+}
+
+// px4_device.c の px4_chrdev_get_cnr の ISDB-T の式
+// P = 10 * log10(5505024 / cndat)
+// C/N = 0.000024P^4 - 0.0016P^3 + 0.0398P^2 + 0.5491P + 3.0965 (dB)
+pub fn cnr_from_cndat_t(cndat: u32) -> f64
+{
+    if cndat == 0
+    {
+        return 0.0;
+    }
+
+    let p = 10.0 * (5505024.0 / cndat as f64).log10();
+    let cnr = (((0.000024 * p - 0.0016) * p + 0.0398) * p + 0.5491) * p + 3.0965;
+
+    cnr.max(0.0)
+}
+
+
+// ここからは ISDB-T (地上) 側の復調部の操作
+// tc90522.c の *_t 関数と、px4_device.c の px4_chrdev_open の ISDB-T 部分の移植
+
+// px4_device.c の px4_chrdev_open にある tc_init_t
+const TC90522_INIT_REGS_T: [(u8, u8); 8] =
+[
+    (0xb0, 0xa0),
+    (0xb2, 0x3d),
+    (0xb3, 0x25),
+    (0xb4, 0x8b),
+    (0xb5, 0x4b),
+    (0xb6, 0x3f),
+    (0xb7, 0xff),
+    (0xb8, 0xc0),
+];
+
+impl<'a, B: BusOps> TC90522<'a, B>
+{
+    // ISDB-T 用の初期設定
+    pub fn init_t(&self) -> Result<(), CtrlMsgError>
+    {
+        self.write_reg_table(&TC90522_INIT_REGS_T)
+    }
+
+    // tc90522.c tc90522_sleep_t の移植
+    pub fn sleep_t(&self, sleep: bool) -> Result<(), CtrlMsgError>
+    {
+        self.write_regs(0x03, &[if sleep { 0x90 } else { 0x80 }])
+    }
+
+    // tc90522.c tc90522_set_agc_t の移植
+    // 最後の 0x01 への書き込みで復調部がリセットされる
+    pub fn set_agc_t(&self, on: bool) -> Result<(), CtrlMsgError>
+    {
+        let table = if on
+        {
+            [(0x25, 0x00), (0x23, 0x4c), (0x01, 0x50)]
+        }
+        else
+        {
+            [(0x25, 0x00), (0x23, 0x4d), (0x01, 0x50)]
+        };
+
+        self.write_reg_table(&table)
+    }
+
+    // tc90522.c tc90522_is_signal_locked_t の移植
+    // 0x80 の bit3/bit5 (同期外れ) が落ちていて、0xb0 の下位 4bit (同期シーケンス) が 8 以上ならロック
+    pub fn is_signal_locked_t(&self) -> Result<bool, CtrlMsgError>
+    {
+        let mut b = [0u8; 1];
+
+        self.read_regs(0x80, &mut b)?;
+        if (b[0] & 0x28) != 0
+        {
+            return Ok(false);
+        }
+
+        self.read_regs(0xb0, &mut b)?;
+        if (b[0] & 0x0f) < 8
+        {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    // ロックするまで待つ。timeout を過ぎたら false
+    pub fn wait_lock_t(&self, timeout: Duration) -> Result<bool, CtrlMsgError>
+    {
+        let start = Instant::now();
+
+        loop
+        {
+            if self.is_signal_locked_t()?
+            {
+                return Ok(true);
+            }
+
+            if start.elapsed() >= timeout
+            {
+                return Ok(false);
+            }
+
+            thread::sleep(TC90522_LOCK_POLL_INTERVAL);
+        }
+    }
+
+    // TMCC (現在の伝送パラメータ) の読み出し
+    // ロックしてから呼ぶこと。ロックしていないときの値は意味が無い。
+    pub fn get_tmcc_t(&self) -> Result<TmccInfo, CtrlMsgError>
+    {
+        let mut b = [0u8; 6];
+        self.read_regs(0xb2, &mut b)?;
+
+        Ok(TmccInfo::from_regs(&b))
+    }
+
+    // tc90522.c tc90522_get_cndat_t の移植
+    pub fn get_cndat_t(&self) -> Result<u32, CtrlMsgError>
+    {
+        let mut b = [0u8; 3];
+        self.read_regs(0x8b, &mut b)?;
+
+        Ok(((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32)
+    }
+}
+
+
+// ISDB-T の誤り測定 (A/B/C 階層ごと)
+// ISDB-S と同じく、レジスタの位置はここにまとめておく
+const TC90522_BER_RESET_REG_T: u8 = 0x75;
+const TC90522_BER_PERIOD_REG_T: u8 = 0x76;
+// 階層 A から 3byte ずつ
+const TC90522_BER_COUNT_REG_T: u8 = 0x9d;
+// 階層 A から 2byte ずつ
+const TC90522_PER_COUNT_REG_T: u8 = 0xa6;
+
+pub const TC90522_LAYER_NUM: usize = 3;
+
+impl<'a, B: BusOps> TC90522<'a, B>
+{
+    // 何パケットごとに測るか (全階層共通、0 は 1 として扱う)
+    pub fn set_ber_period_t(&self, packets: u16) -> Result<(), CtrlMsgError>
+    {
+        self.write_regs(TC90522_BER_PERIOD_REG_T, &packets.max(1).to_be_bytes())
+    }
+
+    pub fn get_ber_period_t(&self) -> Result<u16, CtrlMsgError>
+    {
+        let mut b = [0u8; 2];
+        self.read_regs(TC90522_BER_PERIOD_REG_T, &mut b)?;
+
+        Ok(u16::from_be_bytes(b).max(1))
+    }
+
+    pub fn reset_ber_t(&self) -> Result<(), CtrlMsgError>
+    {
+        self.write_regs(TC90522_BER_RESET_REG_T, &[0x01])
+    }
+
+    // 階層ごとの直近の測定結果 (0: A, 1: B, 2: C)
+    // 使っていない階層は 0 のまま
+    pub fn get_error_counters_t(&self) -> Result<[ErrorCounters; TC90522_LAYER_NUM], CtrlMsgError>
+    {
+        let packets = self.get_ber_period_t()? as u32;
+
+        let mut ber = [0u8; 3 * TC90522_LAYER_NUM];
+        let mut per = [0u8; 2 * TC90522_LAYER_NUM];
+        self.read_multiple_regs(&mut [(TC90522_BER_COUNT_REG_T, &mut ber[..]), (TC90522_PER_COUNT_REG_T, &mut per[..])])?;
+
+        let mut counters = [ErrorCounters::default(); TC90522_LAYER_NUM];
+        for (i, c) in counters.iter_mut().enumerate()
+        {
+            let b = &ber[i * 3..i * 3 + 3];
+            *c = ErrorCounters
+            {
+                bit_errors: ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32,
+                bits: packets * TC90522_RS_PACKET_BITS,
+                packet_errors: u16::from_be_bytes([per[i * 2], per[i * 2 + 1]]) as u32,
+                packets,
+            };
+        }
+
+        Ok(counters)
+    }
+}
+
+// ISDB-T の TMCC
+// 0xb2 から 6byte に、ARIB STD-B31 の TMCC 情報 (B20〜) が先頭から順に詰まっている。
+//   B20-21 システム識別 / B22-25 切替指標 / B26 緊急警報放送 / B27 部分受信
+//   以降、A/B/C 階層ごとに キャリア変調(3) 畳込み符号化率(3) 時間インターリーブ長(3) セグメント数(4)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TmccModulation
+{
+    Dqpsk,
+    Qpsk,
+    Qam16,
+    Qam64,
+    Unknown(u8),
+}
+
+impl TmccModulation
+{
+    fn from_code(v: u8) -> Self
+    {
+        match v
+        {
+            0 => TmccModulation::Dqpsk,
+            1 => TmccModulation::Qpsk,
+            2 => TmccModulation::Qam16,
+            3 => TmccModulation::Qam64,
+            _ => TmccModulation::Unknown(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TmccCodeRate
+{
+    R1_2,
+    R2_3,
+    R3_4,
+    R5_6,
+    R7_8,
+    Unknown(u8),
+}
+
+impl TmccCodeRate
+{
+    fn from_code(v: u8) -> Self
+    {
+        match v
+        {
+            0 => TmccCodeRate::R1_2,
+            1 => TmccCodeRate::R2_3,
+            2 => TmccCodeRate::R3_4,
+            3 => TmccCodeRate::R5_6,
+            4 => TmccCodeRate::R7_8,
+            _ => TmccCodeRate::Unknown(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TmccSystem
+{
+    Television,
+    Sound,
+    Unknown(u8),
+}
+
+// 1階層分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TmccLayer
+{
+    pub modulation: TmccModulation,
+    pub code_rate: TmccCodeRate,
+    // 時間インターリーブ長のコード (0〜3)。実際の長さはモードによって変わる (モード3 なら 0, 1, 2, 4)
+    pub time_interleave: u8,
+    pub segments: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TmccInfo
+{
+    pub system: TmccSystem,
+    // 緊急警報放送 (EWS) の起動フラグ
+    pub emergency: bool,
+    // 部分受信 (ワンセグ) があるか
+    pub partial_reception: bool,
+    // 使っていない階層は None (セグメント数が 0xf)
+    pub layers: [Option<TmccLayer>; 3],
+}
+
+impl TmccInfo
+{
+    // b は 0xb2 からの 6byte
+    pub fn from_regs(b: &[u8; 6]) -> Self
+    {
+        // 0xb3 から先をビット列として順に読む
+        let bits = b[1..].iter().fold(0u64, |acc, v| (acc << 8) | *v as u64);
+        let field = |pos: u32, len: u32| -> u8 { ((bits >> (40 - pos - len)) & ((1 << len) - 1)) as u8 };
+
+        let mut layers = [None; 3];
+        for (i, layer) in layers.iter_mut().enumerate()
+        {
+            let base = i as u32 * 13;
+            let segments = field(base + 9, 4);
+            if segments == 0x0f
+            {
+                continue;
+            }
+
+            *layer = Some(TmccLayer
+            {
+                modulation: TmccModulation::from_code(field(base, 3)),
+                code_rate: TmccCodeRate::from_code(field(base + 3, 3)),
+                time_interleave: field(base + 6, 3),
+                segments,
+            });
+        }
+
+        let system = match b[0] >> 6
+        {
+            0 => TmccSystem::Television,
+            1 => TmccSystem::Sound,
+            v => TmccSystem::Unknown(v),
+        };
+
+        TmccInfo { system, emergency: (b[0] & 0x02) != 0, partial_reception: (b[0] & 0x01) != 0, layers }
+    }
+
+    // ワンセグ (A 階層 1セグメント) だけ受かっているような状態の確認用
+    pub fn total_segments(&self) -> u8
+    {
+        self.layers.iter().flatten().map(|l| l.segments).sum()
+    }
+}
+
+// 選局したときの表示用 (例: "Television, 13 segments, partial reception, A: Qpsk R2_3 TI 2 1seg, B: Qam64 R3_4 TI 2 12seg")
+impl std::fmt::Display for TmccInfo
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "{:?}, {} segments", self.system, self.total_segments())?;
+        if self.partial_reception
+        {
+            write!(f, ", partial reception")?;
+        }
+        if self.emergency
+        {
+            write!(f, ", EWS")?;
+        }
+
+        for (name, layer) in ['A', 'B', 'C'].iter().zip(&self.layers)
+        {
+            if let Some(l) = layer
+            {
+                write!(f, ", {}: {:?} {:?} TI {} {}seg", name, l.modulation, l.code_rate, l.time_interleave, l.segments)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // A 階層がワンセグ (QPSK 2/3)、B 階層が 12セグ (64QAM 3/4)、C 階層は未使用
+    const TMCC_REGS: [u8; 6] = [0x01, 0x25, 0x0b, 0x4b, 0x3f, 0xfe];
+
+    #[test]
+    fn tmcc_layers_from_regs()
+    {
+        let tmcc = TmccInfo::from_regs(&TMCC_REGS);
+
+        assert_eq!(tmcc.system, TmccSystem::Television);
+        assert!(tmcc.partial_reception);
+        assert!(!tmcc.emergency);
+        assert_eq!(tmcc.layers[0], Some(TmccLayer { modulation: TmccModulation::Qpsk, code_rate: TmccCodeRate::R2_3, time_interleave: 2, segments: 1 }));
+        assert_eq!(tmcc.layers[1], Some(TmccLayer { modulation: TmccModulation::Qam64, code_rate: TmccCodeRate::R3_4, time_interleave: 2, segments: 12 }));
+        assert_eq!(tmcc.layers[2], None);
+        assert_eq!(tmcc.total_segments(), 13);
+    }
+
+    #[test]
+    fn tmcc_display()
+    {
+        assert_eq!(TmccInfo::from_regs(&TMCC_REGS).to_string(), "Television, 13 segments, partial reception, A: Qpsk R2_3 TI 2 1seg, B: Qam64 R3_4 TI 2 12seg");
+    }
+}
+
@@ -0,0 +1,170 @@
+// TS の振り分け
+// IT930x は4チューナー分の TS を1本の bulk (ep 0x84) にまとめて流してくる。
+// どのチューナーのパケットかは、先頭の同期バイトを IT930xConfig::inputs の sync_byte (0x17/0x27/0x37/0x47) に
+// 置き換えることで区別している (config_stream_input の aggre mode)。
+// ここで、それを 188 byte ごとに分けて、同期バイトを 0x47 に戻す。
+// px4_device.c の px4_device_stream_handler 相当
+
+use crate::ts_framer::TsFramer;
+
+pub const TS_PACKET_SIZE: usize = 188;
+pub const TS_SYNC_BYTE: u8 = 0x47;
+
+// aggre mode で入力ごとに割り当てる同期バイトの形か
+// device_profile.rs の sync_byte(i) = ((i + 1) << 4) | 0x07 で、入力は 5つまで (0x17〜0x57)
+// ポート番号とは別物 (PX-MLT5 は port 4/3/1/2/0 に 0x17〜0x57 を振る)
+pub fn is_aggregated_sync_byte(sync: u8) -> bool
+{
+    (sync & 0x8f) == 0x07 && (sync & 0x70) != 0
+}
+
+pub struct TsDemuxer
+{
+    // 188 byte 境界の切り出しと同期の取り直しは TsFramer に任せる
+    framer: TsFramer,
+
+    // 振り分け先ごとの同期バイト (DeviceProfile::inputs の順)
+    sync_bytes: Vec<u8>,
+
+    // 振り分け先ごとのパケット数と、どの振り分け先の同期バイトでもなかったパケット数
+    packets: Vec<u64>,
+    unknown_packets: u64,
+}
+
+impl TsDemuxer
+{
+    pub fn new(sync_bytes: &[u8]) -> Self
+    {
+        Self
+        {
+            framer: TsFramer::new(is_aggregated_sync_byte),
+            sync_bytes: sync_bytes.to_vec(),
+            packets: vec![0; sync_bytes.len()],
+            unknown_packets: 0,
+        }
+    }
+
+    // 持ち越しているデータと数えた値を捨てる (bulk 転送を始め直すとき)
+    pub fn reset(&mut self)
+    {
+        self.framer.reset();
+        self.packets.iter_mut().for_each(|p| *p = 0);
+        self.unknown_packets = 0;
+    }
+
+    pub fn framer(&self) -> &TsFramer
+    {
+        &self.framer
+    }
+
+    pub fn packets(&self, index: usize) -> u64
+    {
+        self.packets.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn unknown_packets(&self) -> u64
+    {
+        self.unknown_packets
+    }
+
+    // bulk で受け取ったデータを渡すと、1パケットごとに out(振り分け先の番号, 0x47 に戻したパケット) を呼ぶ
+    pub fn push<F>(&mut self, data: &[u8], mut out: F)
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        let sync_bytes = &self.sync_bytes;
+        let packets = &mut self.packets;
+        let unknown_packets = &mut self.unknown_packets;
+        let mut packet = [0u8; TS_PACKET_SIZE];
+
+        self.framer.push(data, |p|
+        {
+            let Some(index) = sync_bytes.iter().position(|&b| b == p[0]) else
+            {
+                *unknown_packets += 1;
+                return;
+            };
+
+            packet.copy_from_slice(p);
+            packet[0] = TS_SYNC_BYTE;
+            packets[index] += 1;
+            out(index, &packet);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // PX-MLT5 の 5入力分の同期バイト
+    const SYNC_BYTES: [u8; 5] = [0x17, 0x27, 0x37, 0x47, 0x57];
+
+    fn packet(sync: u8, n: u8) -> [u8; TS_PACKET_SIZE]
+    {
+        let mut p = [0u8; TS_PACKET_SIZE];
+        p[0] = sync;
+        p[1] = n;
+        p
+    }
+
+    #[test]
+    fn aggregated_sync_bytes()
+    {
+        for sync in SYNC_BYTES.iter().chain(&[0x67, 0x77])
+        {
+            assert!(is_aggregated_sync_byte(*sync));
+        }
+        for sync in [0x07, 0x87, 0x48, 0x00, 0xff]
+        {
+            assert!(!is_aggregated_sync_byte(sync));
+        }
+    }
+
+    #[test]
+    fn routes_by_sync_byte_including_fifth_input()
+    {
+        let mut demux = TsDemuxer::new(&SYNC_BYTES);
+
+        let mut data = Vec::new();
+        for (n, sync) in [0x57, 0x17, 0x37, 0x57, 0x27, 0x47].iter().enumerate()
+        {
+            data.extend_from_slice(&packet(*sync, n as u8));
+        }
+
+        let mut out = Vec::new();
+        demux.push(&data, |index, p|
+        {
+            assert_eq!(p[0], TS_SYNC_BYTE);
+            out.push((index, p[1]));
+        });
+
+        assert_eq!(out, [(4, 0), (0, 1), (2, 2), (4, 3), (1, 4), (3, 5)]);
+        assert_eq!(demux.packets(4), 2);
+        assert_eq!(demux.packets(0), 1);
+        assert_eq!(demux.unknown_packets(), 0);
+    }
+
+    #[test]
+    fn drops_inputs_not_in_the_table()
+    {
+        // PX-W3U4 は 4入力
+        let mut demux = TsDemuxer::new(&SYNC_BYTES[..4]);
+
+        let mut data = Vec::new();
+        for sync in [0x17, 0x57, 0x17, 0x17]
+        {
+            data.extend_from_slice(&packet(sync, 0));
+        }
+
+        let mut count = 0;
+        demux.push(&data, |index, _| { assert_eq!(index, 0); count += 1; });
+
+        assert_eq!(count, 3);
+        assert_eq!(demux.unknown_packets(), 1);
+
+        demux.reset();
+        assert_eq!((demux.packets(0), demux.unknown_packets()), (0, 0));
+    }
+}
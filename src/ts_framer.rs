@@ -0,0 +1,251 @@
+// TS パケットの切り出し
+// stream_rx で受け取るデータは 188 byte 境界に揃っているとは限らず、転送が化けると途中で同期がずれることもある。
+// ここでは、読み込みをまたいでパケットの残りを持ち越しつつ、同期バイトが何個か連続して 188 byte 間隔で並んでいる位置を探して同期を取り直す。
+// stream_rx の結果を使うものなら何でも使える (TsDemuxer もこれを使う)
+
+use crate::ts_demux::TS_PACKET_SIZE;
+
+// 同期を取るときに、何個連続で同期バイトが並んでいれば良しとするか
+const TS_FRAMER_SYNC_COUNT: usize = 3;
+
+pub struct TsFramer
+{
+    // 同期バイトかどうかの判定
+    // 普通の TS なら 0x47 だけ、IT930x の aggre mode なら 0x17〜0x47
+    is_sync: fn(u8) -> bool,
+
+    // 前回までに受け取って、まだパケットにしていないデータ
+    buf: Vec<u8>,
+    synced: bool,
+    ever_synced: bool,
+
+    // 同期を探す間に読み捨てたバイト数
+    dropped_bytes: u64,
+    // 同期が外れて、取り直した回数と、そのときに読み捨てたバイト数
+    resyncs: u64,
+    resynced_bytes: u64,
+}
+
+impl TsFramer
+{
+    pub fn new(is_sync: fn(u8) -> bool) -> Self
+    {
+        Self
+        {
+            is_sync,
+            buf: Vec::with_capacity(TS_PACKET_SIZE * 2),
+            synced: false,
+            ever_synced: false,
+            dropped_bytes: 0,
+            resyncs: 0,
+            resynced_bytes: 0,
+        }
+    }
+
+    pub fn reset(&mut self)
+    {
+        self.buf.clear();
+        self.synced = false;
+        self.ever_synced = false;
+        self.dropped_bytes = 0;
+        self.resyncs = 0;
+        self.resynced_bytes = 0;
+    }
+
+    pub fn dropped_bytes(&self) -> u64
+    {
+        self.dropped_bytes
+    }
+
+    pub fn resyncs(&self) -> u64
+    {
+        self.resyncs
+    }
+
+    // dropped_bytes のうち、一度同期が取れた後に外れて読み捨てた分
+    pub fn resynced_bytes(&self) -> u64
+    {
+        self.resynced_bytes
+    }
+
+    // 受け取ったデータを渡すと、切り出せたパケットごとに out を呼ぶ
+    pub fn push<F>(&mut self, data: &[u8], mut out: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        self.buf.extend_from_slice(data);
+
+        let mut pos = 0;
+        loop
+        {
+            if self.synced
+            {
+                if self.buf.len() - pos < TS_PACKET_SIZE
+                {
+                    break;
+                }
+
+                if (self.is_sync)(self.buf[pos])
+                {
+                    out(&self.buf[pos..pos + TS_PACKET_SIZE]);
+                    pos += TS_PACKET_SIZE;
+                    continue;
+                }
+
+                // 同期が外れた
+                self.synced = false;
+            }
+
+            match self.find_sync(pos)
+            {
+                Some(found) =>
+                {
+                    self.skip(found - pos);
+                    pos = found;
+                    if self.ever_synced
+                    {
+                        self.resyncs += 1;
+                    }
+                    self.synced = true;
+                    self.ever_synced = true;
+                }
+                None =>
+                {
+                    // 同期位置の候補になり得ない先頭部分は捨てて、残りは次回に持ち越す
+                    let window = TS_PACKET_SIZE * (TS_FRAMER_SYNC_COUNT - 1) + 1;
+                    let keep_from = self.buf.len().saturating_sub(window - 1).max(pos);
+                    self.skip(keep_from - pos);
+                    pos = keep_from;
+                    break;
+                }
+            }
+        }
+
+        self.buf.drain(..pos);
+    }
+
+    // pos 以降で、同期バイトが sync_count 個 188 byte 間隔で並んでいる位置
+    // 判定しきれるだけのデータが無い位置より手前までしか探さない
+    fn find_sync(&self, pos: usize) -> Option<usize>
+    {
+        let span = TS_PACKET_SIZE * (TS_FRAMER_SYNC_COUNT - 1);
+        if self.buf.len() <= pos + span
+        {
+            return None;
+        }
+
+        (pos..self.buf.len() - span).find(|&i|
+        {
+            (0..TS_FRAMER_SYNC_COUNT).all(|n| (self.is_sync)(self.buf[i + n * TS_PACKET_SIZE]))
+        })
+    }
+
+    fn skip(&mut self, len: usize)
+    {
+        self.dropped_bytes += len as u64;
+        if self.ever_synced
+        {
+            self.resynced_bytes += len as u64;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::ts_demux::TS_SYNC_BYTE;
+
+    fn packets(n: usize) -> Vec<u8>
+    {
+        let mut data = Vec::new();
+        for i in 0..n
+        {
+            let mut p = [0u8; TS_PACKET_SIZE];
+            p[0] = TS_SYNC_BYTE;
+            p[1] = i as u8;
+            data.extend_from_slice(&p);
+        }
+        data
+    }
+
+    fn framer() -> TsFramer
+    {
+        TsFramer::new(|b| b == TS_SYNC_BYTE)
+    }
+
+    // push した結果のパケットの 2byte 目 (通し番号)
+    fn push(framer: &mut TsFramer, data: &[u8]) -> Vec<u8>
+    {
+        let mut out = Vec::new();
+        framer.push(data, |p|
+        {
+            assert_eq!(p.len(), TS_PACKET_SIZE);
+            out.push(p[1]);
+        });
+        out
+    }
+
+    #[test]
+    fn carries_packets_across_reads()
+    {
+        let mut f = framer();
+        let data = packets(3);
+
+        // 同期バイトが 3つ並ぶのが見えるまでは出さない
+        let mut out = Vec::new();
+        for chunk in data.chunks(100)
+        {
+            out.extend(push(&mut f, chunk));
+        }
+
+        assert_eq!(out, [0, 1, 2]);
+        assert_eq!(f.dropped_bytes(), 0);
+        assert_eq!(f.resyncs(), 0);
+    }
+
+    #[test]
+    fn drops_leading_garbage()
+    {
+        let mut f = framer();
+        let mut data = vec![0u8; 50];
+        data.extend(packets(4));
+
+        assert_eq!(push(&mut f, &data), [0, 1, 2, 3]);
+        assert_eq!(f.dropped_bytes(), 50);
+        // 最初に同期を取るのは取り直しではない
+        assert_eq!(f.resyncs(), 0);
+        assert_eq!(f.resynced_bytes(), 0);
+    }
+
+    #[test]
+    fn resyncs_after_lost_sync()
+    {
+        let mut f = framer();
+        let mut data = packets(4);
+        data.extend_from_slice(&[0u8; 10]);
+        data.extend(packets(4));
+
+        assert_eq!(push(&mut f, &data[..500]), [0, 1]);
+        assert_eq!(push(&mut f, &data[500..]), [2, 3, 0, 1, 2, 3]);
+        assert_eq!(f.dropped_bytes(), 10);
+        assert_eq!(f.resyncs(), 1);
+        assert_eq!(f.resynced_bytes(), 10);
+
+        f.reset();
+        assert_eq!((f.dropped_bytes(), f.resyncs(), f.resynced_bytes()), (0, 0, 0));
+    }
+
+    #[test]
+    fn keeps_tail_while_searching()
+    {
+        let mut f = framer();
+
+        // 同期が見つからない間も、候補になり得る末尾は持ち越す
+        let data = packets(3);
+        assert!(push(&mut f, &vec![0u8; 1000]).is_empty());
+        assert_eq!(push(&mut f, &data), [0, 1, 2]);
+        assert_eq!(f.dropped_bytes(), 1000);
+    }
+}
@@ -0,0 +1,543 @@
+// TS の SID 分離 (recpt1 の --sid / --strip 相当)
+// recpt1 の tssplitter_lite.c と同じ考え方で、
+//   - PAT を選んだサービスだけに書き換える
+//   - 選んだサービスの PMT から、PCR / ES / ECM の PID を拾って残す
+//   - SI (NIT, SDT, EIT, TOT など) は残す
+// ということをする。--strip はヌルパケット (PID 0x1fff) を落とすだけ。
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ts_demux::{TS_PACKET_SIZE, TS_SYNC_BYTE};
+
+const PID_PAT: u16 = 0x0000;
+const PID_NULL: u16 = 0x1fff;
+
+// tssplitter_lite.c で常に残している SI の PID
+const SI_PIDS: [u16; 24] = [
+    0x0001, 0x0002, 0x0003, 0x0004, 0x0005, 0x0006, 0x0007, 0x0008,
+    0x0009, 0x000a, 0x000b, 0x000c, 0x000d, 0x000e, 0x000f, 0x0010,
+    0x0011, 0x0012, 0x0013, 0x0014, 0x0023, 0x0024, 0x0026, 0x0029,
+];
+
+// ワンセグの PMT PID (ARIB TR-B14)
+const ONESEG_PMT_PIDS: std::ops::RangeInclusive<u16> = 0x1fc8..=0x1fcf;
+
+// --sid で指定できるもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SidSelector
+{
+    Sid(u16),
+    All,
+    Epg,
+    Hd,
+    Sd(usize), // sd1〜sd3 (PAT に載っている順)
+    OneSeg,
+}
+
+// "101,102" や "hd,epg" を解釈する
+pub fn parse_sid_list(s: &str) -> Result<Vec<SidSelector>, String>
+{
+    s.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()).map(|t|
+    {
+        match t.to_ascii_lowercase().as_str()
+        {
+            "all" => Ok(SidSelector::All),
+            "epg" => Ok(SidSelector::Epg),
+            "hd" => Ok(SidSelector::Hd),
+            "sd1" => Ok(SidSelector::Sd(1)),
+            "sd2" => Ok(SidSelector::Sd(2)),
+            "sd3" => Ok(SidSelector::Sd(3)),
+            "1seg" => Ok(SidSelector::OneSeg),
+            _ => t.parse::<u16>().map(SidSelector::Sid).map_err(|_| format!("invalid sid: {}", t)),
+        }
+    }).collect()
+}
+
+pub fn crc32_mpeg2(data: &[u8]) -> u32
+{
+    let mut crc = 0xffffffffu32;
+    for b in data
+    {
+        crc ^= (*b as u32) << 24;
+        for _ in 0..8
+        {
+            crc = if (crc & 0x80000000) != 0 { (crc << 1) ^ 0x04c11db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn packet_pid(p: &[u8]) -> u16
+{
+    (((p[1] & 0x1f) as u16) << 8) | p[2] as u16
+}
+
+// アダプテーションフィールドを飛ばしたペイロード
+fn packet_payload(p: &[u8]) -> Option<&[u8]>
+{
+    let afc = (p[3] >> 4) & 0x03;
+    match afc
+    {
+        0x01 => Some(&p[4..]),
+        0x03 =>
+        {
+            let start = 5 + p[4] as usize;
+            if start < TS_PACKET_SIZE { Some(&p[start..]) } else { None }
+        }
+        _ => None,
+    }
+}
+
+// 複数パケットにまたがるセクションを組み立てる
+#[derive(Default)]
+struct SectionBuffer
+{
+    buf: Vec<u8>,
+    active: bool,
+}
+
+impl SectionBuffer
+{
+    // セクションが揃ったら返す (1パケットで最初の1セクションだけ見る)
+    fn push(&mut self, p: &[u8]) -> Option<Vec<u8>>
+    {
+        let payload = packet_payload(p)?;
+        let pusi = (p[1] & 0x40) != 0;
+
+        if pusi
+        {
+            let pointer = *payload.first()? as usize;
+            if 1 + pointer >= payload.len()
+            {
+                return None;
+            }
+            self.buf.clear();
+            self.buf.extend_from_slice(&payload[1 + pointer..]);
+            self.active = true;
+        }
+        else if self.active
+        {
+            self.buf.extend_from_slice(payload);
+        }
+        else
+        {
+            return None;
+        }
+
+        if self.buf.len() < 3
+        {
+            return None;
+        }
+
+        let len = 3 + ((((self.buf[1] & 0x0f) as usize) << 8) | self.buf[2] as usize);
+        if self.buf.len() < len
+        {
+            return None;
+        }
+
+        self.active = false;
+        let section = self.buf[..len].to_vec();
+
+        // CRC が合わなければ捨てる
+        if len < 12 || crc32_mpeg2(&section) != 0
+        {
+            return None;
+        }
+
+        Some(section)
+    }
+}
+
+pub struct TsSplitter
+{
+    selectors: Vec<SidSelector>,
+    strip_null: bool,
+
+    pat: SectionBuffer,
+    pmt: HashMap<u16, SectionBuffer>,
+
+    // PAT から選んだサービス (sid → PMT PID)
+    programs: Vec<(u16, u16)>,
+    // 選んだサービスの PMT から拾った PID
+    pmt_pids: HashMap<u16, HashSet<u16>>,
+    // 書き換えた PAT (ペイロード部分)
+    new_pat: Option<Vec<u8>>,
+    pat_version: Option<u8>,
+}
+
+impl TsSplitter
+{
+    // selectors が空なら、分離はせず strip だけする
+    pub fn new(selectors: Vec<SidSelector>, strip_null: bool) -> Self
+    {
+        Self
+        {
+            selectors,
+            strip_null,
+            pat: SectionBuffer::default(),
+            pmt: HashMap::new(),
+            programs: Vec::new(),
+            pmt_pids: HashMap::new(),
+            new_pat: None,
+            pat_version: None,
+        }
+    }
+
+    fn is_passthrough(&self) -> bool
+    {
+        self.selectors.is_empty() || self.selectors.contains(&SidSelector::All)
+    }
+
+    // 188 byte のパケットを1つ渡すと、出力すべきものを out に書く
+    pub fn push(&mut self, p: &[u8], out: &mut Vec<u8>)
+    {
+        if p.len() != TS_PACKET_SIZE || p[0] != TS_SYNC_BYTE
+        {
+            return;
+        }
+
+        let pid = packet_pid(p);
+        if self.strip_null && pid == PID_NULL
+        {
+            return;
+        }
+
+        if self.is_passthrough()
+        {
+            out.extend_from_slice(p);
+            return;
+        }
+
+        if pid == PID_PAT
+        {
+            if let Some(section) = self.pat.push(p)
+            {
+                self.update_pat(&section);
+            }
+
+            // PAT の先頭パケットだけ、書き換えたものに差し替える
+            if (p[1] & 0x40) != 0
+            {
+                if let Some(pat) = &self.new_pat
+                {
+                    let mut np = [0xffu8; TS_PACKET_SIZE];
+                    np[..4].copy_from_slice(&[TS_SYNC_BYTE, 0x40, 0x00, 0x10 | (p[3] & 0x0f)]);
+                    np[4] = 0;
+                    np[5..5 + pat.len()].copy_from_slice(pat);
+                    out.extend_from_slice(&np);
+                }
+            }
+            return;
+        }
+
+        if let Some(buf) = self.pmt.get_mut(&pid)
+        {
+            if let Some(section) = buf.push(p)
+            {
+                self.update_pmt(pid, &section);
+            }
+            out.extend_from_slice(p);
+            return;
+        }
+
+        if SI_PIDS.contains(&pid) || self.pmt_pids.values().any(|pids| pids.contains(&pid))
+        {
+            out.extend_from_slice(p);
+        }
+    }
+
+    fn update_pat(&mut self, section: &[u8])
+    {
+        if section[0] != 0x00
+        {
+            return;
+        }
+
+        let version = (section[5] >> 1) & 0x1f;
+        if self.pat_version == Some(version) && self.new_pat.is_some()
+        {
+            return;
+        }
+
+        // (program_number, pid) の一覧。program 0 は NIT
+        let entries: Vec<(u16, u16)> = section[8..section.len() - 4].chunks_exact(4)
+            .map(|e| (((e[0] as u16) << 8) | e[1] as u16, (((e[2] & 0x1f) as u16) << 8) | e[3] as u16))
+            .collect();
+
+        let services: Vec<(u16, u16)> = entries.iter().copied().filter(|(sid, _)| *sid != 0).collect();
+
+        let mut selected: Vec<(u16, u16)> = Vec::new();
+        for sel in &self.selectors
+        {
+            let picked: Vec<(u16, u16)> = match sel
+            {
+                SidSelector::Sid(sid) => services.iter().copied().filter(|(s, _)| s == sid).collect(),
+                SidSelector::Hd => services.first().copied().into_iter().collect(),
+                SidSelector::Sd(n) => services.get(n - 1).copied().into_iter().collect(),
+                SidSelector::OneSeg => services.iter().copied().filter(|(_, pid)| ONESEG_PMT_PIDS.contains(pid)).collect(),
+                SidSelector::All | SidSelector::Epg => Vec::new(),
+            };
+
+            for e in picked
+            {
+                if !selected.contains(&e)
+                {
+                    selected.push(e);
+                }
+            }
+        }
+
+        // 新しい PAT を組み立てる
+        let mut sec = Vec::with_capacity(12 + 4 * (selected.len() + 1));
+        sec.extend_from_slice(&[0x00, 0, 0]);
+        sec.extend_from_slice(&section[3..8]);
+        for (sid, pid) in entries.iter().filter(|(sid, _)| *sid == 0).chain(selected.iter())
+        {
+            sec.extend_from_slice(&[(sid >> 8) as u8, *sid as u8, 0xe0 | ((pid >> 8) as u8 & 0x1f), *pid as u8]);
+        }
+        let len = sec.len() - 3 + 4;
+        sec[1] = 0xb0 | ((len >> 8) as u8 & 0x0f);
+        sec[2] = len as u8;
+        let crc = crc32_mpeg2(&sec);
+        sec.extend_from_slice(&crc.to_be_bytes());
+
+        if sec.len() > TS_PACKET_SIZE - 5
+        {
+            // 183 byte を超える PAT は (45 サービス以上) 想定しない
+            return;
+        }
+
+        self.pmt = selected.iter().map(|(_, pid)| (*pid, SectionBuffer::default())).collect();
+        self.pmt_pids.retain(|pid, _| self.pmt.contains_key(pid));
+        self.programs = selected;
+        self.new_pat = Some(sec);
+        self.pat_version = Some(version);
+    }
+
+    fn update_pmt(&mut self, pmt_pid: u16, section: &[u8])
+    {
+        // ヘッダ (12 byte) と CRC (4 byte) に満たないものは PMT として扱わない
+        if section.len() < 16 || section[0] != 0x02
+        {
+            return;
+        }
+
+        let mut pids = HashSet::new();
+
+        // PCR
+        pids.insert((((section[8] & 0x1f) as u16) << 8) | section[9] as u16);
+
+        let program_info_len = (((section[10] & 0x0f) as usize) << 8) | section[11] as usize;
+        let end = section.len() - 4;
+        let mut pos = 12;
+
+        // program_info_length がセクションからはみ出していたら壊れている
+        if pos + program_info_len > end
+        {
+            return;
+        }
+
+        Self::collect_ca_pids(&section[pos..pos + program_info_len], &mut pids);
+        pos += program_info_len;
+
+        while pos + 5 <= end
+        {
+            let es_pid = (((section[pos + 1] & 0x1f) as u16) << 8) | section[pos + 2] as u16;
+            let es_info_len = (((section[pos + 3] & 0x0f) as usize) << 8) | section[pos + 4] as usize;
+            pids.insert(es_pid);
+
+            let info_start = pos + 5;
+            Self::collect_ca_pids(&section[info_start..(info_start + es_info_len).min(end)], &mut pids);
+            pos = info_start + es_info_len;
+        }
+
+        self.pmt_pids.insert(pmt_pid, pids);
+    }
+
+    // CA descriptor (0x09) の ECM PID
+    fn collect_ca_pids(descs: &[u8], pids: &mut HashSet<u16>)
+    {
+        let mut pos = 0;
+        while pos + 2 <= descs.len()
+        {
+            let tag = descs[pos];
+            let len = descs[pos + 1] as usize;
+            if tag == 0x09 && len >= 4 && pos + 2 + len <= descs.len()
+            {
+                pids.insert((((descs[pos + 4] & 0x1f) as u16) << 8) | descs[pos + 5] as u16);
+            }
+            pos += 2 + len;
+        }
+    }
+
+    // 選ばれたサービス ID
+    pub fn selected_sids(&self) -> Vec<u16>
+    {
+        self.programs.iter().map(|(sid, _)| *sid).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // table_id と table_id_extension、中身からセクションを作る (CRC 付き)
+    fn section(table_id: u8, ext: u16, body: &[u8]) -> Vec<u8>
+    {
+        let len = 5 + body.len() + 4;
+        let mut sec = vec![table_id, 0xb0 | (len >> 8) as u8, len as u8, (ext >> 8) as u8, ext as u8, 0xc1, 0x00, 0x00];
+        sec.extend_from_slice(body);
+        let crc = crc32_mpeg2(&sec);
+        sec.extend_from_slice(&crc.to_be_bytes());
+        sec
+    }
+
+    fn packet(pid: u16, pusi: bool, payload: &[u8]) -> Vec<u8>
+    {
+        let mut p = vec![0xffu8; TS_PACKET_SIZE];
+        p[..4].copy_from_slice(&[TS_SYNC_BYTE, if pusi { 0x40 } else { 0x00 } | (pid >> 8) as u8, pid as u8, 0x10]);
+        p[4..4 + payload.len()].copy_from_slice(payload);
+        p
+    }
+
+    fn section_packet(pid: u16, sec: &[u8]) -> Vec<u8>
+    {
+        let mut payload = vec![0u8];
+        payload.extend_from_slice(sec);
+        packet(pid, true, &payload)
+    }
+
+    fn pat(entries: &[(u16, u16)]) -> Vec<u8>
+    {
+        let body: Vec<u8> = entries.iter().flat_map(|(sid, pid)| [(sid >> 8) as u8, *sid as u8, 0xe0 | (pid >> 8) as u8, *pid as u8]).collect();
+        section(0x00, 0x7fe0, &body)
+    }
+
+    // PCR と ES (と、あれば ECM) だけの PMT
+    fn pmt(sid: u16, pcr: u16, es: &[u16], ecm: Option<u16>) -> Vec<u8>
+    {
+        let mut body = vec![0xe0 | (pcr >> 8) as u8, pcr as u8];
+        match ecm
+        {
+            Some(ecm) => body.extend_from_slice(&[0xf0, 6, 0x09, 4, 0x00, 0x05, 0xe0 | (ecm >> 8) as u8, ecm as u8]),
+            None => body.extend_from_slice(&[0xf0, 0]),
+        }
+        for pid in es
+        {
+            body.extend_from_slice(&[0x02, 0xe0 | (pid >> 8) as u8, *pid as u8, 0xf0, 0]);
+        }
+        section(0x02, sid, &body)
+    }
+
+    fn pids_of(out: &[u8]) -> Vec<u16>
+    {
+        out.chunks_exact(TS_PACKET_SIZE).map(packet_pid).collect()
+    }
+
+    // NIT + 3サービスの PAT と、それぞれの PMT・ES を流す
+    fn run(selectors: Vec<SidSelector>, strip: bool, extra: &[Vec<u8>]) -> (TsSplitter, Vec<u8>)
+    {
+        let mut splitter = TsSplitter::new(selectors, strip);
+        let mut out = Vec::new();
+
+        let mut input = vec![
+            section_packet(PID_PAT, &pat(&[(0, 0x0010), (101, 0x0101), (102, 0x0102), (103, 0x1fc8)])),
+            section_packet(0x0101, &pmt(101, 0x0111, &[0x0111, 0x0112], Some(0x0901))),
+            section_packet(0x0102, &pmt(102, 0x0121, &[0x0121], None)),
+            section_packet(0x1fc8, &pmt(103, 0x0131, &[0x0131], None)),
+        ];
+        input.extend_from_slice(extra);
+        input.extend([0x0111, 0x0112, 0x0901, 0x0121, 0x0131, 0x0012, PID_NULL].iter().map(|pid| packet(*pid, false, &[])));
+
+        for p in &input
+        {
+            splitter.push(p, &mut out);
+        }
+        (splitter, out)
+    }
+
+    #[test]
+    fn parses_sid_list()
+    {
+        assert_eq!(parse_sid_list("101, hd,SD2,epg,1seg,all").unwrap(),
+            [SidSelector::Sid(101), SidSelector::Hd, SidSelector::Sd(2), SidSelector::Epg, SidSelector::OneSeg, SidSelector::All]);
+        assert!(parse_sid_list("101,foo").is_err());
+        assert!(parse_sid_list("70000").is_err());
+    }
+
+    #[test]
+    fn crc_of_a_section_including_its_crc_is_zero()
+    {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376e6e7);
+        assert_eq!(crc32_mpeg2(&pat(&[(0, 0x0010), (101, 0x0101)])), 0);
+    }
+
+    #[test]
+    fn rewrites_pat_to_the_selected_service()
+    {
+        let (splitter, out) = run(vec![SidSelector::Hd], false, &[]);
+
+        assert_eq!(splitter.selected_sids(), [101]);
+        // 102/103 の PMT・ES と、ヌルパケットは落ちて、SI (0x0012) は残る
+        assert_eq!(pids_of(&out), [PID_PAT, 0x0101, 0x0111, 0x0112, 0x0901, 0x0012]);
+
+        // 書き換えた PAT は NIT と 101 だけで、CRC も合っている
+        let payload = &out[5..];
+        let len = 3 + ((((payload[1] & 0x0f) as usize) << 8) | payload[2] as usize);
+        assert_eq!(&payload[..len], pat(&[(0, 0x0010), (101, 0x0101)]).as_slice());
+    }
+
+    #[test]
+    fn selects_by_sid_and_position_and_strips_null()
+    {
+        let (splitter, out) = run(vec![SidSelector::Sid(103), SidSelector::Sd(2)], true, &[]);
+
+        assert_eq!(splitter.selected_sids(), [103, 102]);
+        assert_eq!(pids_of(&out), [PID_PAT, 0x0102, 0x1fc8, 0x0121, 0x0131, 0x0012]);
+    }
+
+    #[test]
+    fn oneseg_and_epg()
+    {
+        let (splitter, out) = run(vec![SidSelector::OneSeg], false, &[]);
+        assert_eq!(splitter.selected_sids(), [103]);
+        assert_eq!(pids_of(&out), [PID_PAT, 0x1fc8, 0x0131, 0x0012]);
+
+        // epg だけなら、サービスは選ばず SI だけ
+        let (splitter, out) = run(vec![SidSelector::Epg], true, &[]);
+        assert!(splitter.selected_sids().is_empty());
+        assert_eq!(pids_of(&out), [PID_PAT, 0x0012]);
+    }
+
+    #[test]
+    fn passthrough_without_selectors()
+    {
+        let (_, out) = run(Vec::new(), true, &[]);
+        assert_eq!(pids_of(&out), [PID_PAT, 0x0101, 0x0102, 0x1fc8, 0x0111, 0x0112, 0x0901, 0x0121, 0x0131, 0x0012]);
+
+        let (_, out) = run(vec![SidSelector::Hd, SidSelector::All], false, &[]);
+        assert_eq!(out.len(), 11 * TS_PACKET_SIZE);
+    }
+
+    #[test]
+    fn ignores_broken_pmt()
+    {
+        let mut splitter = TsSplitter::new(vec![SidSelector::Sid(101)], false);
+        let mut out = Vec::new();
+        splitter.push(&section_packet(PID_PAT, &pat(&[(101, 0x0101)])), &mut out);
+
+        // CRC は合っているが、ヘッダしかない (12 byte) PMT
+        let short = section(0x02, 101, &[]);
+        assert_eq!(short.len(), 12);
+        splitter.push(&section_packet(0x0101, &short), &mut out);
+
+        // program_info_length がセクションの外まで伸びている PMT
+        splitter.push(&section_packet(0x0101, &section(0x02, 101, &[0xe1, 0x11, 0xf0, 0x40, 0x02, 0xe1, 0x12, 0xf0, 0x00])), &mut out);
+
+        assert!(splitter.pmt_pids.is_empty());
+        splitter.push(&packet(0x0111, false, &[]), &mut out);
+        assert_eq!(pids_of(&out), [PID_PAT, 0x0101, 0x0101]);
+    }
+}